@@ -0,0 +1,92 @@
+//! Per-frame LZ4 encoding latency, serial versus on the worker pool.
+//!
+//! Run with `cargo bench --bench encode`.
+
+use remdes::{caps::*, codec::*, session::SendOptions, *};
+use std::time::{Duration, Instant};
+
+const SIZES: &[(usize, usize)] = &[(640, 360), (1280, 720), (1920, 1080), (3840, 2160)];
+const WARMUP: usize = 3;
+const ITERS: usize = 20;
+
+/// A BGRA frame of smooth gradients with some noise, roughly as compressible
+/// as a desktop.
+fn frame((w, h): (usize, usize)) -> Region {
+    let mut state = 0x2545_f491u32;
+    let mut noise = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        (state & 0x7) as u8
+    };
+
+    let mut region = Region::default();
+    *region.data_mut() = (0..w * h)
+        .flat_map(|i| {
+            let (x, y) = (i % w, i / w);
+            [
+                (x / 4) as u8 ^ noise(),
+                (y / 4) as u8,
+                ((x + y) / 8) as u8,
+                255,
+            ]
+        })
+        .collect();
+    region.set_w(w as i32);
+    region.set_h(h as i32);
+    region.set_l(w * h * 4);
+    region
+}
+
+/// Median time to encode `src` as a keyframe, and the bytes it took.
+fn measure(encoder: &mut dyn Encoder, src: &Region) -> (Duration, usize) {
+    let mut times = Vec::with_capacity(ITERS);
+    let mut bytes = 0;
+    for i in 0..WARMUP + ITERS {
+        bytes = 0;
+        let t = Instant::now();
+        encoder
+            .encode(src, EncodeOptions::default(), &mut |datagram| {
+                bytes += datagram.len();
+                Ok(())
+            })
+            .unwrap();
+        if i >= WARMUP {
+            times.push(t.elapsed());
+        }
+    }
+    times.sort();
+    (times[ITERS / 2], bytes)
+}
+
+fn main() {
+    let hello = Hello::new().with_codecs(Codecs::of(&[Codec::Lz4]));
+    let params = hello.negotiate(&hello).unwrap();
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+    println!(
+        "{:>10}  {:>9}  {:>9}  {:>9}  {:>7}",
+        "size", "bytes", "serial", "pool", "speedup"
+    );
+    for &size in SIZES {
+        let src = frame(size);
+        let run = |threads| {
+            let opts = SendOptions {
+                threads,
+                ..Default::default()
+            };
+            measure(&mut *encoder(params, &opts).unwrap(), &src)
+        };
+        let (serial, bytes) = run(1);
+        let (pool, _) = run(threads);
+
+        println!(
+            "{:>10}  {:>9}  {:>9.2?}  {:>9.2?}  {:>6.2}x",
+            format!("{}x{}", size.0, size.1),
+            util::bytes_to_mb_str(bytes),
+            serial,
+            pool,
+            serial.as_secs_f64() / pool.as_secs_f64(),
+        );
+    }
+}
@@ -9,6 +9,19 @@ layout(location = 0) out vec4 FragColor;
 // UNIFORMS
 layout(binding = 0) uniform sampler2D uTexture;
 
+layout(std140, binding = 1) uniform Overlay {
+    vec4 uStatus; // status bar colour (alpha = visibility)
+    float uDim;   // frame brightness multiplier
+};
+
+// status bar height, in texture coordinates
+const float STATUS_HEIGHT = 0.01;
+
 void main() {
     FragColor = texture(uTexture, TexCoord);
+    FragColor.rgb *= uDim;
+
+    if (TexCoord.y < STATUS_HEIGHT) {
+        FragColor = mix(FragColor, vec4(uStatus.rgb, 1.0), uStatus.a);
+    }
 }
@@ -0,0 +1,42 @@
+#version 460 core
+
+// INPUTS
+layout(location = 0) in vec2 TexCoord;
+
+// OUTPUTS
+layout(location = 0) out vec4 FragColor;
+
+// UNIFORMS
+// chroma planes skip unit 1 to keep bindings distinct from the Overlay block
+layout(binding = 0) uniform sampler2D uY;
+layout(binding = 2) uniform sampler2D uU;
+layout(binding = 3) uniform sampler2D uV;
+
+layout(std140, binding = 1) uniform Overlay {
+    vec4 uStatus; // status bar colour (alpha = visibility)
+    float uDim;   // frame brightness multiplier
+};
+
+// status bar height, in texture coordinates
+const float STATUS_HEIGHT = 0.01;
+
+// BT.601 limited-range YUV to RGB
+vec3 yuv_to_rgb(float y, float u, float v) {
+    y = 1.164 * (y - 16.0 / 255.0);
+    u -= 0.5;
+    v -= 0.5;
+    return vec3(y + 1.596 * v, y - 0.392 * u - 0.813 * v, y + 2.017 * u);
+}
+
+void main() {
+    vec3 rgb = yuv_to_rgb(
+        texture(uY, TexCoord).r,
+        texture(uU, TexCoord).r,
+        texture(uV, TexCoord).r
+    );
+    FragColor = vec4(clamp(rgb, 0.0, 1.0) * uDim, 1.0);
+
+    if (TexCoord.y < STATUS_HEIGHT) {
+        FragColor = mix(FragColor, vec4(uStatus.rgb, 1.0), uStatus.a);
+    }
+}
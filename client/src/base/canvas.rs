@@ -0,0 +1,71 @@
+use font8x8::legacy::BASIC_LEGACY;
+
+/// Width and height of a glyph of the bitmap font, in font pixels.
+pub const GLYPH: usize = 8;
+
+/// An RGB colour.
+pub type Color = [u8; 3];
+
+/// A BGRA image drawn on the CPU, for screens of text shown through the
+/// frame pipeline.
+#[derive(Debug)]
+pub struct Canvas {
+    w: usize,
+    h: usize,
+    scale: usize, // window pixels per font pixel
+    data: Vec<u8>,
+}
+
+impl Canvas {
+    /// A `w` by `h` canvas, with text large enough to read at that size.
+    pub fn new(w: usize, h: usize) -> Self {
+        Self {
+            w,
+            h,
+            scale: (h / 300).clamp(1, 4),
+            data: vec![0; w * h * 4],
+        }
+    }
+
+    /// Size of a character cell, in pixels.
+    pub const fn cell(&self) -> usize {
+        GLYPH * self.scale
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Fill the rectangle at `x`, `y`, clipped to the canvas.
+    pub fn fill(&mut self, [x, y, w, h]: [usize; 4], [r, g, b]: Color) {
+        let (x1, y1) = ((x + w).min(self.w), (y + h).min(self.h));
+        for row in y.min(y1)..y1 {
+            let start = (row * self.w + x.min(x1)) * 4;
+            let end = (row * self.w + x1) * 4;
+            for px in self.data[start..end].chunks_exact_mut(4) {
+                px.copy_from_slice(&[b, g, r, 0xff]);
+            }
+        }
+    }
+
+    /// Write `text` with its top-left corner at `x`, `y`, returning where
+    /// it ends. Characters the font lacks are shown as `?`.
+    pub fn text(&mut self, (x, y): (usize, usize), text: &str, color: Color) -> usize {
+        let (scale, cell) = (self.scale, self.cell());
+        let mut x = x;
+        for c in text.chars() {
+            if x + cell > self.w {
+                break;
+            }
+            let c = if c.is_ascii() { c } else { '?' };
+            let glyph = BASIC_LEGACY[c as usize];
+            for (gy, bits) in glyph.into_iter().enumerate() {
+                for gx in (0..GLYPH).filter(|gx| bits & (1 << gx) != 0) {
+                    self.fill([x + gx * scale, y + gy * scale, scale, scale], color);
+                }
+            }
+            x += cell;
+        }
+        x
+    }
+}
@@ -1,49 +1,263 @@
 use crate::*;
-use clap::Parser;
-use remdes::util::get_socket_addr;
-use std::net::{Ipv4Addr, SocketAddr};
+use clap::{ArgGroup, Parser};
+use remdes::{
+    caps::*,
+    discovery::Ports,
+    rendezvous::Registrar,
+    scale::Scale,
+    util::{check_heartbeat, config_dir, parse_millis, resolve_all},
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
-#[derive(Parser, Clone, Copy, Debug)]
+/// File in the config directory holding the client's settings and profiles.
+const CONFIG_FILE: &str = "client.toml";
+
+#[derive(Parser, Clone, Debug)]
+#[command(group(ArgGroup::new("registrar").args(["rendezvous", "relay"]).multiple(true)))]
 pub struct Config {
-    /// Remote TCP address.
-    #[arg(long, default_value_t = get_socket_addr(TCP_PORT))]
-    rt: SocketAddr,
+    /// Server hostname or address (opens the launcher to pick one if no server is given).
+    #[arg(long)]
+    host: Option<String>,
+
+    /// Reach a server in single-port mode, with control and media on this UDP port.
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Connect over QUIC on the UDP port: encrypted control on a stream, frames as datagrams.
+    #[arg(long)]
+    quic: bool,
+
+    /// SHA-256 the server's QUIC certificate must have, as printed by the server (any if omitted).
+    #[arg(long)]
+    fingerprint: Option<String>,
+
+    /// Find the server through this rendezvous server (HOST[:PORT]) and punch through NAT to it (overrides --host).
+    #[arg(long, requires = "session", conflicts_with = "quic")]
+    rendezvous: Option<String>,
+
+    /// Reach the server through this relay (HOST[:PORT]) if punching through fails or is not tried (overrides --host).
+    #[arg(long, requires_all = ["session", "relay_secret"], conflicts_with = "quic")]
+    relay: Option<String>,
+
+    /// Secret shared with the relay.
+    #[arg(long, requires = "relay")]
+    relay_secret: Option<String>,
+
+    /// Session ID the server registered with the rendezvous server or relay.
+    #[arg(long, requires = "registrar")]
+    session: Option<String>,
 
-    /// Local UDP address.
-    #[arg(long, default_value_t = get_socket_addr(
-        pfrs::find_open_port(Ipv4Addr::LOCALHOST, pfrs::Protocol::Udp).expect("No available dynamic ports"),
-    ))]
-    lu: SocketAddr,
+    /// List the servers answering on the LAN and pick one to connect to.
+    #[arg(long, conflicts_with_all = ["host", "port", "quic", "rt", "ru", "registrar"])]
+    discover: bool,
 
-    /// Remote UDP address.
-    #[arg(long, default_value_t = get_socket_addr(UDP_PORT))]
-    ru: SocketAddr,
+    /// Remote TCP address (overrides --host).
+    #[arg(long)]
+    rt: Option<SocketAddr>,
+
+    /// Local UDP address (any free port by default).
+    #[arg(long)]
+    lu: Option<SocketAddr>,
+
+    /// Remote UDP address (the server's control address on the UDP port by default).
+    #[arg(long)]
+    ru: Option<SocketAddr>,
 
     /// Specify the FPS.
     #[arg(short, long, default_value_t = 120)]
     fps: u8,
+
+    /// Ask the server to downscale by a factor (e.g. 0.5) or to fit a size (e.g. 1280x720).
+    #[arg(long, default_value_t = Scale::Full)]
+    scale: Scale,
+
+    /// Codec to request (lz4 or h264).
+    #[arg(long)]
+    codec: Option<Codec>,
+
+    /// Wire pixel format to request (bgra8, bgrx8, rgb565, yuv420 or gray8).
+    #[arg(long)]
+    pixel_format: Option<PixelFormat>,
+
+    /// Worker threads decompressing chunks (0 = one per core).
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+
+    /// Let the kernel coalesce received datagrams with UDP GRO (Linux only).
+    #[arg(long)]
+    gro: bool,
+
+    /// Kernel receive buffer of the UDP socket in KiB (0 keeps the OS default).
+    #[arg(long, default_value_t = 8192)]
+    recv_buffer: usize,
+
+    /// Heartbeat interval in milliseconds, shorter than --timeout.
+    #[arg(long, default_value = "250", value_parser = parse_millis)]
+    heartbeat: Duration,
+
+    /// Milliseconds of server silence before it is declared dead.
+    #[arg(long, default_value = "2000", value_parser = parse_millis)]
+    timeout: Duration,
 }
 
 impl Config {
-    pub const fn remote_tcp_addr(&self) -> SocketAddr {
-        self.rt
+    /// Addresses to try, in order, for the control channel.
+    pub fn remote_control_addrs(&self) -> Result<Vec<SocketAddr>> {
+        match (self.rt, self.port, self.quic) {
+            (Some(addr), None, false) => Ok(vec![addr]),
+            (_, Some(port), _) => resolve_all(self.host(), port),
+            (_, None, true) => resolve_all(self.host(), UDP_PORT),
+            (_, None, false) => resolve_all(self.host(), TCP_PORT),
+        }
+    }
+
+    /// Server hostname or address.
+    pub fn host(&self) -> &str {
+        self.host.as_deref().unwrap_or("127.0.0.1")
+    }
+
+    /// Whether to let the user pick a server in the window, none being given.
+    pub const fn launcher(&self) -> bool {
+        self.host.is_none()
+            && self.rt.is_none()
+            && self.rendezvous.is_none()
+            && self.relay.is_none()
+            && !self.discover
     }
 
-    pub const fn local_udp_addr(&self) -> SocketAddr {
-        self.lu
+    pub const fn discover(&self) -> bool {
+        self.discover
     }
 
-    pub const fn remote_udp_addr(&self) -> SocketAddr {
-        self.ru
+    /// Connect to a server found at `ip`, reachable on `ports`.
+    pub fn with_server(mut self, ip: IpAddr, ports: &Ports) -> Self {
+        self.host = Some(ip.to_string());
+        match ports {
+            Ports::Split { tcp, udp } => {
+                self.rt = (*tcp != TCP_PORT).then_some(SocketAddr::new(ip, *tcp));
+                self.ru = (*udp != UDP_PORT).then_some(SocketAddr::new(ip, *udp));
+            }
+            Ports::Single(port) => self.port = Some(*port),
+            Ports::Quic { port, fingerprint } => {
+                self.port = Some(*port);
+                self.quic = true;
+                // a pinned fingerprint still has the last word
+                self.fingerprint.get_or_insert_with(|| fingerprint.clone());
+            }
+        }
+        self
+    }
+
+    /// Connect to `host` the way the command line says.
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_owned());
+        self
+    }
+
+    /// Port carrying both control and media, in single-port mode.
+    pub const fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Session ID the server registered as.
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    /// Rendezvous server and relay to find the server through, in order.
+    pub fn registrars(&self) -> Result<Vec<Registrar>> {
+        Registrar::resolve_hosts(
+            self.rendezvous.as_deref(),
+            self.relay.as_deref().zip(self.relay_secret.as_deref()),
+        )
+    }
+
+    pub const fn quic(&self) -> bool {
+        self.quic
+    }
+
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.fingerprint.as_deref()
+    }
+
+    /// Address to receive frames from `remote` on.
+    pub const fn local_udp_addr(&self, remote: SocketAddr) -> SocketAddr {
+        match (self.lu, remote) {
+            (Some(addr), _) => addr,
+            (None, SocketAddr::V4(_)) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            (None, SocketAddr::V6(_)) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        }
+    }
+
+    /// Address frames are requested from, given the server's control address.
+    pub const fn remote_udp_addr(&self, control: SocketAddr) -> SocketAddr {
+        match (self.ru, self.port.is_some() || self.session.is_some()) {
+            (Some(addr), _) => addr,
+            (None, true) => control,
+            (None, false) => SocketAddr::new(control.ip(), UDP_PORT),
+        }
     }
 
     pub const fn fps(&self) -> u8 {
         self.fps
     }
+
+    pub const fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    /// Capabilities to offer the server.
+    pub const fn hello(&self) -> Hello {
+        let mut hello = Hello::new();
+        if let Some(codec) = self.codec {
+            hello = hello.with_codecs(Codecs::of(&[codec]));
+        }
+        if let Some(format) = self.pixel_format {
+            hello = hello.with_pixel_formats(PixelFormats::of(&[format]));
+        }
+        hello
+    }
+
+    pub const fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub const fn gro(&self) -> bool {
+        self.gro
+    }
+
+    /// Requested receive buffer in bytes, if any.
+    pub const fn recv_buffer(&self) -> Option<usize> {
+        match self.recv_buffer {
+            0 => None,
+            kib => Some(kib * 1024),
+        }
+    }
+
+    pub const fn heartbeat(&self) -> Duration {
+        self.heartbeat
+    }
+
+    pub const fn timeout(&self) -> Duration {
+        self.timeout
+    }
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self::parse()
+impl Config {
+    /// Settings from the command line, over those of the config file.
+    pub fn load() -> Result<Self> {
+        Self::checked(remdes::config::parse(CONFIG_FILE, true)?)
+    }
+
+    /// [`Self::load`] as if `--host` was given, so the host's profile applies.
+    pub fn load_for(host: &str) -> Result<Self> {
+        let args = std::env::args_os().chain(["--host".into(), host.into()]);
+        let file = config_dir().map(|dir| dir.join(CONFIG_FILE));
+        Self::checked(remdes::config::parse_from(args, (file, true))?)
+    }
+
+    fn checked(self) -> Result<Self> {
+        check_heartbeat((self.heartbeat, self.timeout))?;
+        Ok(self)
     }
 }
@@ -0,0 +1,96 @@
+use crate::*;
+use remdes::{caps::*, proto::*};
+use std::thread::sleep;
+
+/// Control channel shared between the network and event threads.
+#[derive(Clone, Debug, Default)]
+pub struct Control {
+    inner: Arc<Mutex<Option<ControlChannel>>>,
+}
+
+impl Control {
+    pub fn set(&self, ctrl: ControlChannel) {
+        *self.inner.lock() = Some(ctrl);
+    }
+
+    /// Drop the current connection, if any.
+    pub fn close(&self) {
+        if let Some(ctrl) = self.inner.lock().take() {
+            ctrl.shutdown();
+        }
+    }
+
+    /// Send a message if connected. Failures are left to the network thread.
+    pub fn send(&self, msg: Message) {
+        if let Some(ctrl) = self.inner.lock().as_ref()
+            && let Err(e) = ctrl.send(msg)
+        {
+            log::debug!("control send failed: {e}");
+        }
+    }
+}
+
+/// Exchange [`Hello`]s with the server and receive the session parameters.
+pub fn handshake(ctrl: &ControlChannel, local: Hello) -> Result<SessionParams> {
+    ctrl.send(Message::Hello(local))?;
+
+    let peer = match ctrl.recv()? {
+        Message::Hello(peer) => peer,
+        msg => bail!("Expected hello, got {msg:?}"),
+    };
+
+    match ctrl.recv()? {
+        Message::Session(params) => {
+            // reject parameters this build cannot honour
+            local.accepts(&params)?;
+            Ok(params)
+        }
+        Message::Goodbye(reason) => match local.negotiate(&peer) {
+            Err(e) => bail!("Server rejected the session ({reason:?}): {e}"),
+            std::result::Result::Ok(_) => bail!("Server rejected the session ({reason:?})"),
+        },
+        msg => bail!("Expected session parameters, got {msg:?}"),
+    }
+}
+
+/// Dispatch server messages and exchange heartbeats until the connection ends.
+pub fn init_control(
+    ctrl: ControlChannel,
+    heartbeat: Arc<Heartbeat>,
+    tx_event: Arc<EventSender>,
+) -> JoinHandle<Result<()>> {
+    spawn(move || {
+        let handler = {
+            let heartbeat = heartbeat.clone();
+            move |msg| match msg {
+                Message::Pong(_) => {
+                    log::debug!("rtt {:?}", heartbeat.rtt().unwrap_or_default());
+                    true
+                }
+
+                // server-imposed fps target
+                Message::SetFps(fps) => tx_event.push_custom_event(UserEvent::Fps(fps)).is_ok(),
+
+                Message::Goodbye(reason) => {
+                    log::info!("server closed the session: {reason:?}");
+                    false
+                }
+
+                msg => {
+                    log::trace!("ignoring {msg:?}");
+                    true
+                }
+            }
+        };
+
+        let end = run_control(&ctrl, &heartbeat, handler, |dur| {
+            sleep(dur);
+            true
+        })?;
+        if end == ControlEnd::TimedOut {
+            log::warn!("server timed out after {:?}", heartbeat.timeout());
+        }
+        log::info!("last rtt {:?}", heartbeat.rtt().unwrap_or_default());
+        Ok(())
+    })
+}
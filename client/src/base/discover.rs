@@ -0,0 +1,44 @@
+use crate::*;
+use remdes::discovery::{BROADCAST, DISCOVER_TIMEOUT, discover};
+use std::io::BufRead;
+
+/// List the servers answering on the LAN and let the user pick one, returning
+/// `cfg` pointed at it.
+pub fn pick_server(cfg: Config) -> Result<Config> {
+    println!("Looking for servers...");
+    let servers = discover(&[BROADCAST], DISCOVER_TIMEOUT)?;
+    if servers.is_empty() {
+        bail!("No servers answered on the LAN");
+    }
+    for (i, (ip, server)) in servers.iter().enumerate() {
+        println!(
+            "{:>3}) {} @ {ip} ({}): {}",
+            i + 1,
+            server.hostname,
+            server.ports,
+            server.window
+        );
+    }
+
+    let mut stdin = std::io::stdin().lock();
+    let mut line = String::new();
+    let choice = loop {
+        print!("Server [1]: ");
+        std::io::stdout().flush()?;
+        line.clear();
+        if stdin.read_line(&mut line)? == 0 {
+            bail!("No server picked");
+        }
+        match line.trim() {
+            "" => break 0,
+            n => match n.parse::<usize>() {
+                std::result::Result::Ok(n) if (1..=servers.len()).contains(&n) => break n - 1,
+                _ => println!("Pick a number from 1 to {}", servers.len()),
+            },
+        }
+    };
+
+    let (ip, server) = &servers[choice];
+    log::info!("connecting to {} @ {ip}", server.hostname);
+    Ok(cfg.with_server(*ip, &server.ports))
+}
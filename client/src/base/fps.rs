@@ -32,9 +32,25 @@ impl Fps {
     }
 }
 
+/// Frame-rate targets stepped through by the fps hotkeys (0 = unlimited).
+const FPS_STEPS: &[u8] = &[15, 24, 30, 60, 90, 120, 144, 240, 0];
+
+/// The next fps target above or below `fps`.
+pub fn step_fps(fps: u8, up: bool) -> u8 {
+    // unlimited sorts above every finite target
+    let rank = |fps: u8| if fps == 0 { u16::MAX } else { fps as u16 };
+    let next = if up {
+        FPS_STEPS.iter().find(|&&step| rank(step) > rank(fps))
+    } else {
+        FPS_STEPS.iter().rev().find(|&&step| rank(step) < rank(fps))
+    };
+    next.copied().unwrap_or(fps)
+}
+
 #[derive(Debug, Default)]
 pub struct Limit {
     nanos: AtomicU64,
+    fps: AtomicU8,
 }
 
 impl Limit {
@@ -42,10 +58,16 @@ impl Limit {
         Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
     }
 
+    /// The fps target the limit was last set to.
+    pub fn fps(&self) -> u8 {
+        self.fps.load(Ordering::Relaxed)
+    }
+
     pub fn set(&self, fps: u8) {
         let dur = remdes::util::tick_dur(fps as f32);
         let nanos = dur.as_nanos().min(u64::MAX as u128) as u64;
         self.nanos.store(nanos, Ordering::Relaxed);
+        self.fps.store(fps, Ordering::Relaxed);
     }
 }
 
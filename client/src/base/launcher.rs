@@ -0,0 +1,333 @@
+use crate::*;
+use remdes::discovery::{BROADCAST, DISCOVER_TIMEOUT, discover};
+use sdl2::{
+    EventPump,
+    keyboard::TextInputUtil,
+    mouse::{MouseButton, MouseUtil},
+};
+use std::{sync::Weak, thread::sleep};
+
+/// Time between searches of the LAN while the launcher is open.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How long the launcher waits for input before checking for new servers.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+const BACKGROUND: Color = [0x18, 0x1a, 0x20];
+const FIELD: Color = [0x2a, 0x2e, 0x38];
+const SELECTION: Color = [0x2d, 0x4a, 0x80];
+const TEXT: Color = [0xe8, 0xe8, 0xe8];
+const MUTED: Color = [0x88, 0x8c, 0x96];
+
+const HINT: &str = "Up/Down: select  Enter: connect  Del: forget  Esc: quit";
+
+/// Servers found on the LAN with the windows they capture, or `None` before
+/// the first search is done.
+type Found = Option<Vec<(Target, String)>>;
+
+/// Lets the user pick a server inside the window, among recent ones and those
+/// found on the LAN, then shows how connecting to it goes until it streams.
+pub struct Launcher {
+    recent: Recent,
+    found: Arc<Mutex<Found>>, // refreshed in the background
+    shown: Found,             // as last drawn, without recent servers
+    entry: String,
+    selected: usize,
+    rows: Vec<(usize, usize)>, // pixel rows spanned by each listed server
+    connecting: Option<(String, ConnState)>, // to the server picked
+    tex: glow::NativeTexture,
+    mouse: MouseUtil,
+    text_input: TextInputUtil,
+}
+
+impl Launcher {
+    pub fn new(gl: &glow::Context, sdl: &sdl2::Sdl, video: &sdl2::VideoSubsystem) -> Self {
+        let found: Arc<Mutex<Found>> = Default::default();
+        {
+            let found = Arc::downgrade(&found);
+            spawn(move || scan(&found));
+        }
+
+        // the pointer is needed to click, and keys to type
+        let (mouse, text_input) = (sdl.mouse(), video.text_input());
+        mouse.set_relative_mouse_mode(false);
+        text_input.start();
+
+        Self {
+            recent: Recent::load(),
+            found,
+            shown: None,
+            entry: String::new(),
+            selected: 0,
+            rows: Vec::new(),
+            connecting: None,
+            tex: unsafe { create_texture(gl) },
+            mouse,
+            text_input,
+        }
+    }
+
+    /// Servers listed, in order: recent ones, then those found on the LAN.
+    fn listed(&self) -> impl Iterator<Item = &Target> {
+        let found = self.shown.iter().flatten().map(|(target, _)| target);
+        self.recent.servers().iter().chain(found)
+    }
+
+    /// Take in the latest search of the LAN, returning whether it changed.
+    fn refresh(&mut self) -> bool {
+        let found = self.found.lock().clone().map(|mut found| {
+            let recent = self.recent.servers();
+            found.retain(|(target, _)| !recent.iter().any(|other| other.same(target)));
+            found
+        });
+        if found == self.shown {
+            return false;
+        }
+        self.shown = found;
+        self.selected = self.selected.min(self.listed().count().saturating_sub(1));
+        true
+    }
+
+    /// The server typed in, or else the one selected.
+    fn choice(&self) -> Option<Target> {
+        match self.entry.trim() {
+            "" => self.listed().nth(self.selected).cloned(),
+            host => Some(Target::typed(host)),
+        }
+    }
+
+    /// Wait for the user to pick a server, or `None` if they quit instead.
+    pub fn pick(
+        &mut self,
+        gl: &glow::Context,
+        window: &Window,
+        ep: &mut EventPump,
+        prog: &Program,
+    ) -> Option<Target> {
+        self.refresh();
+        self.draw(gl, window, prog);
+
+        loop {
+            let event = ep.wait_event_timeout(POLL_INTERVAL.as_millis() as u32);
+            let changed = match event {
+                None => false,
+
+                Some(
+                    Event::Quit { .. }
+                    | Event::KeyDown {
+                        keycode: Some(Keycode::Escape),
+                        ..
+                    },
+                ) => return None,
+
+                Some(Event::KeyDown {
+                    keycode: Some(Keycode::Return | Keycode::KpEnter),
+                    ..
+                }) => match self.choice() {
+                    Some(target) => return Some(target),
+                    None => false,
+                },
+
+                Some(Event::MouseButtonDown {
+                    mouse_btn: MouseButton::Left,
+                    y,
+                    ..
+                }) => {
+                    let y = y.max(0) as usize;
+                    if let Some(i) = self.rows.iter().position(|&(y0, y1)| (y0..y1).contains(&y)) {
+                        self.selected = i;
+                        self.entry.clear();
+                        return self.choice();
+                    }
+                    false
+                }
+
+                Some(Event::TextInput { text, .. }) => {
+                    self.entry.push_str(&text);
+                    true
+                }
+
+                Some(Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                }) => self.entry.pop().is_some(),
+
+                Some(Event::KeyDown {
+                    keycode: Some(key @ (Keycode::Up | Keycode::Down)),
+                    ..
+                }) => {
+                    let last = self.listed().count().saturating_sub(1);
+                    self.selected = match key {
+                        Keycode::Up => self.selected.saturating_sub(1),
+                        _ => (self.selected + 1).min(last),
+                    };
+                    true
+                }
+
+                // forget the selected recent server
+                Some(Event::KeyDown {
+                    keycode: Some(Keycode::Delete),
+                    ..
+                }) if self.entry.is_empty() && self.selected < self.recent.servers().len() => {
+                    if let Err(e) = self.recent.remove(self.selected) {
+                        log::warn!("cannot forget server: {e}");
+                    }
+                    self.shown = None; // to list it again if found
+                    self.refresh();
+                    true
+                }
+
+                Some(Event::Window {
+                    win_event: WindowEvent::Resized(w, h),
+                    ..
+                }) => {
+                    unsafe { gl.viewport(0, 0, w, h) };
+                    true
+                }
+
+                Some(Event::Window {
+                    win_event: WindowEvent::Exposed,
+                    ..
+                }) => true,
+
+                _ => false,
+            };
+
+            if self.refresh() || changed {
+                self.draw(gl, window, prog);
+            }
+        }
+    }
+
+    /// Remember `target` as the latest choice and show it being connected to.
+    pub fn connect(&mut self, target: &Target) {
+        if let Err(e) = self.recent.push(target.clone()) {
+            log::warn!("cannot remember server: {e}");
+        }
+        self.connecting = Some((target.name.clone(), ConnState::default()));
+        self.selected = 0;
+    }
+
+    pub fn set_state(&mut self, state: ConnState) {
+        if let Some((_, current)) = &mut self.connecting {
+            *current = state;
+        }
+    }
+
+    /// Draw the launcher with `prog`, in place of frames.
+    pub fn draw(&mut self, gl: &glow::Context, window: &Window, prog: &Program) {
+        let (w, h) = window.size();
+        let canvas = self.render(w as usize, h as usize);
+        unsafe {
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.tex));
+            upload(
+                gl,
+                [0, 0, w as i32, h as i32],
+                layout(PixelFormat::Bgra8),
+                canvas.data(),
+                true,
+            );
+            gl.use_program(Some(prog.native()));
+        }
+        display(gl, window);
+    }
+
+    fn render(&mut self, w: usize, h: usize) -> Canvas {
+        let mut canvas = Canvas::new(w, h);
+        let cell = canvas.cell();
+        let (line, margin, pad) = (cell * 3 / 2, cell * 2, cell / 4);
+        canvas.fill([0, 0, w, h], BACKGROUND);
+
+        let mut y = margin;
+        canvas.text((margin, y), "remdes", TEXT);
+        y += line * 2;
+
+        // address entry, with a cursor while it takes input
+        let x = canvas.text((margin, y), "Address ", MUTED);
+        canvas.fill([x, y - pad, w.saturating_sub(x + margin), line], FIELD);
+        let x = canvas.text((x + pad, y), &self.entry, TEXT);
+        if self.connecting.is_none() {
+            canvas.text((x, y), "_", TEXT);
+        }
+        y += line * 2;
+
+        let recent = self.recent.servers().iter().map(|target| (target, None));
+        let found = self.shown.iter().flatten();
+        let found = found.map(|(target, window)| (target, Some(window.as_str())));
+        let empty = match self.shown {
+            Some(_) => "none found",
+            None => "searching...",
+        };
+        let sections: [(_, Vec<_>, _); 2] = [
+            ("Recent", recent.collect(), "none yet"),
+            ("On this network", found.collect(), empty),
+        ];
+
+        let mut rows = Vec::new();
+        for (heading, servers, empty) in sections {
+            canvas.text((margin, y), heading, MUTED);
+            y += line;
+            if servers.is_empty() {
+                canvas.text((margin + margin, y), empty, MUTED);
+                y += line;
+            }
+            for (target, window) in servers {
+                let row = (y - pad, y - pad + line);
+                if rows.len() == self.selected && self.entry.is_empty() {
+                    canvas.fill(
+                        [margin, row.0, w.saturating_sub(2 * margin), line],
+                        SELECTION,
+                    );
+                }
+                let x = canvas.text((margin + margin, y), &target.to_string(), TEXT);
+                if let Some(window) = window {
+                    canvas.text((x, y), &format!(": {window}"), MUTED);
+                }
+                rows.push(row);
+                y += line;
+            }
+            y += line;
+        }
+        self.rows = rows;
+
+        let status = match &self.connecting {
+            Some((name, state)) => format!("{name}: {}", state.describe()),
+            None => HINT.to_owned(),
+        };
+        canvas.text((margin, h.saturating_sub(margin + cell)), &status, TEXT);
+        canvas
+    }
+
+    /// Hand the window back to the stream.
+    pub fn close(self, gl: &glow::Context) {
+        unsafe { gl.delete_texture(self.tex) };
+        self.text_input.stop();
+        self.mouse.set_relative_mouse_mode(true);
+    }
+}
+
+/// Search the LAN for servers until the launcher is gone.
+fn scan(found: &Weak<Mutex<Found>>) {
+    loop {
+        let servers = match discover(&[BROADCAST], DISCOVER_TIMEOUT) {
+            std::result::Result::Ok(servers) => servers,
+            Err(e) => {
+                log::warn!("LAN discovery failed: {e}");
+                if let Some(found) = found.upgrade() {
+                    found.lock().get_or_insert_default();
+                }
+                return;
+            }
+        };
+        let Some(found) = found.upgrade() else {
+            return;
+        };
+        let servers = servers.iter().map(|(ip, announcement)| {
+            let target = Target::found(*ip, announcement);
+            (target, announcement.window.clone())
+        });
+        *found.lock() = Some(servers.collect());
+        drop(found);
+        sleep(RESCAN_INTERVAL);
+    }
+}
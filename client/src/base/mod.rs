@@ -1,11 +1,23 @@
+mod canvas;
 mod cfg;
+mod ctrl;
+mod discover;
 mod fps;
+mod launcher;
 mod net;
+mod overlay;
+mod recent;
 mod tex;
 mod util;
 
+pub use canvas::*;
 pub use cfg::*;
+pub use ctrl::*;
+pub use discover::*;
 pub use fps::*;
+pub use launcher::*;
 pub use net::*;
+pub use overlay::*;
+pub use recent::*;
 pub use tex::*;
 pub use util::*;
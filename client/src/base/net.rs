@@ -1,19 +1,58 @@
 use crate::*;
+use remdes::{
+    arq::{ArqStream, MEDIA_HELLO},
+    caps::*,
+    codec,
+    proto::*,
+    quic::{QuicDatagrams, QuicStream},
+    rendezvous::{Registrar, punch_through},
+    sock::{BatchReceiver, DatagramSource, set_buffer_sizes},
+};
 use std::{
-    io::Read,
-    net::{TcpStream, UdpSocket},
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    thread::sleep,
     time::Instant,
 };
 
-fn handle_header(buf: &[u8], region: &mut Region) {
-    // deserialize and reset header
-    let header_bytes = &buf[..size_of::<RegionHeader>()];
-    let header = *bytemuck::from_bytes::<RegionHeader>(header_bytes);
-    region.update(header);
+/// Delay before the first reconnection attempt.
+const BACKOFF_MIN: Duration = Duration::from_millis(250);
+
+/// Upper bound on the delay between reconnection attempts.
+const BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Time allowed for the connect and hello exchange.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a UDP receive blocks before the control channel is checked.
+const RECV_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Minimum time between keyframe requests while frames cannot be decoded.
+const KEYFRAME_RETRY: Duration = Duration::from_millis(500);
+
+/// Lifecycle of the connection to the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConnState {
+    #[default]
+    Connecting,
+    Handshaking,
+    Streaming,
+    Reconnecting,
+}
+
+impl ConnState {
+    pub const fn describe(self) -> &'static str {
+        match self {
+            Self::Connecting => "connecting...",
+            Self::Handshaking => "handshaking...",
+            Self::Streaming => "streaming",
+            Self::Reconnecting => "connection lost, reconnecting...",
+        }
+    }
 }
 
 fn init_frame_handler(
-    tx_event: EventSender,
+    tx_event: Arc<EventSender>,
     [rx_frame, rx_render]: [Waiter; 2],
     [frame_og, frame_aux]: [Arc<Mutex<Region>>; 2],
     fps_upt: Arc<FpsUpdater>,
@@ -59,95 +98,361 @@ fn init_frame_handler(
     })
 }
 
-pub fn init_remote(
+/// Connect to the first of `addrs` that accepts within [`CONNECT_TIMEOUT`].
+fn connect_tcp(addrs: &[SocketAddr]) -> Result<TcpStream> {
+    let mut last = anyhow!("No server address");
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+            std::result::Result::Ok(tcp) => return Ok(tcp),
+            Err(e) => last = anyhow!("Server unreachable at {addr}: {e}"),
+        }
+    }
+    Err(last)
+}
+
+/// Where a session's frames arrive from.
+enum Media {
+    /// Raw datagrams, requested from the server's UDP address on a socket
+    /// bound (and perhaps punched through NAT) beforehand.
+    Udp(UdpSocket, SocketAddr),
+    /// The datagrams of the QUIC connection carrying control.
+    Quic(QuicDatagrams),
+}
+
+/// Resources shared across connection attempts.
+struct Link {
     cfg: Config,
-    tx_event: EventSender,
-    frame_og: Arc<Mutex<Region>>,
-    rx_render: Waiter,
-    fps_upt: Arc<FpsUpdater>,
+    ctrl: Control,
+    tx_event: Arc<EventSender>,
+    frame_aux: Arc<Mutex<Region>>,
+    tx_frame: Waker,
     limit_dur: Arc<Limit>,
-) -> JoinHandle<Result<()>> {
-    spawn(move || {
-        let _heartbeat = {
-            let mut tcp = TcpStream::connect(cfg.remote_tcp_addr()).unwrap();
-            tcp.read_exact(&mut [0]).unwrap();
-            tcp.write_all(&[0]).unwrap();
-
-            spawn(move || -> Result<()> {
-                loop {
-                    tcp.read_exact(&mut [0]).unwrap();
-                    tcp.write_all(&[0]).unwrap();
+}
+
+impl Link {
+    fn set_state(&self, state: ConnState) {
+        log::info!("{}", state.describe());
+        _ = self.tx_event.push_custom_event(UserEvent::State(state));
+    }
+
+    /// Bind a UDP socket to reach `remote` from, with the configured buffer.
+    fn bind_udp(&self, remote: SocketAddr) -> Result<UdpSocket> {
+        let udp = UdpSocket::bind(self.cfg.local_udp_addr(remote))?;
+        let requested = self.cfg.recv_buffer();
+        let (_, recv_buffer) = set_buffer_sizes(&udp, (None, requested))?;
+        log::info!("UDP receive buffer: {} KiB", recv_buffer / 1024);
+        if requested.is_some_and(|size| recv_buffer < size) {
+            log::warn!("UDP receive buffer capped by the OS; bursts may be dropped");
+        }
+        Ok(udp)
+    }
+
+    /// Find the server through each registrar in turn, punching through to
+    /// it (or the relay) from fresh control and media sockets, and return
+    /// them with the endpoint reached.
+    fn punch(
+        &self,
+        (registrars, session): (&[Registrar], &str),
+    ) -> Result<(UdpSocket, UdpSocket, SocketAddr)> {
+        let mut last = anyhow!("No rendezvous server or relay");
+        for registrar in registrars {
+            let local = match registrar.addr() {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            };
+            let control = UdpSocket::bind((local, 0))?;
+            let media = self.bind_udp(registrar.addr())?;
+            match punch_through(&[&control, &media], (registrar, session)) {
+                std::result::Result::Ok(server) => {
+                    log::info!("punched through to {server} via {registrar}");
+                    return Ok((control, media, server));
                 }
-            })
+                Err(e) => {
+                    log::warn!("{e} via {registrar}");
+                    last = e;
+                }
+            }
+        }
+        Err(last)
+    }
+
+    /// Establish the control channel and settle the session parameters,
+    /// returning them along with where the frames will come from.
+    fn connect(&self) -> Result<(JoinHandle<Result<()>>, SessionParams, Media)> {
+        let (ctrl, media) = if let Some(session) = self.cfg.session() {
+            let (control, media, server) = self.punch((&self.cfg.registrars()?, session))?;
+            let ctrl = ControlChannel::new(ArqStream::connect_from(control, server)?);
+            (ctrl, Some(Media::Udp(media, server)))
+        } else {
+            let addrs = self.cfg.remote_control_addrs()?;
+            match (self.cfg.quic(), self.cfg.port()) {
+                // nothing answers a UDP connect, so the handshake tells if it worked
+                (false, Some(_)) => {
+                    let addr = addrs.first().ok_or_else(|| anyhow!("No server address"))?;
+                    (ControlChannel::new(ArqStream::connect(*addr)?), None)
+                }
+                (false, None) => (ControlChannel::tcp(connect_tcp(&addrs)?)?, None),
+                (true, _) => {
+                    let addr = *addrs.first().ok_or_else(|| anyhow!("No server address"))?;
+                    let udp = self.bind_udp(addr)?;
+                    let stream = QuicStream::connect(udp, addr, self.cfg.fingerprint())?;
+                    let datagrams = stream.datagrams();
+                    (ControlChannel::new(stream), Some(Media::Quic(datagrams)))
+                }
+            }
+        };
+        let (hello, media) = match media {
+            // QUIC datagrams cannot be fragmented, so chunks must fit in one
+            Some(Media::Quic(datagrams)) => (
+                self.cfg.hello().with_max_datagram(datagrams.max_datagram()),
+                Media::Quic(datagrams),
+            ),
+            Some(media) => (self.cfg.hello(), media),
+            None => {
+                let remote = self.cfg.remote_udp_addr(ctrl.peer_addr()?);
+                (self.cfg.hello(), Media::Udp(self.bind_udp(remote)?, remote))
+            }
         };
 
-        let udp = UdpSocket::bind(cfg.local_udp_addr()).unwrap();
-        udp.connect(cfg.remote_udp_addr()).unwrap();
-        udp.send(&[0]).unwrap();
+        self.set_state(ConnState::Handshaking);
+        ctrl.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        let params = handshake(&ctrl, hello)?;
+        ctrl.set_read_timeout(None)?;
+        log::info!("session: {params:?}");
+        _ = self
+            .tx_event
+            .push_custom_event(UserEvent::Format(params.pixel_format()?));
 
-        // auxillary frame buffer
-        let frame_aux: Arc<Mutex<Region>> = Default::default();
+        self.ctrl.set(ctrl.clone());
+        self.ctrl.send(Message::SetFps(self.limit_dur.fps()));
+        self.ctrl.send(Message::Scale(self.cfg.scale()));
+        let heartbeat = Arc::new(Heartbeat::new(self.cfg.heartbeat(), self.cfg.timeout()));
+        let control = init_control(ctrl, heartbeat, self.tx_event.clone());
+        Ok((control, params, media))
+    }
 
-        // udp receiving
-        let mut buf = vec![0; 2 + UDP_CHUNK_SIZE];
+    /// Receive frames from `media` until the connection is lost.
+    fn stream(
+        &self,
+        control: &JoinHandle<Result<()>>,
+        params: SessionParams,
+        media: Media,
+    ) -> Result<()> {
+        match media {
+            Media::Udp(udp, remote) => {
+                udp.set_read_timeout(Some(RECV_TIMEOUT))?;
+                udp.connect(remote)?;
+                udp.send(&MEDIA_HELLO)?;
+
+                // several datagrams per call where supported
+                let mut batch = BatchReceiver::new(&udp, params.max_datagram(), self.cfg.gro())?;
+                self.receive(control, params, (&mut batch, remote))
+            }
+            Media::Quic(mut datagrams) => {
+                datagrams.set_read_timeout(Some(RECV_TIMEOUT));
+                let remote = datagrams.peer_addr();
+                self.receive(control, params, (&mut datagrams, remote))
+            }
+        }
+    }
+
+    /// Decode the frames arriving from `source` until the connection is lost.
+    fn receive(
+        &self,
+        control: &JoinHandle<Result<()>>,
+        params: SessionParams,
+        (batch, remote): (&mut impl DatagramSource, SocketAddr),
+    ) -> Result<()> {
+        let chunk_size = params.chunk_size();
+        let mut decoder = codec::decoder(params, self.cfg.threads())?;
 
         // local header-info and frame buffer data
         let mut region = Region::default();
+        let mut has_header = false;
+
+        // receiver statistics, reported every second
+        let mut stats = Stats::default();
+        let mut t_stats = Instant::now();
+        let mut drops = 0; // kernel drops already reported
+
+        // chunks announced by and received for the current frame
+        let (mut expected, mut received) = (0u32, 0u32);
+
+        // frame inter-arrival jitter, smoothed as in RFC 3550
+        let mut t_header: Option<Instant> = None;
+        let mut gap = Duration::ZERO;
+        let mut jitter = 0.0f32; // micros
+
+        // last keyframe request, sent whenever a frame cannot be decoded
+        let mut t_keyframe: Option<Instant> = None;
 
-        // attempt to obtain and initialize an initial region
         loop {
-            let n = udp.recv(&mut buf)?;
-            if n != size_of::<RegionHeader>() {
-                continue;
+            match batch.recv() {
+                std::result::Result::Ok(()) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if control.is_finished() {
+                        bail!("Control channel closed");
+                    }
+                    continue;
+                }
+                // an ICMP error came back for the datagrams sent to `remote`
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset
+                    ) =>
+                {
+                    bail!(
+                        "Server UDP port {remote} is unreachable (not listening, or blocked by a firewall)"
+                    )
+                }
+                Err(e) => return Err(e.into()),
+            }
+
+            for datagram in batch.datagrams() {
+                let n = datagram.len();
+                stats.bytes = stats.bytes.saturating_add(n as u32);
+
+                if t_stats.elapsed() >= SECOND {
+                    stats.jitter = jitter as u32;
+                    stats.overflow = batch.drops().wrapping_sub(drops);
+                    drops = batch.drops();
+                    if stats.overflow != 0 {
+                        log::warn!(
+                            "receive buffer overflowed, {} datagrams dropped",
+                            stats.overflow
+                        );
+                    }
+                    self.ctrl.send(Message::Stats(std::mem::take(&mut stats)));
+                    t_stats = Instant::now();
+
+                    if control.is_finished() {
+                        bail!("Control channel closed");
+                    }
+                }
+
+                if n == size_of::<RegionHeader>() {
+                    stats.frames += 1;
+                    stats.lost += expected.saturating_sub(received);
+
+                    let now = Instant::now();
+                    if let Some(prev) = t_header.replace(now) {
+                        let new_gap = now - prev;
+                        let d = new_gap.abs_diff(gap).as_micros() as f32;
+                        jitter += (d - jitter) / 16.0;
+                        gap = new_gap;
+                    }
+
+                    if has_header {
+                        match decoder.finish(&mut region) {
+                            std::result::Result::Ok(complete) => {
+                                // let the server delta against this frame
+                                if complete {
+                                    self.ctrl.send(Message::Ack(region.seq()));
+                                }
+
+                                // bring shared region up-to-date
+                                {
+                                    let mut g = self.frame_aux.lock();
+                                    std::mem::swap(&mut region, &mut g);
+                                }
+
+                                // wake the render handler
+                                self.tx_frame.wake();
+                            }
+                            Err(e) => {
+                                log::debug!("frame dropped: {e}");
+                                if t_keyframe.is_none_or(|t| t.elapsed() >= KEYFRAME_RETRY) {
+                                    self.ctrl.send(Message::RequestKeyframe);
+                                    t_keyframe = Some(Instant::now());
+                                }
+                            }
+                        }
+                    } else {
+                        has_header = true;
+                        self.set_state(ConnState::Streaming);
+                    }
+
+                    // start the next frame
+                    let header: RegionHeader = bytemuck::pod_read_unaligned(datagram);
+                    decoder.begin(header);
+                    expected = header.l().div_ceil(chunk_size) as u32;
+                    received = 0;
+                    stats.chunks += expected;
+
+                    continue;
+                }
+
+                // skip chunks until the first header arrives (and runts)
+                if !has_header {
+                    continue;
+                }
+
+                // each chunk is prefixed by its index within the frame, and is
+                // decompressed off this thread
+                if let Some((idx, payload)) = codec::unseal(datagram)
+                    && decoder.chunk(idx, payload)
+                {
+                    received += 1;
+                }
             }
-            handle_header(&buf, &mut region);
-            break;
         }
+    }
+}
+
+pub fn init_remote(
+    cfg: Config,
+    ctrl: Control,
+    tx_event: Arc<EventSender>,
+    frame_og: Arc<Mutex<Region>>,
+    rx_render: Waiter,
+    fps_upt: Arc<FpsUpdater>,
+    limit_dur: Arc<Limit>,
+) -> JoinHandle<Result<()>> {
+    spawn(move || {
+        // auxillary frame buffer
+        let frame_aux: Arc<Mutex<Region>> = Default::default();
 
         // net & frame-handler communicator
         let (tx_frame, rx_frame) = pair();
 
         // event-handler region communicator
         let _frame_handler = init_frame_handler(
-            tx_event,
+            tx_event.clone(),
             [rx_frame, rx_render],
             [frame_og, frame_aux.clone()],
             fps_upt,
-            limit_dur,
+            limit_dur.clone(),
         );
 
-        // let mut bandwidth = 0;
+        let link = Link {
+            cfg,
+            ctrl,
+            tx_event,
+            frame_aux,
+            tx_frame,
+            limit_dur,
+        };
+        let mut backoff = BACKOFF_MIN;
 
+        // connection state machine
         loop {
-            // let start = Instant::now();
-            let n = udp.recv(&mut buf)?;
-            // bandwidth += n;
+            link.set_state(ConnState::Connecting);
 
-            if n == size_of::<RegionHeader>() {
-                // bring shared region up-to-date
-                {
-                    let mut g = frame_aux.lock();
-                    std::mem::swap(&mut region, &mut g);
+            match link.connect() {
+                std::result::Result::Ok((control, params, media)) => {
+                    backoff = BACKOFF_MIN;
+                    if let Err(e) = link.stream(&control, params, media) {
+                        log::warn!("stream ended: {e}");
+                    }
+                    link.ctrl.close();
                 }
-
-                // wake the render handler
-                tx_frame.wake();
-
-                // reset local region
-                handle_header(&buf, &mut region);
-
-                continue;
+                Err(e) => log::warn!("connect failed: {e}"),
             }
 
-            // TODO - expand & document
-            let idx = u16::from_le_bytes([buf[0], buf[1]]);
-            let payload_compressed = &buf[2..n];
-            let payload =
-                lz4::block::decompress(payload_compressed, Some(UDP_CHUNK_SIZE as i32)).unwrap();
-            let chunk_len = payload.len();
-            let start = UDP_CHUNK_SIZE * idx as usize;
-            let end = start + chunk_len;
-            region.data_mut()[start..end].copy_from_slice(payload.as_slice());
+            link.set_state(ConnState::Reconnecting);
+            sleep(backoff);
+            backoff = (backoff * 2).min(BACKOFF_MAX);
         }
     })
 }
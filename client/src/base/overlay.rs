@@ -0,0 +1,70 @@
+use crate::*;
+use bytemuck::{Pod, Zeroable};
+
+/// Uniform block binding shared with `simple.frag` and `yuv.frag`.
+const OVERLAY_BINDING: u32 = 1;
+
+/// std140 layout of the `Overlay` uniform block.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct OverlayBlock {
+    status: [f32; 4], // status bar colour (alpha = visibility)
+    dim: f32,         // frame brightness multiplier
+    _pad: [f32; 3],
+}
+
+impl OverlayBlock {
+    const fn of(state: ConnState) -> Self {
+        let (status, dim) = match state {
+            ConnState::Streaming => ([0.0; 4], 1.0),
+            ConnState::Connecting | ConnState::Handshaking => ([0.2, 0.5, 1.0, 1.0], 0.4),
+            ConnState::Reconnecting => ([1.0, 0.6, 0.1, 1.0], 0.4),
+        };
+        Self {
+            status,
+            dim,
+            _pad: [0.0; 3],
+        }
+    }
+}
+
+/// Dims the last frame and draws a status bar while not streaming.
+pub struct Overlay {
+    ubo: glow::NativeBuffer,
+}
+
+impl Overlay {
+    pub fn new(gl: &glow::Context) -> Result<Self> {
+        unsafe {
+            let ubo = gl.create_buffer().map_err(|e| anyhow!(e))?;
+            gl.bind_buffer(glow::UNIFORM_BUFFER, Some(ubo));
+            gl.buffer_data_u8_slice(
+                glow::UNIFORM_BUFFER,
+                bytemuck::bytes_of(&OverlayBlock::of(ConnState::default())),
+                glow::DYNAMIC_DRAW,
+            );
+            gl.bind_buffer_base(glow::UNIFORM_BUFFER, OVERLAY_BINDING, Some(ubo));
+            Ok(Self { ubo })
+        }
+    }
+
+    pub fn update(&self, gl: &glow::Context, state: ConnState) {
+        self.set(gl, OverlayBlock::of(state));
+    }
+
+    /// Show the texture as is, e.g. the launcher's.
+    pub fn hide(&self, gl: &glow::Context) {
+        self.set(gl, OverlayBlock::of(ConnState::Streaming));
+    }
+
+    fn set(&self, gl: &glow::Context, block: OverlayBlock) {
+        unsafe {
+            gl.bind_buffer(glow::UNIFORM_BUFFER, Some(self.ubo));
+            gl.buffer_sub_data_u8_slice(glow::UNIFORM_BUFFER, 0, bytemuck::bytes_of(&block));
+        }
+    }
+
+    pub fn delete(&self, gl: &glow::Context) {
+        unsafe { gl.delete_buffer(self.ubo) }
+    }
+}
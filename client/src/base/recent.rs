@@ -0,0 +1,128 @@
+use crate::*;
+use remdes::{
+    discovery::{Announcement, Ports},
+    util::config_dir,
+};
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, path::PathBuf};
+
+/// Servers remembered by the launcher.
+const MAX_RECENT: usize = 8;
+
+/// File in the config directory the launcher remembers servers in.
+const RECENT_FILE: &str = "recent.toml";
+
+/// A server the launcher can connect to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    /// What the launcher lists the server as.
+    pub name: String,
+    pub host: String,
+    /// How the server said to reach it, if it was found on the LAN; typed
+    /// addresses are reached the way the command line says.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ports: Option<Ports>,
+}
+
+impl Target {
+    /// A hostname or address typed by the user.
+    pub fn typed(host: &str) -> Self {
+        Self {
+            name: host.to_owned(),
+            host: host.to_owned(),
+            ports: None,
+        }
+    }
+
+    /// A server that answered discovery from `ip`.
+    pub fn found(ip: IpAddr, announcement: &Announcement) -> Self {
+        Self {
+            name: announcement.hostname.clone(),
+            host: ip.to_string(),
+            ports: Some(announcement.ports.clone()),
+        }
+    }
+
+    /// Whether both lead to the same server, whatever it is called.
+    pub fn same(&self, other: &Self) -> bool {
+        self.host == other.host && self.ports == other.ports
+    }
+
+    /// Point `cfg` at the server.
+    pub fn apply(&self, cfg: Config) -> Config {
+        match (&self.ports, self.host.parse()) {
+            (Some(ports), std::result::Result::Ok(ip)) => cfg.with_server(ip, ports),
+            _ => cfg.with_host(&self.host),
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)?;
+        match &self.ports {
+            Some(ports) => write!(f, " ({}, {ports})", self.host),
+            None if self.host != self.name => write!(f, " ({})", self.host),
+            None => std::result::Result::Ok(()),
+        }
+    }
+}
+
+/// Servers connected to from the launcher, most recent first.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Recent {
+    #[serde(default)]
+    servers: Vec<Target>,
+}
+
+impl Recent {
+    fn path() -> Option<PathBuf> {
+        Some(config_dir()?.join(RECENT_FILE))
+    }
+
+    /// Load the remembered servers, starting afresh if there are none or
+    /// the file cannot be read.
+    pub fn load() -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default();
+        };
+        let text = match std::fs::read_to_string(&path) {
+            std::result::Result::Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                log::warn!("cannot read {}: {e}", path.display());
+                return Self::default();
+            }
+        };
+        toml::from_str(&text)
+            .inspect_err(|e| log::warn!("ignoring {}: {e}", path.display()))
+            .unwrap_or_default()
+    }
+
+    pub fn servers(&self) -> &[Target] {
+        &self.servers
+    }
+
+    /// Remember `target` as the latest choice.
+    pub fn push(&mut self, target: Target) -> Result<()> {
+        self.servers.retain(|other| !other.same(&target));
+        self.servers.insert(0, target);
+        self.servers.truncate(MAX_RECENT);
+        self.save()
+    }
+
+    /// Forget the `i`th server.
+    pub fn remove(&mut self, i: usize) -> Result<()> {
+        self.servers.remove(i);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = Self::path().ok_or_else(|| anyhow!("No config directory"))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, toml::to_string(self)?)
+            .map_err(|e| anyhow!("Cannot write {}: {e}", path.display()))
+    }
+}
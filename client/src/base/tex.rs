@@ -1,10 +1,76 @@
 use glow::{HasContext, PixelUnpackData};
-use remdes::Region;
+use remdes::{Region, caps::PixelFormat};
+
+/// Texture units of the chroma planes, matching the bindings in `yuv.frag`.
+const CHROMA_UNITS: [u32; 2] = [glow::TEXTURE2, glow::TEXTURE3];
+
+/// Internal format, pixel format and pixel type of a texture upload.
+type Layout = (u32, u32, u32);
+
+/// How each wire pixel format is uploaded (the luma plane, for YUV).
+pub const fn layout(format: PixelFormat) -> Layout {
+    match format {
+        PixelFormat::Bgra8 => (glow::RGBA8, glow::BGRA, glow::UNSIGNED_BYTE),
+        PixelFormat::Bgrx8 => (glow::RGB8, glow::BGR, glow::UNSIGNED_BYTE),
+        PixelFormat::Rgb565 => (glow::RGB8, glow::RGB, glow::UNSIGNED_SHORT_5_6_5),
+        PixelFormat::Yuv420 | PixelFormat::Gray8 => (glow::R8, glow::RED, glow::UNSIGNED_BYTE),
+    }
+}
+
+/// Create a texture sampled linearly and clamped at the edges.
+pub unsafe fn create_texture(gl: &glow::Context) -> glow::NativeTexture {
+    unsafe {
+        let tex = gl.create_texture().expect("Cannot create texture");
+        gl.bind_texture(glow::TEXTURE_2D, Some(tex));
+
+        for (param, value) in [
+            (glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE),
+            (glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE),
+            (glow::TEXTURE_MIN_FILTER, glow::LINEAR),
+            (glow::TEXTURE_MAG_FILTER, glow::LINEAR),
+        ] {
+            gl.tex_parameter_i32(glow::TEXTURE_2D, param, value as i32);
+        }
+        tex
+    }
+}
+
+/// Upload `data` to the texture bound to the active unit, reallocating it if
+/// `realloc` is set.
+pub unsafe fn upload(
+    gl: &glow::Context,
+    [x, y, w, h]: [i32; 4],
+    (internal, format, ty): Layout,
+    data: &[u8],
+    realloc: bool,
+) {
+    let data = PixelUnpackData::Slice(Some(data));
+    unsafe {
+        if realloc {
+            gl.tex_image_2d(
+                glow::TEXTURE_2D,
+                0,
+                internal as i32,
+                w,
+                h,
+                0,
+                format,
+                ty,
+                data,
+            );
+        } else {
+            // update only changed pixels
+            gl.tex_sub_image_2d(glow::TEXTURE_2D, 0, x, y, w, h, format, ty, data);
+        }
+    }
+}
 
 pub struct Texture2D {
     pub vao: glow::NativeVertexArray,
     pub _vbo: glow::NativeBuffer,
     pub tex: glow::NativeTexture,
+    pub planes: [glow::NativeTexture; 2], // chroma, for YUV
+    pub format: PixelFormat,
     pub width: i32,
     pub height: i32,
 }
@@ -44,82 +110,95 @@ impl Texture2D {
             gl.vertex_attrib_pointer_f32(1, 2, glow::FLOAT, false, stride, 2 * 4);
 
             // --- Texture setup ---
-            let tex = gl.create_texture().expect("Cannot create texture");
-            gl.bind_texture(glow::TEXTURE_2D, Some(tex));
+            let planes = CHROMA_UNITS.map(|unit| {
+                gl.active_texture(unit);
+                create_texture(gl)
+            });
+            gl.active_texture(glow::TEXTURE0);
+            let tex = create_texture(gl);
             gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 1);
 
-            // --- Texture params ---
-            gl.tex_parameter_i32(
-                glow::TEXTURE_2D,
-                glow::TEXTURE_WRAP_S,
-                glow::CLAMP_TO_EDGE as i32,
-            );
-            gl.tex_parameter_i32(
-                glow::TEXTURE_2D,
-                glow::TEXTURE_WRAP_T,
-                glow::CLAMP_TO_EDGE as i32,
-            );
-            gl.tex_parameter_i32(
-                glow::TEXTURE_2D,
-                glow::TEXTURE_MIN_FILTER,
-                glow::LINEAR as i32,
-            );
-            gl.tex_parameter_i32(
-                glow::TEXTURE_2D,
-                glow::TEXTURE_MAG_FILTER,
-                glow::LINEAR as i32,
-            );
-
             Self {
                 vao,
                 _vbo: vbo, // TODO - currently not modifying vertex data,
                 tex,
+                planes,
+                format: PixelFormat::Bgra8,
                 width: 0,
                 height: 0,
             }
         }
     }
 
+    /// Switch to a new wire pixel format, reallocating on the next update.
+    pub fn set_format(&mut self, gl: &glow::Context, format: PixelFormat) {
+        self.format = format;
+        self.width = 0;
+        self.height = 0;
+
+        unsafe { gl.bind_texture(glow::TEXTURE_2D, Some(self.tex)) };
+
+        // spread luma over every channel for grayscale
+        let swizzle = match format {
+            PixelFormat::Gray8 => [glow::RED, glow::RED, glow::RED, glow::ONE],
+            _ => [glow::RED, glow::GREEN, glow::BLUE, glow::ALPHA],
+        };
+        let params = [
+            glow::TEXTURE_SWIZZLE_R,
+            glow::TEXTURE_SWIZZLE_G,
+            glow::TEXTURE_SWIZZLE_B,
+            glow::TEXTURE_SWIZZLE_A,
+        ];
+        for (param, value) in params.into_iter().zip(swizzle) {
+            unsafe { gl.tex_parameter_i32(glow::TEXTURE_2D, param, value as i32) };
+        }
+    }
+
     pub fn update(&mut self, gl: &glow::Context, f: &Region) {
+        let [x, y, w, h] = [f.x(), f.y(), f.w(), f.h()];
+        if w == 0 || h == 0 {
+            return;
+        }
+
+        // (Re)allocate texture if the size changed or it's the first time
+        let realloc = self.width != w || self.height != h;
+        if realloc {
+            self.width = w;
+            self.height = h;
+
+            // the quad stretches the encoded frame back over the window
+            log::info!("frame {w}x{h} (source {}x{})", f.src_w(), f.src_h());
+        }
+
+        let plane_layout = layout(self.format);
+        let (w_us, h_us) = (w as usize, h as usize);
+        let luma_len = (w_us * h_us).min(f.data().len());
         unsafe {
-            let [x, y, w, h] = [f.x(), f.y(), f.w(), f.h()];
-
-            // (Re)allocate texture if the size changed or it's the first time
-            if self.width != w || self.height != h {
-                self.width = w;
-                self.height = h;
-
-                gl.tex_image_2d(
-                    glow::TEXTURE_2D,
-                    0,
-                    glow::RGBA8 as i32,
-                    w,
-                    h,
-                    0,
-                    glow::BGRA,
-                    glow::UNSIGNED_BYTE,
-                    PixelUnpackData::Slice(Some(f.data())),
-                );
-            } else {
-                // update only changed pixels
-                gl.tex_sub_image_2d(
-                    glow::TEXTURE_2D,
-                    0,
-                    x,
-                    y,
-                    w,
-                    h,
-                    glow::BGRA,
-                    glow::UNSIGNED_BYTE,
-                    PixelUnpackData::Slice(Some(f.data())),
-                );
+            if self.format != PixelFormat::Yuv420 {
+                upload(gl, [x, y, w, h], plane_layout, f.data(), realloc);
+                return;
             }
+
+            // luma, then each chroma plane on its own unit
+            let (luma, chroma) = f.data().split_at(luma_len);
+            upload(gl, [x, y, w, h], plane_layout, luma, realloc);
+
+            let (cw, ch) = PixelFormat::chroma_size((w_us, h_us));
+            let rect = [x / 2, y / 2, cw as i32, ch as i32];
+            for (unit, plane) in CHROMA_UNITS.into_iter().zip(chroma.chunks(cw * ch)) {
+                gl.active_texture(unit);
+                upload(gl, rect, plane_layout, plane, realloc);
+            }
+            gl.active_texture(glow::TEXTURE0);
         }
     }
 
     pub fn delete(&self, gl: &glow::Context) {
         unsafe {
             gl.delete_texture(self.tex);
+            for plane in self.planes {
+                gl.delete_texture(plane);
+            }
             gl.delete_vertex_array(self.vao);
         }
     }
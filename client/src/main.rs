@@ -10,7 +10,7 @@ use anyhow::*;
 use atomic_enum::*;
 use glow::HasContext;
 use parking_lot::Mutex;
-use remdes::*;
+use remdes::{caps::PixelFormat, proto::*, *};
 use sdl2::{event::*, keyboard::*, video::*};
 use spin_sleep::SpinSleeper;
 use std::{
@@ -38,10 +38,12 @@ pub enum RenderStateKind {
 pub enum UserEvent {
     Render,
     Fps(u8),
+    State(ConnState),
+    Format(PixelFormat),
 }
 
 /// Render the texture
-fn display(gl: &glow::Context, window: &Window) {
+pub fn display(gl: &glow::Context, window: &Window) {
     unsafe {
         gl.clear(glow::COLOR_BUFFER_BIT);
         gl.draw_arrays(glow::TRIANGLE_STRIP, 0, 4);
@@ -53,15 +55,21 @@ fn display(gl: &glow::Context, window: &Window) {
 }
 
 /// Event loop: handles new textures and updates VBO with scale
+#[allow(clippy::too_many_arguments)]
 fn event_loop(
     gl: &glow::Context,
-    window: Window,
+    mut window: Window,
     mut ep: sdl2::EventPump,
-    tex: &mut Texture2D,
+    (tex, overlay, progs): (&mut Texture2D, &Overlay, &Shaders),
+    mut launcher: Option<Launcher>,
     frame: Arc<Mutex<Region>>,
     tx_render: Waker,
-    set_fps_limit: &mut impl FnMut(u8),
+    ctrl: &Control,
+    (set_fps_limit, mut fps): (&mut impl FnMut(u8), u8),
 ) {
+    let mut state = ConnState::default();
+    let mut prog = progs.simple();
+
     for event in ep.wait_iter() {
         match event {
             Event::Quit { .. }
@@ -70,11 +78,34 @@ fn event_loop(
                 ..
             } => break,
 
+            // step the fps target
+            Event::KeyDown {
+                keycode: Some(key @ (Keycode::Up | Keycode::Down)),
+                repeat: false,
+                ..
+            } => {
+                fps = step_fps(fps, key == Keycode::Up);
+                log::info!("fps target: {fps}");
+                set_fps_limit(fps);
+                ctrl.send(Message::SetFps(fps));
+            }
+
             Event::Window {
                 win_event: WindowEvent::Resized(w, h),
                 ..
             } => {
                 unsafe { gl.viewport(0, 0, w, h) };
+
+                // no frames arrive to redraw the overlay
+                if let Some(launcher) = &mut launcher {
+                    launcher.draw(gl, &window, progs.simple());
+                } else if state != ConnState::Streaming {
+                    display(gl, &window);
+                }
+                ctrl.send(Message::Resize {
+                    w: w as u16,
+                    h: h as u16,
+                });
             }
 
             Event::User { .. } => {
@@ -88,23 +119,104 @@ fn event_loop(
                     }
 
                     // set a new fps target
-                    UserEvent::Fps(fps) => set_fps_limit(fps),
+                    UserEvent::Fps(target) => {
+                        fps = target;
+                        set_fps_limit(fps);
+                        ctrl.send(Message::SetFps(fps));
+                    }
+
+                    // wire pixel format of a new session
+                    UserEvent::Format(format) => {
+                        tex.set_format(gl, format);
+                        prog = match format {
+                            PixelFormat::Yuv420 => progs.yuv(),
+                            _ => progs.simple(),
+                        };
+                        unsafe { gl.use_program(Some(prog.native())) };
+                    }
+
+                    // keep showing the last frame, dimmed, until streaming resumes
+                    UserEvent::State(new_state) => {
+                        state = new_state;
+
+                        // the launcher shows how connecting goes until frames arrive
+                        match launcher.take() {
+                            Some(launcher) if state == ConnState::Streaming => {
+                                launcher.close(gl);
+                                unsafe {
+                                    gl.bind_texture(glow::TEXTURE_2D, Some(tex.tex));
+                                    gl.use_program(Some(prog.native()));
+                                }
+                                overlay.update(gl, state);
+                            }
+                            Some(mut shown) => {
+                                shown.set_state(state);
+                                shown.draw(gl, &window, progs.simple());
+                                launcher = Some(shown);
+                            }
+                            None => {
+                                overlay.update(gl, state);
+                                display(gl, &window);
+                            }
+                        }
+
+                        let title = match state {
+                            ConnState::Streaming => "remdes".to_string(),
+                            _ => format!("remdes - {}", state.describe()),
+                        };
+                        _ = window.set_title(&title);
+                    }
                 }
             }
             _ => (),
         }
     }
+
+    if let Some(launcher) = launcher {
+        launcher.close(gl);
+    }
 }
 
 fn main() -> Result<()> {
-    let cfg = Config::default();
+    let mut cfg = Config::load()?;
     env_logger::init();
 
+    // pick a server before any window opens
+    if cfg.discover() {
+        cfg = pick_server(cfg)?;
+    }
+
     // Setup SDL and OpenGL
-    let (_sdl, _video, window, ev, ep, _ctx, gl) = init()?;
+    let (sdl, video, window, ev, mut ep, _ctx, gl) = init()?;
 
     // Initialize shader and texture
     let progs = Shaders::init(&gl)?;
+    let mut tex = Texture2D::new(&gl);
+    let overlay = Overlay::new(&gl)?;
+    unsafe {
+        gl.use_program(Some(progs.simple().native()));
+        gl.bind_vertex_array(Some(tex.vao));
+    }
+
+    // let the user pick a server in the window, none being given
+    let launcher = match cfg.launcher() {
+        true => {
+            let mut launcher = Launcher::new(&gl, &sdl, &video);
+            overlay.hide(&gl);
+            let Some(target) = launcher.pick(&gl, &window, &mut ep, progs.simple()) else {
+                launcher.close(&gl);
+                progs.delete(&gl);
+                tex.delete(&gl);
+                overlay.delete(&gl);
+                return Ok(());
+            };
+            log::info!("connecting to {target}");
+            launcher.connect(&target);
+            cfg = target.apply(Config::load_for(&target.host)?);
+            Some(launcher)
+        }
+        false => None,
+    };
 
     // frames-per-second facilitation
     let (fps, fps_upt, limit, limit_dur): (Arc<Fps>, Arc<FpsUpdater>, Arc<AtomicU8>, Arc<Limit>) =
@@ -127,37 +239,38 @@ fn main() -> Result<()> {
     let (tx_render, rx_render) = pair();
     let frame: Arc<Mutex<Region>> = Default::default();
 
+    // control channel to the server
+    let ctrl = Control::default();
+
     // networking thread
     let _conn = init_remote(
-        cfg,
-        ev.event_sender(),
+        cfg.clone(),
+        ctrl.clone(),
+        Arc::new(ev.event_sender()),
         frame.clone(),
         rx_render,
         fps_upt,
         limit_dur,
     );
 
-    // texture for frame data
-    let mut tex = Texture2D::new(&gl);
-    unsafe {
-        gl.use_program(Some(progs.simple().native()));
-        gl.bind_vertex_array(Some(tex.vao));
-    }
-
     // start polling input and custom events
     event_loop(
         &gl,
         window,
         ep,
-        &mut tex,
+        (&mut tex, &overlay, &progs),
+        launcher,
         frame,
         tx_render,
-        &mut set_fps_limit,
+        &ctrl,
+        (&mut set_fps_limit, cfg.fps()),
     );
 
     // clean up
+    ctrl.send(Message::Goodbye(GoodbyeReason::Quit));
     progs.delete(&gl);
     tex.delete(&gl);
+    overlay.delete(&gl);
 
     Ok(())
 }
@@ -0,0 +1,74 @@
+//! Headless stand-ins for the server and client that only find each other
+//! and exchange one control message, for testing rendezvous and relaying
+//! without a window. A rendezvous of `-` leaves it out.
+//!
+//! ```text
+//! cargo run --example punch -- server <SESSION> <BIND> <RENDEZVOUS> [<RELAY> <SECRET>]
+//! cargo run --example punch -- client <SESSION> <RENDEZVOUS> [<RELAY> <SECRET>]
+//! ```
+
+use remdes::{arq::*, proto::*, rendezvous::*, util::*, *};
+use std::{net::UdpSocket, sync::Arc, thread::spawn, time::Duration};
+
+const USAGE: &str = "Usage: punch server SESSION BIND RENDEZVOUS [RELAY SECRET]
+       punch client SESSION RENDEZVOUS [RELAY SECRET]";
+
+/// Registrars in order of preference.
+fn registrars(args: &[String]) -> Result<Vec<Registrar>> {
+    let (rendezvous, relay) = match args {
+        [rendezvous] => (rendezvous.as_str(), None),
+        [rendezvous, relay, secret] => {
+            (rendezvous.as_str(), Some((relay.as_str(), secret.as_str())))
+        }
+        _ => bail!(USAGE),
+    };
+    Registrar::resolve_hosts(Some(rendezvous).filter(|&r| r != "-"), relay)
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [role, session, bind, rest @ ..] if role == "server" => {
+            let registrars = registrars(rest)?;
+            let udp = UdpSocket::bind(resolve(bind, UDP_PORT)?)?;
+            let listener = Arc::new(ArqListener::new(&udp)?);
+            {
+                let (listener, session) = (listener.clone(), session.clone());
+                spawn(move || advertise(&listener, (&registrars, &session)));
+            }
+            println!("Registered as {session:?} @ {:?}", udp.local_addr()?);
+
+            let ctrl = ControlChannel::new(listener.accept()?);
+            let msg = ctrl.recv()?;
+            println!("{msg:?} from {:?}", ctrl.peer_addr()?);
+            ctrl.send(Message::Ack(0))?;
+            // give the acknowledgement time to arrive
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        [role, session, rest @ ..] if role == "client" => {
+            let mut last = anyhow!("No rendezvous or relay");
+            for registrar in registrars(rest)? {
+                let (control, media) =
+                    (UdpSocket::bind("0.0.0.0:0")?, UdpSocket::bind("0.0.0.0:0")?);
+                let server = match punch_through(&[&control, &media], (&registrar, session)) {
+                    std::result::Result::Ok(server) => server,
+                    Err(e) => {
+                        println!("{e}");
+                        last = e;
+                        continue;
+                    }
+                };
+                println!("Punched through to {server:?}");
+
+                let ctrl = ControlChannel::new(ArqStream::connect_from(control, server)?);
+                ctrl.set_read_timeout(Some(Duration::from_secs(5)))?;
+                ctrl.send(Message::SetFps(60))?;
+                println!("{:?} from {server:?}", ctrl.recv()?);
+                return Ok(());
+            }
+            return Err(last);
+        }
+        _ => bail!(USAGE),
+    }
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use clap::Parser;
+use remdes::{
+    relay::{Relay, Secret},
+    util::resolve,
+    *,
+};
+use std::net::UdpSocket;
+
+/// Forwards control and media between servers and clients that cannot reach each other.
+#[derive(Parser, Debug)]
+struct Config {
+    /// Host or address to listen on (0.0.0.0 or :: for every interface).
+    #[arg(long, default_value = "0.0.0.0")]
+    bind: String,
+
+    /// UDP port to listen on.
+    #[arg(long, default_value_t = RELAY_PORT)]
+    port: u16,
+
+    /// Secret servers and clients must sign their registrations with.
+    #[arg(long)]
+    secret: String,
+
+    /// Maximum rate of each session in Mbit/s, both directions together (0 = unlimited).
+    #[arg(long, default_value_t = 50)]
+    max_bitrate: u32,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cfg = Config::parse();
+
+    let max_bitrate = match cfg.max_bitrate {
+        0 => None,
+        mbits => Some(mbits as u64 * 1_000_000),
+    };
+    let udp = UdpSocket::bind(resolve(&cfg.bind, cfg.port)?)?;
+    let relay = Relay::new(udp, Secret::new(&cfg.secret), max_bitrate);
+    println!("Relay listening @ {:?}", relay.local_addr()?);
+    match max_bitrate {
+        Some(bits) => println!("Sessions capped at {} Mbit/s\n", bits / 1_000_000),
+        None => println!("Sessions uncapped\n"),
+    }
+
+    relay.run(|role, session, addr| println!("\t{role:?} {addr:?} joined {session:?}"))
+}
@@ -0,0 +1,24 @@
+use clap::Parser;
+use remdes::{rendezvous::Rendezvous, util::resolve, *};
+use std::net::UdpSocket;
+
+/// Introduces servers and clients behind NAT to each other by session ID.
+#[derive(Parser, Debug)]
+struct Config {
+    /// Host or address to listen on (0.0.0.0 or :: for every interface).
+    #[arg(long, default_value = "0.0.0.0")]
+    bind: String,
+
+    /// UDP port to listen on.
+    #[arg(long, default_value_t = RENDEZVOUS_PORT)]
+    port: u16,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cfg = Config::parse();
+
+    let mut rendezvous = Rendezvous::new(UdpSocket::bind(resolve(&cfg.bind, cfg.port)?)?);
+    println!("Rendezvous listening @ {:?}", rendezvous.local_addr()?);
+
+    rendezvous.run(|role, session, addr| println!("\t{role:?} {addr:?} joined {session:?}"))
+}
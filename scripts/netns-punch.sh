@@ -0,0 +1,59 @@
+#!/bin/sh
+# Punch through between two network namespaces on one Linux box (as root).
+#
+# remdes-srv (10.200.0.1) runs the rendezvous server, the relay and a
+# headless server, remdes-cli (10.200.0.2) a headless client. Where iptables
+# is installed, each namespace drops inbound UDP it did not ask for, as a NAT
+# would, so the exchange only succeeds once both sides have punched.
+#
+# With `relay` as the argument, the client skips the rendezvous server and
+# goes through the relay instead.
+set -eu
+
+SESSION=netns
+SECRET=netns-secret
+RENDEZVOUS=10.200.0.1
+[ "${1:-}" = relay ] && RENDEZVOUS=-
+cd "$(dirname "$0")/.."
+cargo build -q -p rendezvous -p relay
+cargo build -q --example punch
+BIN=target/debug
+
+cleanup() {
+    kill $PIDS 2>/dev/null || true
+    ip netns del remdes-srv 2>/dev/null || true
+    ip netns del remdes-cli 2>/dev/null || true
+}
+PIDS=
+trap cleanup EXIT
+
+ip netns add remdes-srv
+ip netns add remdes-cli
+ip link add remdes-srv0 netns remdes-srv type veth peer name remdes-cli0 netns remdes-cli
+ip -n remdes-srv addr add 10.200.0.1/24 dev remdes-srv0
+ip -n remdes-cli addr add 10.200.0.2/24 dev remdes-cli0
+for ns in remdes-srv remdes-cli; do
+    ip -n $ns link set lo up
+    ip -n $ns link set $ns"0" up
+done
+
+if command -v iptables >/dev/null; then
+    for ns in remdes-srv remdes-cli; do
+        ip netns exec $ns iptables -A INPUT -m conntrack --ctstate ESTABLISHED,RELATED -j ACCEPT
+        ip netns exec $ns iptables -A INPUT -p udp --dport 54297 -j ACCEPT
+        ip netns exec $ns iptables -A INPUT -p udp --dport 54307 -j ACCEPT
+        ip netns exec $ns iptables -A INPUT -p udp -j DROP
+    done
+else
+    echo "iptables not found; testing without a firewall"
+fi
+
+ip netns exec remdes-srv $BIN/rendezvous --bind 10.200.0.1 &
+PIDS="$PIDS $!"
+ip netns exec remdes-srv $BIN/relay --bind 10.200.0.1 --secret $SECRET &
+PIDS="$PIDS $!"
+ip netns exec remdes-srv $BIN/examples/punch server $SESSION 10.200.0.1 10.200.0.1 10.200.0.1 $SECRET &
+PIDS="$PIDS $!"
+sleep 1
+
+ip netns exec remdes-cli $BIN/examples/punch client $SESSION $RENDEZVOUS 10.200.0.1 $SECRET
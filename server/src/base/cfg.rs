@@ -1,8 +1,17 @@
 use crate::*;
-use clap::Parser;
-use remdes::util::get_socket_addr;
+use clap::{ArgGroup, Parser};
+use remdes::{
+    caps::*,
+    rendezvous::Registrar,
+    scale::Scale,
+    session::SendOptions,
+    util::{check_heartbeat, parse_millis, resolve},
+};
 use std::{net::SocketAddr, time::Duration};
 
+/// File in the config directory holding the server's settings.
+const CONFIG_FILE: &str = "server.toml";
+
 /// Calculates the duration of a single game tick.
 fn parse_tps(s: &str) -> Result<Duration> {
     let tps = s.parse::<f32>()?;
@@ -10,28 +19,101 @@ fn parse_tps(s: &str) -> Result<Duration> {
     if tps == 0.0 {
         bail!("TPS must be greater than zero.")
     } else if tps > 1024.0 {
-        bail!("TPS must be less than or equal to 256.")
+        bail!("TPS must be less than or equal to 1024.")
     }
     Ok(remdes::util::tick_dur(tps))
 }
 
 #[derive(Parser, Debug)]
+#[command(group(ArgGroup::new("registrar").args(["rendezvous", "relay"]).multiple(true)))]
 pub struct Config {
     /// Target window whose title contains the given substring.
     #[arg(short, long)]
     window: String,
 
-    /// Local TCP address.
-    #[arg(long, default_value_t = get_socket_addr(TCP_PORT))]
-    lt: SocketAddr,
+    /// Host or address to listen on (0.0.0.0 or :: for every interface).
+    #[arg(long, default_value = "127.0.0.1")]
+    bind: String,
+
+    /// Serve control and media on this one UDP port, instead of TCP 54277 and UDP 54287.
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Serve over QUIC on the UDP port: encrypted control on a stream, frames as datagrams.
+    #[arg(long)]
+    quic: bool,
+
+    /// Register with this rendezvous server (HOST[:PORT]) so clients behind NAT can punch through; implies single-port mode.
+    #[arg(long, requires = "session", conflicts_with = "quic")]
+    rendezvous: Option<String>,
+
+    /// Also register with this relay (HOST[:PORT]), for clients that cannot punch through; implies single-port mode.
+    #[arg(long, requires_all = ["session", "relay_secret"], conflicts_with = "quic")]
+    relay: Option<String>,
+
+    /// Secret shared with the relay.
+    #[arg(long, requires = "relay")]
+    relay_secret: Option<String>,
 
-    /// Local UDP address.
-    #[arg(long, default_value_t = get_socket_addr(UDP_PORT))]
-    lu: SocketAddr,
+    /// Session ID clients ask the rendezvous server or relay for.
+    #[arg(long, requires = "registrar")]
+    session: Option<String>,
+
+    /// Do not answer LAN discovery (UDP 54317), which is otherwise on unless bound to loopback.
+    #[arg(long)]
+    no_announce: bool,
+
+    /// Local TCP address (overrides --bind).
+    #[arg(long)]
+    lt: Option<SocketAddr>,
+
+    /// Local UDP address (overrides --bind).
+    #[arg(long)]
+    lu: Option<SocketAddr>,
 
     /// Server ticks/sec.
     #[arg(short, long, default_value = "128", value_parser = parse_tps)]
     tps: Duration,
+
+    /// Maximum send rate in Mbit/s (unlimited if omitted).
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_bitrate: Option<u32>,
+
+    /// Downscale frames by a factor (e.g. 0.5) or to fit a size (e.g. 1280x720).
+    #[arg(long, default_value_t = Scale::Full)]
+    scale: Scale,
+
+    /// Worker threads compressing chunks (0 = one per core).
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+
+    /// Coalesce equal-sized datagrams with UDP GSO (Linux only).
+    #[arg(long)]
+    gso: bool,
+
+    /// Kernel send buffer of the UDP socket in KiB (0 keeps the OS default).
+    #[arg(long, default_value_t = 4096)]
+    send_buffer: usize,
+
+    /// Milliseconds between forced keyframes (0 disables).
+    #[arg(long, default_value_t = 2000)]
+    keyframe_interval: u64,
+
+    /// Restrict the codec (lz4 or h264).
+    #[arg(long)]
+    codec: Option<Codec>,
+
+    /// Restrict the wire pixel format (bgra8, bgrx8, rgb565, yuv420 or gray8).
+    #[arg(long)]
+    pixel_format: Option<PixelFormat>,
+
+    /// Heartbeat interval in milliseconds, shorter than --timeout.
+    #[arg(long, default_value = "250", value_parser = parse_millis)]
+    heartbeat: Duration,
+
+    /// Milliseconds of client silence before it is declared dead.
+    #[arg(long, default_value = "2000", value_parser = parse_millis)]
+    timeout: Duration,
 }
 
 impl Config {
@@ -39,21 +121,108 @@ impl Config {
         self.window.as_str()
     }
 
-    pub const fn local_tcp_addr(&self) -> SocketAddr {
-        self.lt
+    pub fn local_tcp_addr(&self) -> Result<SocketAddr> {
+        self.lt.map_or_else(|| resolve(&self.bind, TCP_PORT), Ok)
+    }
+
+    pub fn local_udp_addr(&self) -> Result<SocketAddr> {
+        let port = self.port.unwrap_or(UDP_PORT);
+        self.lu.map_or_else(|| resolve(&self.bind, port), Ok)
+    }
+
+    /// Whether control shares the UDP port with the media.
+    pub const fn single_port(&self) -> bool {
+        self.port.is_some() || self.rendezvous.is_some() || self.relay.is_some()
     }
 
-    pub const fn local_udp_addr(&self) -> SocketAddr {
-        self.lu
+    /// Session ID to register as.
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    /// Rendezvous server and relay to register with.
+    pub fn registrars(&self) -> Result<Vec<Registrar>> {
+        Registrar::resolve_hosts(
+            self.rendezvous.as_deref(),
+            self.relay.as_deref().zip(self.relay_secret.as_deref()),
+        )
+    }
+
+    /// Whether to answer clients discovering servers on the LAN.
+    pub const fn announce(&self) -> bool {
+        !self.no_announce
+    }
+
+    pub const fn quic(&self) -> bool {
+        self.quic
     }
 
     pub const fn tps(&self) -> Duration {
         self.tps
     }
+
+    /// Maximum send rate in bits/sec.
+    pub fn max_bitrate(&self) -> Option<u64> {
+        self.max_bitrate.map(|mbits| mbits as u64 * 1_000_000)
+    }
+
+    pub const fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    /// Requested send buffer in bytes, if any.
+    pub const fn send_buffer(&self) -> Option<usize> {
+        match self.send_buffer {
+            0 => None,
+            kib => Some(kib * 1024),
+        }
+    }
+
+    /// Time between forced keyframes, if any.
+    pub const fn keyframe_interval(&self) -> Option<Duration> {
+        match self.keyframe_interval {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// Limits applied to every session.
+    pub fn send_options(&self) -> SendOptions {
+        SendOptions {
+            max_bitrate: self.max_bitrate(),
+            scale: self.scale(),
+            keyframe_interval: self.keyframe_interval(),
+            threads: self.threads,
+            gso: self.gso,
+        }
+    }
+
+    /// Capabilities to offer clients.
+    pub const fn hello(&self) -> Hello {
+        let mut hello = Hello::new();
+        if let Some(codec) = self.codec {
+            hello = hello.with_codecs(Codecs::of(&[codec]));
+        }
+        if let Some(format) = self.pixel_format {
+            hello = hello.with_pixel_formats(PixelFormats::of(&[format]));
+        }
+        hello
+    }
+
+    pub const fn heartbeat(&self) -> Duration {
+        self.heartbeat
+    }
+
+    pub const fn timeout(&self) -> Duration {
+        self.timeout
+    }
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self::parse()
+impl Config {
+    /// Settings from the command line, over those of the config file.
+    pub fn load() -> Result<Self> {
+        let cfg: Self = remdes::config::parse(CONFIG_FILE, false)?;
+        check_heartbeat((cfg.heartbeat, cfg.timeout))?;
+        Ok(cfg)
     }
 }
@@ -0,0 +1,76 @@
+use crate::*;
+
+/// Exchange [`Hello`]s with a newly connected client and settle the session.
+pub fn handshake(ctrl: &ControlChannel, local: Hello) -> Result<SessionParams> {
+    let Message::Hello(peer) = ctrl.recv()? else {
+        bail!("Expected hello");
+    };
+
+    ctrl.send(Message::Hello(local))?;
+
+    match local.negotiate(&peer) {
+        std::result::Result::Ok(params) => {
+            ctrl.send(Message::Session(params))?;
+            Ok(params)
+        }
+        Err(e) => {
+            ctrl.send(Message::Goodbye(GoodbyeReason::Incompatible))?;
+            bail!("Incompatible client: {e}")
+        }
+    }
+}
+
+/// Apply a single control message. Returns `false` once the session should end.
+pub fn handle_message(
+    msg: Message,
+    (heartbeat, session, rate): (&Heartbeat, &Session, &mut RateController),
+) -> bool {
+    match msg {
+        // liveness and rtt are tracked by the heartbeat itself
+        Message::Ping(_) | Message::Pong(_) => (),
+
+        // picked up by the capture and sender threads
+        Message::SetFps(fps) => {
+            println!("\tclient fps target: {fps}");
+            session.set_fps(fps);
+        }
+
+        Message::Scale(scale) => {
+            println!("\tclient scale: {scale}");
+            session.set_scale(scale);
+        }
+
+        // the client's decoder lost track of the stream
+        Message::RequestKeyframe => session.request_keyframe(),
+
+        // later frames are sent as deltas against it
+        Message::Ack(seq) => session.ack(seq),
+
+        Message::Resize { w, h } => println!("\tclient viewport: {w}x{h}"),
+
+        // adapt the encoding to the link
+        Message::Stats(stats) => {
+            println!(
+                "\tstats: {} fps, {}/s, loss {:.1}% ({} overflowed), jitter {:?}, rtt {:?}",
+                stats.frames,
+                remdes::util::bytes_to_mb_str(stats.bytes as usize),
+                stats.loss() * 100.0,
+                stats.overflow,
+                stats.jitter(),
+                heartbeat.rtt().unwrap_or_default(),
+            );
+            if let Some(quality) = rate.on_stats(&stats, heartbeat.rtt()) {
+                println!("\tquality: {quality:?}");
+                session.set_quality(quality);
+            }
+        }
+
+        Message::Goodbye(reason) => {
+            println!("\tclient left: {reason:?}");
+            return false;
+        }
+
+        Message::Hello(_) | Message::Session(_) => eprintln!("Unexpected {msg:?} mid-session"),
+    }
+    true
+}
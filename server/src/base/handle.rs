@@ -10,27 +10,23 @@ use windows_capture::{
     },
 };
 
+/// Poll interval for noticing that the capture ended on its own.
+const CAPTURE_POLL: Duration = Duration::from_millis(250);
+
 struct Streamer {
-    region: Arc<Mutex<Region>>,
-    region_id: Arc<AtomicU8>,
-    is_running: Arc<AtomicBool>,
-    tx_dist: Waker,
+    slot: Arc<FrameSlot>,
+    session: Arc<Session>,
 }
 
 impl GraphicsCaptureApiHandler for Streamer {
     type Error = Error;
-    type Flags = (Arc<Mutex<Region>>, Arc<AtomicU8>, Arc<AtomicBool>, Waker);
+    type Flags = (Arc<FrameSlot>, Arc<Session>);
 
     // Function that will be called to create a new instance. The flags can be
     // passed from settings.
     fn new(ctx: windows_capture::capture::Context<Self::Flags>) -> Result<Self, Self::Error> {
-        let (region, region_id, is_running, tx_dist) = ctx.flags;
-        Ok(Self {
-            region,
-            region_id,
-            is_running,
-            tx_dist,
-        })
+        let (slot, session) = ctx.flags;
+        Ok(Self { slot, session })
     }
 
     fn on_frame_arrived(
@@ -38,9 +34,8 @@ impl GraphicsCaptureApiHandler for Streamer {
         frame: &mut windows_capture::frame::Frame,
         capture_control: InternalCaptureControl,
     ) -> Result<(), Self::Error> {
-        if !self.is_running.load(Ordering::Relaxed) {
+        if !self.session.is_active() {
             capture_control.stop();
-            self.tx_dist.signal();
             return Ok(());
         }
 
@@ -51,9 +46,7 @@ impl GraphicsCaptureApiHandler for Streamer {
 
         // TODO - impl regional tiling
 
-        {
-            let mut region = self.region.lock();
-
+        self.slot.publish(|region| {
             // update region metadata and buffer
             region.set_x(0);
             region.set_y(0);
@@ -61,49 +54,50 @@ impl GraphicsCaptureApiHandler for Streamer {
             region.set_h(h);
             region.set_l(l);
 
+            // reuses the buffer's allocation once it has grown to fit
             let dst = region.data_mut();
+            dst.clear();
+            dst.extend_from_slice(src);
+        });
+        Ok(())
+    }
 
-            if dst.capacity() < l {
-                dst.reserve_exact(l - dst.capacity());
-            }
-            unsafe {
-                dst.set_len(l);
-                std::ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr(), l);
-            }
-        }
-
-        // SAFETY - fetch_update always returns Some(..)
-        unsafe {
-            self.region_id
-                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |id| {
-                    Some(id.wrapping_add(1))
-                })
-                .unwrap_unchecked();
-        }
-        self.tx_dist.wake();
+    // the target window was closed, which ends the session on purpose
+    fn on_closed(&mut self) -> Result<(), Self::Error> {
+        self.session.end();
         Ok(())
     }
 }
 
+/// Capture interval for the session: the client's frame interval, bounded by
+/// the server's own tick rate.
+fn capture_interval(cfg: &Config, session: &Session) -> Duration {
+    session
+        .frame_interval()
+        .map_or(cfg.tps(), |dur| dur.max(cfg.tps()))
+}
+
+/// Title of the window the `--window` substring selects, or the substring
+/// itself if no window matches (yet).
+pub fn window_title(substring: &str) -> String {
+    Window::from_contains_name(substring)
+        .and_then(|window| window.title())
+        .unwrap_or_else(|_| substring.to_owned())
+}
+
 pub fn start_capturing(
     cfg: Config,
-    region: Arc<Mutex<Region>>,
-    region_id: Arc<AtomicU8>,
-    is_running: Arc<AtomicBool>,
-    (tx_dist, rx_conn): (Waker, Waiter),
+    slot: Arc<FrameSlot>,
+    session: Arc<Session>,
 ) -> JoinHandle<Result<()>> {
     spawn(move || {
-        rx_conn.update_thread();
-
-        // Gets the primary monitor, refer to the docs for other capture items.
+        // the first window whose title contains `--window`
         let target = Window::from_contains_name(cfg.window())?;
 
         loop {
-            // wait for connection
-            rx_conn.wait();
-
-            // reset back to normal state (true)
-            is_running.store(true, Ordering::SeqCst);
+            // wait for a client session
+            session.wait_active();
+            let interval = capture_interval(&cfg, &session);
 
             // these settings not necessarily universally compatible
             // TODO - figure out minimum compatible defaults.
@@ -112,22 +106,36 @@ pub fn start_capturing(
                 CursorCaptureSettings::Default,
                 DrawBorderSettings::WithoutBorder,
                 SecondaryWindowSettings::Default,
-                MinimumUpdateIntervalSettings::Custom(cfg.tps()),
+                MinimumUpdateIntervalSettings::Custom(interval),
                 DirtyRegionSettings::ReportOnly,
                 ColorFormat::Bgra8,
-                (
-                    region.clone(),
-                    region_id.clone(),
-                    is_running.clone(),
-                    tx_dist.clone(),
-                ),
+                (slot.clone(), session.clone()),
             );
 
-            // begin screen capturing
-            if let Err(e) = Streamer::start(settings) {
-                eprintln!("run: {:?}", e);
-                // fallback reset
-                is_running.store(false, Ordering::SeqCst);
+            // begin screen capturing on a dedicated thread
+            let capture = match Streamer::start_free_threaded(settings) {
+                std::result::Result::Ok(capture) => capture,
+                Err(e) => {
+                    eprintln!("run: {:?}", e);
+                    session.end_with(GoodbyeReason::Error);
+                    continue;
+                }
+            };
+
+            // stop explicitly rather than waiting for a frame that a static
+            // window may never produce
+            while session.wait_ended(CAPTURE_POLL) {
+                if capture.is_finished() {
+                    session.end_with(GoodbyeReason::Error);
+                }
+
+                // restart with the client's new frame-rate target
+                if capture_interval(&cfg, &session) != interval {
+                    break;
+                }
+            }
+            if let Err(e) = capture.stop() {
+                eprintln!("stop: {:?}", e);
             }
         }
     })
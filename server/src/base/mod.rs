@@ -1,7 +1,9 @@
 mod cfg;
+mod ctrl;
 mod handle;
 mod util;
 
 pub use cfg::*;
+pub use ctrl::*;
 pub use handle::*;
 pub use util::*;
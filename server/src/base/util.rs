@@ -1,70 +1,32 @@
 use crate::*;
-use std::{io::Write, net::SocketAddr};
+use remdes::sock::DatagramSink;
+use std::io::Write;
 
 pub fn handle_client(
-    udp: &UdpSocket,
-    addr: SocketAddr,
-    region: &Arc<Mutex<Region>>,
-    is_running: &AtomicBool,
-    rx_dist: &Waiter,
+    sink: &mut dyn DatagramSink,
+    params: (SessionParams, SendOptions),
+    slot: &FrameSlot,
+    session: &Session,
 ) -> Result<()> {
     let mut out = std::io::stdout();
 
-    let mut buf = [0u8; 2 + UDP_CHUNK_SIZE];
-    let mut current_region = Region::default();
-
-    loop {
-        let t = std::time::Instant::now();
-
-        // wait for notification
-        rx_dist.wait();
-
-        // bring the current region up-to-date
-        {
-            let mut g = region.lock();
-            std::mem::swap(&mut current_region, &mut *g);
-        }
-        let header = current_region.header();
-        let data = current_region.data();
-
-        /////////////////////////////////////////////
-
-        // send the frame to the client
-        udp.send_to(bytemuck::bytes_of(&header), addr)?;
-
-        // distribute each chunk
-        for (i, chunk) in data.chunks(UDP_CHUNK_SIZE).enumerate() {
-            let chunk_compressed = lz4::block::compress(chunk, None, false).unwrap();
-            let chunk_len = chunk_compressed.len();
-
-            let idx_bytes = (i as u16).to_le_bytes();
-            buf[..2].copy_from_slice(&idx_bytes);
-
-            // Copy chunk into buffer
-            buf[2..2 + chunk_len].copy_from_slice(chunk_compressed.as_slice());
-
-            // Send header + chunk bytes
-            udp.send_to(&buf[..2 + chunk_len], addr)?;
-        }
-
+    send_frames(sink, params, (slot, session), |header, elapsed| {
         // Print timing info
-        out.write_all(
+        _ = out.write_all(
             format!(
-                "({}, {}, {}, {}) len={} [{}] -> {:?}\n",
+                "({}, {}, {}, {}) of {}x{} len={} [{}] -> {:?}\n",
                 header.x(),
                 header.y(),
                 header.w(),
                 header.h(),
+                header.src_w(),
+                header.src_h(),
                 header.l(),
                 remdes::util::bytes_to_mb_str(header.l()),
-                t.elapsed(),
+                elapsed,
             )
             .as_bytes(),
-        )?;
-        out.flush()?;
-
-        if !is_running.load(Ordering::Relaxed) {
-            break Ok(());
-        }
-    }
+        );
+        _ = out.flush();
+    })
 }
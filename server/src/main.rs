@@ -1,104 +1,267 @@
 mod base;
 use base::*;
 
-use parking_lot::Mutex;
-use remdes::*;
+use crossbeam_channel::{Sender, bounded};
+use remdes::{
+    arq::ArqListener,
+    caps::*,
+    discovery::{Announcement, Beacon, Ports},
+    proto::*,
+    quic::{QuicDatagrams, QuicListener},
+    rate::*,
+    rendezvous::advertise,
+    session::*,
+    sock::{BatchSender, DatagramSink, set_buffer_sizes},
+    util::hostname,
+    *,
+};
 use std::{
-    io::{Read, Write},
-    net::{TcpListener, TcpStream, UdpSocket},
-    sync::{
-        Arc,
-        atomic::{AtomicBool, AtomicU8, Ordering},
-    },
-    thread::{JoinHandle, sleep, spawn},
-    time::{Duration, Instant},
+    net::{SocketAddr, TcpListener, UdpSocket},
+    sync::Arc,
+    thread::{JoinHandle, spawn},
+    time::Duration,
 };
-use waitx::*;
 
-fn init_heartbeat(
-    tcp: TcpListener,
-    tx_tcp: Waker,
-    is_running: Arc<AtomicBool>,
-) -> JoinHandle<Result<()>> {
-    spawn(move || {
-        fn handle_stream(mut stream: &TcpStream) -> Result<()> {
-            stream.write_all(&[0])?;
-            stream.read_exact(&mut [0])?;
-            Ok(())
-        }
+/// How long a new client has to send its initial UDP datagram.
+const ACCEPT_TIMEOUT: Duration = SECOND;
 
-        for stream in tcp.incoming().filter_map(Result::ok) {
-            // initial heartbeat
-            if handle_stream(&stream).is_err() {
-                continue;
-            }
-            println!("\tTCP {:?}", stream.peer_addr()?);
+/// A client's control channel, along with the datagrams carrying its frames
+/// if it connected over QUIC.
+type Client = (ControlChannel, Option<QuicDatagrams>);
+
+/// Heartbeat with a settled client until either direction fails or the
+/// session ends.
+fn serve_control(
+    ctrl: &ControlChannel,
+    session: &Arc<Session>,
+    (heartbeat, timeout): (Duration, Duration),
+) -> Result<()> {
+    let heartbeat = Arc::new(Heartbeat::new(heartbeat, timeout));
+    let handler = {
+        let (heartbeat, session) = (heartbeat.clone(), session.clone());
+        let mut rate = RateController::default();
+        move |msg| handle_message(msg, (&heartbeat, &session, &mut rate))
+    };
+
+    let end = run_control(ctrl, &heartbeat, handler, |dur| {
+        let active = session.wait_ended(dur);
+        if !active {
+            let reason = session.end_reason().unwrap_or(GoodbyeReason::Quit);
+            _ = ctrl.send(Message::Goodbye(reason));
+        }
+        active
+    })?;
+    if end == ControlEnd::TimedOut {
+        println!("\tclient timed out after {timeout:?}");
+        session.end_with(GoodbyeReason::Timeout);
+    }
+    println!("\tlast rtt {:?}", heartbeat.rtt().unwrap_or_default());
+    Ok(())
+}
 
-            tx_tcp.signal(); // notify main thread of a new conn
+fn init_control(
+    incoming: impl Iterator<Item = Client> + Send + 'static,
+    tx_ctrl: Sender<(SessionParams, SocketAddr, Option<QuicDatagrams>)>,
+    session: Arc<Session>,
+    (hello, heartbeat, timeout): (Hello, Duration, Duration),
+) -> JoinHandle<Result<()>> {
+    spawn(move || {
+        for (ctrl, quic) in incoming {
+            // QUIC datagrams cannot be fragmented, so chunks must fit in one
+            let hello = match &quic {
+                Some(datagrams) => hello.with_max_datagram(datagrams.max_datagram()),
+                None => hello,
+            };
 
-            loop {
-                if handle_stream(&stream).is_err() {
-                    is_running.store(false, Ordering::SeqCst);
-                    break;
+            // initial hello exchange, which a silent peer must not stall
+            let settled = ctrl.peer_addr().and_then(|peer| {
+                ctrl.set_read_timeout(Some(timeout))?;
+                let params = handshake(&ctrl, hello)?;
+                ctrl.set_read_timeout(None)?;
+                Ok((peer, params))
+            });
+            let (peer, params) = match settled {
+                std::result::Result::Ok(settled) => settled,
+                Err(e) => {
+                    eprintln!("Handshake failed: {e}");
+                    continue;
                 }
-                sleep(Duration::from_millis(250));
+            };
+            println!("\tcontrol {peer:?} {params:?}");
+
+            // waits for the previous session to be released
+            session.begin();
+            let served = match tx_ctrl.send((params, peer, quic)) {
+                // the main thread streams while this one heartbeats
+                std::result::Result::Ok(()) => serve_control(&ctrl, &session, (heartbeat, timeout)),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = served {
+                eprintln!("Control failed: {e}");
+                session.end_with(GoodbyeReason::Error);
             }
+            session.end();
         }
         Ok(())
     })
 }
 
 fn main() -> anyhow::Result<()> {
-    let cfg = Config::default();
+    let cfg = Config::load()?;
 
     // bind sockets
-    let tcp = TcpListener::bind(cfg.local_tcp_addr())?;
-    let udp = UdpSocket::bind(cfg.local_udp_addr())?;
+    let udp = UdpSocket::bind(cfg.local_udp_addr()?)?;
+    let (send_buffer, _) = set_buffer_sizes(&udp, (cfg.send_buffer(), None))?;
 
-    println!(
-        "TCP listening @ {:?}\nUDP listening @ {:?}\n",
-        tcp.local_addr()?,
-        udp.local_addr()?
-    );
+    // control runs over TCP, or shares the UDP port in single-port and QUIC modes
+    let listener = match (cfg.quic(), cfg.single_port()) {
+        (false, true) => Some(Arc::new(ArqListener::new(&udp)?)),
+        _ => None,
+    };
 
-    let (region, region_id, is_running): (Arc<Mutex<Region>>, Arc<AtomicU8>, Arc<AtomicBool>) =
-        Default::default();
+    // keep the session registered, and punch back towards clients behind NAT
+    let registrars = cfg.registrars()?;
+    if let (Some(listener), Some(session)) = (&listener, cfg.session()) {
+        for registrar in &registrars {
+            println!("Registering as {session:?} with {registrar}");
+        }
+        let (listener, session) = (listener.clone(), session.to_owned());
+        spawn(move || {
+            if let Err(e) = advertise(&listener, (&registrars, &session)) {
+                eprintln!("Registration failed: {e}");
+            }
+        });
+    }
+
+    let udp_port = udp.local_addr()?.port();
+    let (incoming, ports): (Box<dyn Iterator<Item = Client> + Send>, _) = if cfg.quic() {
+        let quic = QuicListener::new(udp.try_clone()?)?;
+        println!(
+            "QUIC listening @ {:?}\nCertificate fingerprint {}",
+            quic.local_addr()?,
+            quic.fingerprint()
+        );
+        let ports = Ports::Quic {
+            port: udp_port,
+            fingerprint: quic.fingerprint().to_owned(),
+        };
+        let incoming = Box::new(std::iter::repeat_with(move || quic.accept()).filter_map(
+            |stream| {
+                let stream = stream
+                    .inspect_err(|e| eprintln!("QUIC connection failed: {e}"))
+                    .ok()?;
+                let datagrams = stream.datagrams();
+                Some((ControlChannel::new(stream), Some(datagrams)))
+            },
+        ));
+        (incoming, ports)
+    } else if let Some(listener) = &listener {
+        println!("Listening @ {:?} (control and media)", udp.local_addr()?);
+        let listener = listener.clone();
+        let incoming = Box::new(
+            std::iter::repeat_with(move || listener.accept())
+                .filter_map(|stream| Some((ControlChannel::new(stream.ok()?), None))),
+        );
+        (incoming, Ports::Single(udp_port))
+    } else {
+        let tcp = TcpListener::bind(cfg.local_tcp_addr()?)?;
+        println!(
+            "TCP listening @ {:?}\nUDP listening @ {:?}",
+            tcp.local_addr()?,
+            udp.local_addr()?
+        );
+        let ports = Ports::Split {
+            tcp: tcp.local_addr()?.port(),
+            udp: udp_port,
+        };
+        let incoming = Box::new(
+            std::iter::repeat_with(move || tcp.accept())
+                .filter_map(|conn| Some((ControlChannel::tcp(conn.ok()?.0).ok()?, None))),
+        );
+        (incoming, ports)
+    };
+
+    // answer LAN discovery, unless only reachable from this host
+    if cfg.announce() && !udp.local_addr()?.ip().is_loopback() {
+        match Beacon::new(DISCOVERY_PORT) {
+            std::result::Result::Ok(beacon) => {
+                println!("Answering LAN discovery on UDP {DISCOVERY_PORT}");
+                let (window, hostname) = (cfg.window().to_owned(), hostname());
+                spawn(move || {
+                    let announcement = || Announcement {
+                        hostname: hostname.clone(),
+                        window: window_title(&window),
+                        ports: ports.clone(),
+                    };
+                    if let Err(e) = beacon.run(announcement) {
+                        eprintln!("LAN discovery failed: {e}");
+                    }
+                });
+            }
+            Err(e) => eprintln!("LAN discovery unavailable: {e}"),
+        }
+    }
+    println!("UDP send buffer {} KiB\n", send_buffer / 1024);
+    if cfg.send_buffer().is_some_and(|size| send_buffer < size) {
+        eprintln!("UDP send buffer capped by the OS; bursts may be dropped");
+    }
 
-    let (tx_conn, rx_conn) = pair();
-    let (tx_tcp, rx_tcp) = pair();
-    let (tx_dist, rx_dist) = pair();
+    let (slot, session): (Arc<FrameSlot>, Arc<Session>) = Default::default();
+    let (tx_ctrl, rx_ctrl) = bounded(1);
 
-    let _handler = start_capturing(
-        cfg,
-        region.clone(),
-        region_id.clone(),
-        is_running.clone(),
-        (tx_dist, rx_conn),
+    // control thread
+    let _control = init_control(
+        incoming,
+        tx_ctrl,
+        session.clone(),
+        (cfg.hello(), cfg.heartbeat(), cfg.timeout()),
     );
 
-    // TCP-based heartbeat thread
-    let _heartbeat = init_heartbeat(tcp, tx_tcp, is_running.clone());
+    let opts = cfg.send_options();
+    let _handler = start_capturing(cfg, slot.clone(), session.clone());
 
     loop {
-        println!("Waiting for TCP connection...");
-        rx_tcp.wait();
-
-        // continuously attempt to obtain UDP message.
-        // timeout after 1 second.
-        let t = Instant::now();
-        while t.elapsed() < SECOND {
-            match udp.recv_from(&mut [0; 1]) {
-                std::result::Result::Ok((_, addr)) => {
+        println!("Waiting for a client...");
+        let (params, peer, quic) = rx_ctrl.recv()?;
+
+        let sink: Option<Box<dyn DatagramSink>> = match quic {
+            Some(datagrams) => {
+                println!("\tQUIC {peer:?}\n");
+                Some(Box::new(datagrams))
+            }
+            None => {
+                let media = match &listener {
+                    Some(listener) => listener.accept_media(peer.ip(), ACCEPT_TIMEOUT),
+                    None => accept_peer(&udp, ACCEPT_TIMEOUT)?,
+                };
+                media.map(|addr| {
                     println!("\tUDP {:?}\n", addr);
-                    tx_conn.signal();
-                    _ = handle_client(&udp, addr, &region, is_running.as_ref(), &rx_dist);
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionReset => {
-                    sleep(Duration::from_millis(1));
-                    continue;
+                    let batch = BatchSender::new(&udp, addr, params.max_datagram(), opts.gso);
+                    Box::new(batch) as Box<dyn DatagramSink>
+                })
+            }
+        };
+        match sink {
+            Some(mut sink) => {
+                let before = slot.stats();
+                if let Err(e) = handle_client(&mut *sink, (params, opts), &slot, &session) {
+                    eprintln!("Stream failed: {e}");
+                    session.end_with(GoodbyeReason::Error);
                 }
-                Err(e) => eprintln!("Unexpected: {e:?}"),
+                let stats = slot.stats().since(before);
+                println!(
+                    "\t{} of {} captured frames dropped",
+                    stats.dropped, stats.published
+                );
+            }
+            None => {
+                eprintln!("No UDP datagram within {ACCEPT_TIMEOUT:?}");
+                session.end_with(GoodbyeReason::Timeout);
             }
         }
+
+        // tear down and forget the peer before accepting another
+        session.end();
+        session.release();
     }
 }
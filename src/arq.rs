@@ -0,0 +1,450 @@
+use crate::{proto::*, *};
+use parking_lot::{Condvar, Mutex};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, SyncSender, sync_channel},
+    },
+    thread::spawn,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Kinds of control datagram; anything else arriving on the port is media,
+/// or belongs to another protocol sharing it (see [`ArqListener::recv_other`]).
+mod kind {
+    /// A message: `[kind][conn: u32 LE][seq: u32 LE][framed message]`.
+    pub const DATA: u8 = 1;
+    /// Cumulative acknowledgement: `[kind][conn: u32 LE][next seq: u32 LE]`.
+    pub const ACK: u8 = 2;
+}
+
+/// Size of the `[kind][conn][seq]` prefix of every control datagram.
+const PREFIX: usize = 9;
+
+/// Datagram a client sends from its media socket to be streamed to.
+pub const MEDIA_HELLO: [u8; 1] = [0];
+
+/// Time before an unacknowledged message is sent again.
+pub const RETRANSMIT: Duration = Duration::from_millis(200);
+
+/// Silence after which a connection is dropped. The heartbeat normally ends
+/// sessions well before this; it reaps peers that never finished a handshake.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Messages allowed in flight (or held out of order) per connection.
+pub const WINDOW: usize = 256;
+
+/// How often the reader wakes up to retransmit and reap connections.
+const TICK: Duration = Duration::from_millis(50);
+
+/// Connections and media peers waiting to be accepted.
+const BACKLOG: usize = 8;
+
+#[derive(Debug, Default)]
+struct Outbox {
+    next: u32,
+    unacked: VecDeque<(u32, Vec<u8>, Instant)>, // seq, datagram, last sent
+}
+
+#[derive(Debug, Default)]
+struct Inbox {
+    next: u32,
+    early: BTreeMap<u32, Message>, // arrived ahead of a gap
+    ready: VecDeque<Message>,
+    timeout: Option<Duration>,
+    closed: bool,
+}
+
+/// One peer's half of a reliable, ordered message stream.
+#[derive(Debug)]
+struct Conn {
+    addr: SocketAddr,
+    id: u32,
+    outbox: Mutex<Outbox>,
+    inbox: Mutex<Inbox>,
+    cv: Condvar,
+    last_heard: Mutex<Instant>,
+}
+
+impl Conn {
+    fn new(addr: SocketAddr, id: u32) -> Self {
+        Self {
+            addr,
+            id,
+            outbox: Default::default(),
+            inbox: Default::default(),
+            cv: Condvar::new(),
+            last_heard: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn datagram(&self, kind: u8, seq: u32, len: usize) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(PREFIX + len);
+        datagram.push(kind);
+        datagram.extend_from_slice(&self.id.to_le_bytes());
+        datagram.extend_from_slice(&seq.to_le_bytes());
+        datagram
+    }
+
+    fn send(&self, udp: &UdpSocket, msg: &Message) -> Result<()> {
+        if self.inbox.lock().closed {
+            bail!("Control channel closed");
+        }
+        let mut frame = Vec::with_capacity(16);
+        msg.encode(&mut frame);
+
+        let mut outbox = self.outbox.lock();
+        if outbox.unacked.len() >= WINDOW {
+            bail!("Control channel backed up ({WINDOW} messages unacknowledged)");
+        }
+        let seq = outbox.next;
+        let mut datagram = self.datagram(kind::DATA, seq, frame.len());
+        datagram.extend_from_slice(&frame);
+        udp.send_to(&datagram, self.addr)?;
+
+        outbox.next += 1;
+        outbox.unacked.push_back((seq, datagram, Instant::now()));
+        Ok(())
+    }
+
+    /// Queue an arriving message, returning the next sequence number owed.
+    fn deliver(&self, seq: u32, msg: Message) -> u32 {
+        let mut guard = self.inbox.lock();
+        let inbox = &mut *guard;
+        if seq == inbox.next {
+            inbox.ready.push_back(msg);
+            inbox.next += 1;
+            while let Some(msg) = inbox.early.remove(&inbox.next) {
+                inbox.ready.push_back(msg);
+                inbox.next += 1;
+            }
+            self.cv.notify_all();
+        } else if seq > inbox.next && inbox.early.len() < WINDOW {
+            inbox.early.insert(seq, msg);
+        }
+        inbox.next
+    }
+
+    fn recv(&self) -> Result<Message> {
+        let mut inbox = self.inbox.lock();
+        let deadline = inbox.timeout.map(|dur| Instant::now() + dur);
+        loop {
+            if let Some(msg) = inbox.ready.pop_front() {
+                return Ok(msg);
+            }
+            if inbox.closed {
+                bail!("Control channel closed");
+            }
+            match deadline {
+                Some(deadline) => {
+                    if self.cv.wait_until(&mut inbox, deadline).timed_out() {
+                        bail!("Timed out waiting for a control message");
+                    }
+                }
+                None => self.cv.wait(&mut inbox),
+            }
+        }
+    }
+
+    /// Forget every message the peer has seen, i.e. before `next`.
+    fn acked(&self, next: u32) {
+        self.outbox.lock().unacked.retain(|&(seq, ..)| seq >= next);
+    }
+
+    fn retransmit(&self, udp: &UdpSocket) {
+        let now = Instant::now();
+        for (_, datagram, sent) in &mut self.outbox.lock().unacked {
+            if now - *sent >= RETRANSMIT {
+                _ = udp.send_to(datagram, self.addr);
+                *sent = now;
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.inbox.lock().closed = true;
+        self.cv.notify_all();
+    }
+}
+
+/// A socket's control connections, fed by a reader thread.
+#[derive(Debug)]
+struct Shared {
+    udp: UdpSocket,
+    conns: Mutex<HashMap<(SocketAddr, u32), Arc<Conn>>>,
+    incoming: Option<SyncSender<ArqStream>>, // listeners only
+    media: Option<SyncSender<SocketAddr>>,   // listeners only
+    other: Option<SyncSender<(Vec<u8>, SocketAddr)>>, // listeners only
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn on_datagram(self: &Arc<Self>, datagram: &[u8], addr: SocketAddr) {
+        if datagram == MEDIA_HELLO {
+            if let Some(media) = &self.media {
+                _ = media.try_send(addr);
+            }
+            return;
+        }
+        if datagram.len() < PREFIX || !matches!(datagram[0], kind::DATA | kind::ACK) {
+            if let Some(other) = &self.other {
+                _ = other.try_send((datagram.to_vec(), addr));
+            }
+            return;
+        }
+        let kind = datagram[0];
+        let id = u32::from_le_bytes(datagram[1..5].try_into().unwrap());
+        let seq = u32::from_le_bytes(datagram[5..9].try_into().unwrap());
+
+        let known = self.conns.lock().get(&(addr, id)).cloned();
+        let conn = match known {
+            Some(conn) => conn,
+            // a new peer's first message
+            None if kind == kind::DATA && seq == 0 => match &self.incoming {
+                Some(incoming) => {
+                    let conn = Arc::new(Conn::new(addr, id));
+                    self.conns.lock().insert((addr, id), conn.clone());
+                    let stream = ArqStream {
+                        shared: self.clone(),
+                        conn: conn.clone(),
+                    };
+                    // dropping a stream that does not fit closes it again
+                    if incoming.try_send(stream).is_err() {
+                        return;
+                    }
+                    conn
+                }
+                None => return,
+            },
+            None => return,
+        };
+        *conn.last_heard.lock() = Instant::now();
+
+        match kind {
+            kind::DATA => {
+                let next = match Message::read_from(&mut &datagram[PREFIX..]) {
+                    std::result::Result::Ok(msg) => conn.deliver(seq, msg),
+                    Err(_) => return,
+                };
+                _ = self.udp.send_to(&conn.datagram(kind::ACK, next, 0), addr);
+            }
+            kind::ACK => conn.acked(seq),
+            _ => {}
+        }
+    }
+
+    /// Retransmit what is overdue and drop connections gone quiet.
+    fn sweep(&self) {
+        let conns: Vec<_> = self.conns.lock().values().cloned().collect();
+        for conn in conns {
+            if conn.last_heard.lock().elapsed() > IDLE_TIMEOUT {
+                conn.close();
+                self.conns.lock().remove(&(conn.addr, conn.id));
+            } else {
+                conn.retransmit(&self.udp);
+            }
+        }
+    }
+
+    fn start(self) -> Result<Arc<Self>> {
+        self.udp.set_read_timeout(Some(TICK))?;
+        let shared = Arc::new(self);
+        let reader = shared.clone();
+        spawn(move || reader.run());
+        Ok(shared)
+    }
+
+    fn run(self: Arc<Self>) {
+        let mut buf = vec![0; 1 << 16]; // stray datagrams must not truncate
+        let mut t_sweep = Instant::now();
+
+        while !self.closed.load(Ordering::Relaxed) {
+            match self.udp.recv_from(&mut buf) {
+                std::result::Result::Ok((n, addr)) => self.on_datagram(&buf[..n], addr),
+                // timeouts, and ICMP errors left by peers that are gone
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock
+                            | ErrorKind::TimedOut
+                            | ErrorKind::ConnectionReset
+                            | ErrorKind::ConnectionRefused
+                    ) => {}
+                Err(_) => break,
+            }
+            if t_sweep.elapsed() >= TICK {
+                self.sweep();
+                t_sweep = Instant::now();
+            }
+        }
+
+        for conn in self.conns.lock().drain().map(|(_, conn)| conn) {
+            conn.close();
+        }
+    }
+}
+
+/// Reliable, ordered control messages over UDP, so that a session needs a
+/// single port.
+///
+/// Each message travels in its own datagram under a per-connection sequence
+/// number; the receiver acknowledges the next number it expects, and the
+/// sender repeats whatever is still unacknowledged every [`RETRANSMIT`].
+#[derive(Debug)]
+pub struct ArqStream {
+    shared: Arc<Shared>,
+    conn: Arc<Conn>,
+}
+
+impl ArqStream {
+    /// Open a control connection to `server` from a fresh local socket.
+    pub fn connect(server: SocketAddr) -> Result<Self> {
+        let local = match server {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        Self::connect_from(UdpSocket::bind((local, 0))?, server)
+    }
+
+    /// Open a control connection to `server` from `udp`, e.g. a socket that
+    /// has punched a hole through NAT towards it.
+    pub fn connect_from(udp: UdpSocket, server: SocketAddr) -> Result<Self> {
+        let shared = Shared {
+            udp,
+            conns: Default::default(),
+            incoming: None,
+            media: None,
+            other: None,
+            closed: AtomicBool::new(false),
+        }
+        .start()?;
+
+        // tells this connection apart from earlier ones from the same port
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos();
+        let id = nanos ^ std::process::id().rotate_left(16);
+
+        let conn = Arc::new(Conn::new(server, id));
+        shared.conns.lock().insert((server, id), conn.clone());
+        Ok(Self { shared, conn })
+    }
+}
+
+impl Transport for ArqStream {
+    fn send(&self, msg: &Message) -> Result<()> {
+        self.conn.send(&self.shared.udp, msg)
+    }
+
+    fn recv(&self) -> Result<Message> {
+        self.conn.recv()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.conn.inbox.lock().timeout = timeout;
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.conn.addr)
+    }
+
+    fn shutdown(&self) {
+        self.conn.close();
+        let key = (self.conn.addr, self.conn.id);
+        self.shared.conns.lock().remove(&key);
+        // a client's socket serves this connection alone
+        if self.shared.incoming.is_none() {
+            self.shared.closed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for ArqStream {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Accepts [`ArqStream`]s on a socket that also carries the media.
+///
+/// A reader thread takes every datagram arriving on the socket: control
+/// datagrams go to their connection, while a [`MEDIA_HELLO`] announces the
+/// address a client wants its frames sent to. Anything else is kept for
+/// [`ArqListener::recv_other`].
+#[derive(Debug)]
+pub struct ArqListener {
+    shared: Arc<Shared>,
+    incoming: Mutex<Receiver<ArqStream>>,
+    media: Mutex<Receiver<SocketAddr>>,
+    other: Mutex<Receiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl ArqListener {
+    /// Start reading from (a handle to) `udp`, which stays usable for sending.
+    pub fn new(udp: &UdpSocket) -> Result<Self> {
+        let (tx_incoming, incoming) = sync_channel(BACKLOG);
+        let (tx_media, media) = sync_channel(BACKLOG);
+        let (tx_other, other) = sync_channel(BACKLOG);
+        let shared = Shared {
+            udp: udp.try_clone()?,
+            conns: Default::default(),
+            incoming: Some(tx_incoming),
+            media: Some(tx_media),
+            other: Some(tx_other),
+            closed: AtomicBool::new(false),
+        }
+        .start()?;
+
+        Ok(Self {
+            shared,
+            incoming: Mutex::new(incoming),
+            media: Mutex::new(media),
+            other: Mutex::new(other),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.shared.udp.local_addr()?)
+    }
+
+    /// Block until a new peer sends its first message.
+    pub fn accept(&self) -> Result<ArqStream> {
+        Ok(self.incoming.lock().recv()?)
+    }
+
+    /// Wait up to `timeout` for a [`MEDIA_HELLO`] from `ip`, skipping any
+    /// left over from other hosts.
+    pub fn accept_media(&self, ip: IpAddr, timeout: Duration) -> Option<SocketAddr> {
+        let media = self.media.lock();
+        let t = Instant::now();
+        while let Some(rem) = timeout.checked_sub(t.elapsed()) {
+            match media.recv_timeout(rem) {
+                std::result::Result::Ok(addr) if addr.ip() == ip => return Some(addr),
+                std::result::Result::Ok(_) => continue,
+                Err(_) => break,
+            }
+        }
+        None
+    }
+
+    /// Send a datagram of another protocol from the listening socket.
+    pub(crate) fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.shared.udp.send_to(datagram, addr)
+    }
+
+    /// Wait up to `timeout` for a datagram of neither the control nor the
+    /// media protocol, such as a rendezvous reply. Unclaimed ones are dropped
+    /// once a few of them are queued.
+    pub fn recv_other(&self, timeout: Duration) -> Option<(Vec<u8>, SocketAddr)> {
+        self.other.lock().recv_timeout(timeout).ok()
+    }
+}
+
+impl Drop for ArqListener {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,394 @@
+use crate::{sock::MAX_UDP_PAYLOAD, *};
+use std::{fmt, str::FromStr};
+
+/// Declares a wire enum along with a compact bitmask set of its variants.
+macro_rules! wire_enum {
+    ($(#[$meta:meta])* $name:ident, $set:ident { $($(#[$vmeta:meta])* $variant:ident = $val:literal),+ $(,)? }) => {
+        $(#[$meta])*
+        #[repr(u8)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($(#[$vmeta])* $variant = $val),+
+        }
+
+        impl $name {
+            /// Every variant, in order of preference (best first).
+            pub const ALL: &[Self] = &[$(Self::$variant),+];
+
+            pub const fn bit(self) -> u16 {
+                1 << self as u8
+            }
+        }
+
+        impl TryFrom<u8> for $name {
+            type Error = Error;
+
+            fn try_from(v: u8) -> Result<Self> {
+                match v {
+                    $($val => Ok(Self::$variant),)+
+                    _ => bail!(concat!("Unknown ", stringify!($name), ": {}"), v),
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Error;
+
+            /// Parse a variant by its (case-insensitive) name.
+            fn from_str(s: &str) -> Result<Self> {
+                $(if s.eq_ignore_ascii_case(stringify!($variant)) {
+                    return Ok(Self::$variant);
+                })+
+                bail!(
+                    concat!("Unknown ", stringify!($name), " {:?} (expected one of: {})"),
+                    s,
+                    [$(stringify!($variant)),+].join(", ").to_lowercase()
+                )
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let name = match self {
+                    $(Self::$variant => stringify!($variant)),+
+                };
+                f.write_str(&name.to_lowercase())
+            }
+        }
+
+        #[doc = concat!("Set of [`", stringify!($name), "`]s.")]
+        #[derive(Clone, Copy, Default, PartialEq, Eq)]
+        pub struct $set(u16);
+
+        impl $set {
+            pub const EMPTY: Self = Self(0);
+
+            /// The set as sent on the wire, one bit per member.
+            pub const fn bits(&self) -> u16 {
+                self.0
+            }
+
+            /// The set of a wire bitmask; bits of unknown members are kept
+            /// but never listed.
+            pub const fn from_bits(bits: u16) -> Self {
+                Self(bits)
+            }
+
+            pub const fn of(items: &[$name]) -> Self {
+                let mut bits = 0;
+                let mut i = 0;
+                while i < items.len() {
+                    bits |= items[i].bit();
+                    i += 1;
+                }
+                Self(bits)
+            }
+
+            pub const fn contains(&self, item: $name) -> bool {
+                self.0 & item.bit() != 0
+            }
+
+            pub const fn intersect(&self, rhs: Self) -> Self {
+                Self(self.0 & rhs.0)
+            }
+
+            pub const fn is_empty(&self) -> bool {
+                self.0 == 0
+            }
+
+            /// Members of the set, in order of preference.
+            pub fn iter(&self) -> impl Iterator<Item = $name> + '_ {
+                $name::ALL.iter().copied().filter(|v| self.contains(*v))
+            }
+        }
+
+        impl fmt::Debug for $set {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_set().entries(self.iter()).finish()
+            }
+        }
+    };
+}
+
+wire_enum! {
+    /// Compression applied to each frame.
+    Codec, Codecs {
+        /// LZ4 on each chunk of raw pixels; cheap, but only suited to a LAN.
+        Lz4 = 0,
+        /// H.264 video (OpenH264), for low-bandwidth links.
+        H264 = 1,
+    }
+}
+
+impl Codec {
+    /// Pixel formats this codec can carry.
+    pub const fn pixel_formats(self) -> PixelFormats {
+        match self {
+            Self::Lz4 => PixelFormats::of(PixelFormat::ALL),
+            Self::H264 => PixelFormats::of(&[PixelFormat::Yuv420]),
+        }
+    }
+
+    /// Nearest size at or below `w`x`h` that this codec can encode.
+    pub const fn frame_size(self, (w, h): (usize, usize)) -> (usize, usize) {
+        match self {
+            Self::Lz4 => (w, h),
+            // 4:2:0 macroblocks need even dimensions
+            Self::H264 => (
+                if w > 2 { w & !1 } else { 2 },
+                if h > 2 { h & !1 } else { 2 },
+            ),
+        }
+    }
+}
+
+wire_enum! {
+    /// Layout of the pixels within a frame.
+    PixelFormat, PixelFormats {
+        /// 4 bytes per pixel, as captured.
+        Bgra8 = 0,
+        /// 3 bytes per pixel, with the (always opaque) alpha dropped.
+        Bgrx8 = 1,
+        /// 2 bytes per pixel, 5-6-5 bits of red, green and blue.
+        Rgb565 = 2,
+        /// Planar BT.601 Y, U and V, with chroma subsampled 2x2.
+        Yuv420 = 3,
+        /// 1 byte of BT.601 luma per pixel.
+        Gray8 = 4,
+    }
+}
+
+wire_enum! {
+    /// Optional session features.
+    Feature, Features {
+        Audio = 0,
+        Input = 1,
+        Fec = 2,
+    }
+}
+
+/// Capabilities advertised by each peer at session start.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Hello {
+    version: u16,
+    codecs: Codecs,
+    pixel_formats: PixelFormats,
+    features: Features,
+    max_chunk: u32,
+}
+
+impl Hello {
+    /// Current revision of the protocol.
+    pub const VERSION: u16 = 9;
+
+    /// Size of an encoded hello of the current version.
+    pub const LEN: usize = 12;
+
+    /// Capabilities of this build.
+    pub const fn new() -> Self {
+        Self {
+            version: Self::VERSION,
+            codecs: Codecs::of(Codec::ALL),
+            pixel_formats: PixelFormats::of(PixelFormat::ALL),
+            features: Features::EMPTY,
+            max_chunk: UDP_CHUNK_SIZE as u32,
+        }
+    }
+
+    /// Restrict the codecs offered to `codecs`.
+    pub const fn with_codecs(mut self, codecs: Codecs) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    /// Restrict the pixel formats offered to `formats`.
+    pub const fn with_pixel_formats(mut self, formats: PixelFormats) -> Self {
+        self.pixel_formats = formats;
+        self
+    }
+
+    /// Shrink chunks so that each fits in a datagram of `len` bytes (see
+    /// [`SessionParams::max_datagram`]), for links that cannot fragment.
+    pub const fn with_max_datagram(mut self, len: usize) -> Self {
+        let chunk = len.saturating_sub(18) * 255 / 256;
+        if chunk < self.max_chunk as usize {
+            self.max_chunk = chunk as u32;
+        }
+        self
+    }
+
+    pub const fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub const fn codecs(&self) -> Codecs {
+        self.codecs
+    }
+
+    pub const fn pixel_formats(&self) -> PixelFormats {
+        self.pixel_formats
+    }
+
+    pub const fn features(&self) -> Features {
+        self.features
+    }
+
+    pub const fn max_chunk(&self) -> usize {
+        self.max_chunk as usize
+    }
+
+    /// Encode the hello as sent on the wire, little-endian.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0..2].copy_from_slice(&self.version.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.codecs.bits().to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.pixel_formats.bits().to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.features.bits().to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.max_chunk.to_le_bytes());
+        bytes
+    }
+
+    /// Decode a hello, tolerating layouts of other protocol versions so that
+    /// mismatches can be reported instead of failing to parse.
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        if let std::result::Result::Ok(bytes) = <&[u8; Self::LEN]>::try_from(payload) {
+            let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+            return Ok(Self {
+                version: u16_at(0),
+                codecs: Codecs::from_bits(u16_at(2)),
+                pixel_formats: PixelFormats::from_bits(u16_at(4)),
+                features: Features::from_bits(u16_at(6)),
+                max_chunk: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            });
+        }
+        let Some(version) = payload.first_chunk() else {
+            bail!("Malformed hello ({} bytes)", payload.len());
+        };
+        Ok(Self {
+            version: u16::from_le_bytes(*version),
+            ..Default::default()
+        })
+    }
+
+    /// Pick the session parameters shared by this (server) hello and `peer`'s.
+    pub fn negotiate(&self, peer: &Hello) -> Result<SessionParams> {
+        if self.version != peer.version {
+            bail!(
+                "Protocol version mismatch (local v{}, peer v{})",
+                self.version,
+                peer.version
+            );
+        }
+
+        let codecs = self.codecs.intersect(peer.codecs);
+        if codecs.is_empty() {
+            bail!(
+                "No common codec (local {:?}, peer {:?})",
+                self.codecs,
+                peer.codecs
+            );
+        }
+
+        // the first codec able to carry a common pixel format wins
+        let pixel_formats = self.pixel_formats.intersect(peer.pixel_formats);
+        let Some((codec, pixel_format)) = codecs.iter().find_map(|codec| {
+            let format = pixel_formats
+                .intersect(codec.pixel_formats())
+                .iter()
+                .next()?;
+            Some((codec, format))
+        }) else {
+            bail!(
+                "No common pixel format for {:?} (local {:?}, peer {:?})",
+                codecs,
+                self.pixel_formats,
+                peer.pixel_formats
+            );
+        };
+
+        let chunk_size = self.max_chunk.min(peer.max_chunk);
+        if chunk_size == 0 {
+            bail!("Peer advertised a zero chunk size");
+        }
+
+        let params = SessionParams {
+            codec: codec as u8,
+            pixel_format: pixel_format as u8,
+            features: self.features.intersect(peer.features),
+            chunk_size,
+        };
+        if params.max_datagram() > MAX_UDP_PAYLOAD {
+            bail!("Chunk size {chunk_size} does not fit in a datagram");
+        }
+        Ok(params)
+    }
+
+    /// Check that `params`, as chosen by the server, are ones this (client)
+    /// hello offered, rather than trusting the peer.
+    pub fn accepts(&self, params: &SessionParams) -> Result<()> {
+        let (codec, pixel_format) = (params.codec()?, params.pixel_format()?);
+        if !self.codecs.contains(codec) || !self.pixel_formats.contains(pixel_format) {
+            bail!("Server chose unsupported session parameters: {params:?}");
+        }
+        if params.chunk_size() == 0 || params.chunk_size() > self.max_chunk() {
+            bail!(
+                "Server chose a chunk size of {} (at most {} supported)",
+                params.chunk_size(),
+                self.max_chunk()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Parameters chosen by the server for a single session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SessionParams {
+    codec: u8,
+    pixel_format: u8,
+    features: Features,
+    chunk_size: u32,
+}
+
+impl SessionParams {
+    /// Encode the parameters as sent on the wire, little-endian.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let [f0, f1] = self.features.bits().to_le_bytes();
+        let [c0, c1, c2, c3] = self.chunk_size.to_le_bytes();
+        [self.codec, self.pixel_format, f0, f1, c0, c1, c2, c3]
+    }
+
+    pub fn from_bytes([codec, pixel_format, f0, f1, c0, c1, c2, c3]: [u8; 8]) -> Self {
+        Self {
+            codec,
+            pixel_format,
+            features: Features::from_bits(u16::from_le_bytes([f0, f1])),
+            chunk_size: u32::from_le_bytes([c0, c1, c2, c3]),
+        }
+    }
+
+    pub fn codec(&self) -> Result<Codec> {
+        Codec::try_from(self.codec)
+    }
+
+    pub fn pixel_format(&self) -> Result<PixelFormat> {
+        PixelFormat::try_from(self.pixel_format)
+    }
+
+    pub const fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Uncompressed size of each frame chunk.
+    pub const fn chunk_size(&self) -> usize {
+        self.chunk_size as usize
+    }
+
+    /// Largest datagram a single chunk can occupy: the chunk index followed
+    /// by its compressed payload, which may exceed `chunk_size` for
+    /// incompressible data.
+    pub const fn max_datagram(&self) -> usize {
+        let n = self.chunk_size();
+        2 + n + n / 255 + 16 // LZ4_COMPRESSBOUND
+    }
+}
@@ -0,0 +1,626 @@
+use crate::{caps::*, session::SendOptions, *};
+use lz4::block::CompressionMode;
+use openh264::{
+    OpenH264API,
+    decoder::Decoder as H264Decoder,
+    encoder::{BitRate, Encoder as H264Encoder, EncoderConfig, FrameType, UsageType},
+    formats::{YUVSlices, YUVSource},
+};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::{collections::VecDeque, sync::mpsc};
+
+/// Frames kept on either side as references for [`Codec::Lz4`] deltas.
+pub const HISTORY: usize = 8;
+
+/// Bitrate the H.264 encoder targets when the server sets no cap, in bits/sec.
+pub const H264_BITRATE: u64 = 8_000_000;
+
+/// Per-frame settings passed to an [`Encoder`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EncodeOptions {
+    /// LZ4 high-compression level (0 = fast mode).
+    pub level: i32,
+    /// Emit a frame that decodes on its own.
+    pub keyframe: bool,
+    /// Latest frame the receiver acknowledged (0 = none).
+    pub acked: u32,
+}
+
+/// Turns frames into datagrams: a [`RegionHeader`] announcing `l` bytes of
+/// payload, followed by the payload in chunks, each prefixed by its index.
+pub trait Encoder: Send {
+    /// Encode `frame`, handing each datagram to `send`. Returns the header
+    /// sent, or `None` if the encoder dropped the frame.
+    fn encode(
+        &mut self,
+        frame: &Region,
+        opts: EncodeOptions,
+        send: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<Option<RegionHeader>>;
+}
+
+/// Rebuilds frames from the datagrams of the matching [`Encoder`].
+pub trait Decoder: Send {
+    /// Start the frame announced by `header`.
+    fn begin(&mut self, header: RegionHeader);
+
+    /// Apply chunk `idx` of the current frame. Returns `false` if it was
+    /// rejected.
+    fn chunk(&mut self, idx: u16, payload: &[u8]) -> bool;
+
+    /// Complete the current frame into `frame` (whose buffer may be taken
+    /// for reuse), returning whether it arrived in full and should be
+    /// acknowledged. Fails if it cannot be shown until the server sends a
+    /// keyframe.
+    fn finish(&mut self, frame: &mut Region) -> Result<bool>;
+}
+
+/// Encoder for the session's codec, within the server's limits.
+pub fn encoder(params: SessionParams, opts: &SendOptions) -> Result<Box<dyn Encoder>> {
+    let (codec, format) = (params.codec()?, params.pixel_format()?);
+    if !codec.pixel_formats().contains(format) {
+        bail!("{codec} cannot carry {format} frames");
+    }
+    Ok(match codec {
+        Codec::Lz4 => Box::new(Lz4Encoder::new(params.chunk_size(), opts.threads)?),
+        Codec::H264 => Box::new(H264FrameEncoder::new(
+            params.chunk_size(),
+            opts.max_bitrate,
+        )?),
+    })
+}
+
+/// Decoder for the session's codec, decompressing on `threads` workers
+/// (0 = one per core) where the codec allows.
+pub fn decoder(params: SessionParams, threads: usize) -> Result<Box<dyn Decoder>> {
+    Ok(match params.codec()? {
+        Codec::Lz4 => Box::new(Lz4Decoder::new(params.chunk_size(), threads)?),
+        Codec::H264 => Box::new(H264FrameDecoder::new(params.chunk_size())?),
+    })
+}
+
+/// Flag set in the index of a chunk followed by a byte of padding.
+const PADDED: u16 = 0x8000;
+
+/// Most chunks a frame can be split into, leaving the top bit of each index
+/// for [`PADDED`].
+pub const MAX_CHUNKS: usize = PADDED as usize;
+
+/// Finish chunk `idx`, whose payload of `len` bytes was staged after a
+/// 2-byte gap at the start of `buf`, returning its datagram.
+fn seal(buf: &mut [u8], idx: usize, len: usize) -> &[u8] {
+    let mut idx = idx as u16;
+    let mut n = 2 + len;
+
+    // a datagram the size of a header would be mistaken for one, so it gets
+    // an extra byte, flagged for [`unseal`] to drop
+    if n == size_of::<RegionHeader>() {
+        idx |= PADDED;
+        buf[n] = 0;
+        n += 1;
+    }
+    buf[..2].copy_from_slice(&idx.to_le_bytes());
+    &buf[..n]
+}
+
+/// Split a chunk datagram into its index and payload, without any padding.
+/// Returns `None` for runts.
+pub fn unseal(datagram: &[u8]) -> Option<(u16, &[u8])> {
+    let (idx, payload) = datagram.split_first_chunk()?;
+    let idx = u16::from_le_bytes(*idx);
+    match idx & PADDED {
+        0 => Some((idx, payload)),
+        _ => Some((idx & !PADDED, payload.split_last()?.1)),
+    }
+}
+
+/// Fail if a frame of `len` bytes needs more than [`MAX_CHUNKS`] chunks.
+fn check_chunks(len: usize, chunk_size: usize) -> Result<usize> {
+    let n = len.div_ceil(chunk_size);
+    if n > MAX_CHUNKS {
+        bail!("Frame of {len} bytes needs {n} chunks (at most {MAX_CHUNKS})");
+    }
+    Ok(n)
+}
+
+/// Send `payload` as chunk `idx`, staged in `buf`.
+fn send_chunk(
+    buf: &mut Vec<u8>,
+    idx: usize,
+    payload: &[u8],
+    send: &mut dyn FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    buf.clear();
+    buf.resize(2, 0);
+    buf.extend_from_slice(payload);
+    buf.push(0); // room for padding
+    send(seal(buf, idx, payload.len()))
+}
+
+/// Oldest buffer of a full `history`, or a new one, to be refilled.
+fn recycle(history: &mut VecDeque<(u32, Vec<u8>)>) -> Vec<u8> {
+    match history.len() {
+        HISTORY.. => history
+            .pop_front()
+            .map(|(_, data)| data)
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Per-chunk buffers reused across frames.
+#[derive(Debug, Default)]
+struct ChunkSlot {
+    delta: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl ChunkSlot {
+    /// Compress `chunk` (XORed with `base`, if any) into a datagram.
+    fn compress(
+        &mut self,
+        idx: usize,
+        (chunk, base): (&[u8], Option<&[u8]>),
+        (mode, bound): (Option<CompressionMode>, usize),
+    ) -> Result<&[u8]> {
+        let src = match base {
+            Some(base) => {
+                self.delta.clear();
+                self.delta
+                    .extend(chunk.iter().zip(base).map(|(a, b)| a ^ b));
+                self.delta.as_slice()
+            }
+            None => chunk,
+        };
+        self.out.resize(bound, 0);
+        let len = lz4::block::compress_to_buffer(src, mode, false, &mut self.out[2..])?;
+        Ok(seal(&mut self.out, idx, len))
+    }
+}
+
+/// Compresses each chunk of raw pixels independently, so that every chunk
+/// received can be shown.
+///
+/// Frames are sent as an XOR delta against the latest frame the receiver
+/// acknowledged, which is mostly zeros and compresses far better. Without a
+/// usable acknowledgement, or when asked to, a full keyframe is sent instead.
+///
+/// Chunks are compressed on a worker pool into buffers kept between frames,
+/// and each is sent, in index order, as soon as it and its predecessors are
+/// done.
+#[derive(Debug)]
+pub struct Lz4Encoder {
+    chunk_size: usize,
+    bound: usize, // datagram size of an incompressible chunk, plus padding
+    seq: u32,
+    history: VecDeque<(u32, Vec<u8>)>, // recently sent frames, oldest first
+    pool: ThreadPool,
+    slots: Vec<ChunkSlot>,
+}
+
+impl Lz4Encoder {
+    /// An encoder compressing on `threads` workers (0 = one per core).
+    pub fn new(chunk_size: usize, threads: usize) -> Result<Self> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("lz4-{i}"))
+            .build()?;
+        Ok(Self {
+            chunk_size,
+            bound: 2 + lz4::block::compress_bound(chunk_size)? + 1,
+            seq: 0,
+            history: VecDeque::with_capacity(HISTORY),
+            pool,
+            slots: Vec::new(),
+        })
+    }
+}
+
+impl Encoder for Lz4Encoder {
+    fn encode(
+        &mut self,
+        frame: &Region,
+        opts: EncodeOptions,
+        send: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<Option<RegionHeader>> {
+        let mode = match opts.level {
+            0 => None,
+            level => Some(CompressionMode::HIGHCOMPRESSION(level)),
+        };
+        let (data, chunk_size) = (frame.data(), self.chunk_size);
+        let n = check_chunks(data.len(), chunk_size)?;
+
+        self.seq += 1;
+        let mut header = frame.header();
+        header.set_seq(self.seq);
+
+        // delta against the acknowledged frame while it is still held
+        let base = self
+            .history
+            .iter()
+            .filter(|_| !opts.keyframe)
+            .find(|(seq, data)| *seq == opts.acked && data.len() == frame.data().len());
+        if let Some((seq, _)) = base {
+            header.set_base(*seq);
+        }
+        send(bytemuck::bytes_of(&header))?;
+
+        if self.slots.len() < n {
+            self.slots.resize_with(n, ChunkSlot::default);
+        }
+
+        let bound = self.bound;
+        let slots = &mut self.slots;
+        let result = self.pool.in_place_scope(|scope| {
+            let (tx, rx) = mpsc::channel();
+            for (i, (chunk, slot)) in data.chunks(chunk_size).zip(slots).enumerate() {
+                let base = base.map(|(_, base)| &base[i * chunk_size..][..chunk.len()]);
+                let tx = tx.clone();
+                scope.spawn(move |_| {
+                    _ = tx.send((i, slot.compress(i, (chunk, base), (mode, bound))));
+                });
+            }
+            drop(tx);
+
+            // send in index order, holding back chunks that finish early
+            let mut done: Vec<_> = (0..n).map(|_| None).collect();
+            let mut next = 0;
+            for (i, datagram) in rx {
+                done[i] = Some(datagram);
+                while let Some(datagram) = done.get_mut(next).and_then(Option::take) {
+                    send(datagram?)?;
+                    next += 1;
+                }
+            }
+            Ok(())
+        });
+        result?;
+
+        let mut data = recycle(&mut self.history);
+        data.clear();
+        data.extend_from_slice(frame.data());
+        self.history.push_back((self.seq, data));
+        Ok(Some(header))
+    }
+}
+
+/// Raw view of the part of a buffer a decompression task works on.
+#[derive(Clone, Copy, Debug)]
+struct RawSlice(*mut u8, usize);
+
+// SAFETY: each task gets a disjoint range of the frame being decoded, and
+// the decoder waits for every task before touching its buffers otherwise.
+unsafe impl Send for RawSlice {}
+
+/// Decompress `src` into `dst`, XORing it with `base` if given.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes and `base` for reads, and neither may be
+/// accessed elsewhere (or `base` written) until this returns.
+unsafe fn decompress_into(src: &[u8], dst: RawSlice, base: Option<RawSlice>) -> bool {
+    let dst = unsafe { std::slice::from_raw_parts_mut(dst.0, dst.1) };
+    match lz4::block::decompress_to_buffer(src, Some(dst.len() as i32), dst) {
+        std::result::Result::Ok(n) if n == dst.len() => {
+            if let Some(base) = base {
+                let base = unsafe { std::slice::from_raw_parts(base.0, base.1) };
+                dst.iter_mut()
+                    .zip(base)
+                    .for_each(|(dst, base)| *dst ^= base);
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Decompresses chunks on a worker pool, straight into the frame being
+/// decoded, so that the receiving thread only copies each datagram.
+///
+/// The frame is owned by the decoder until [`Decoder::finish`] hands it
+/// over, and every task is waited for before then.
+#[derive(Debug)]
+pub struct Lz4Decoder {
+    chunk_size: usize,
+    current: Region,
+    refs: VecDeque<(u32, Vec<u8>)>, // frames received in full, oldest first
+    base: Option<usize>,            // index of the current frame's reference
+    missing: bool,                  // the current frame's reference is gone
+    seen: Vec<bool>,                // chunks of the current frame queued
+    received: usize,                // chunks of the current frame decoded
+    pool: ThreadPool,
+    pending: usize, // tasks yet to report back
+    tx: mpsc::Sender<(bool, Vec<u8>)>,
+    rx: mpsc::Receiver<(bool, Vec<u8>)>,
+    spare: Vec<Vec<u8>>, // payload buffers returned by finished tasks
+}
+
+impl Lz4Decoder {
+    /// A decoder decompressing on `threads` workers (0 = one per core).
+    pub fn new(chunk_size: usize, threads: usize) -> Result<Self> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("unlz4-{i}"))
+            .build()?;
+        let (tx, rx) = mpsc::channel();
+        Ok(Self {
+            chunk_size,
+            current: Region::default(),
+            refs: VecDeque::with_capacity(HISTORY),
+            base: None,
+            missing: false,
+            seen: Vec::new(),
+            received: 0,
+            pool,
+            pending: 0,
+            tx,
+            rx,
+            spare: Vec::new(),
+        })
+    }
+
+    /// Block until every queued chunk has been decompressed.
+    fn wait(&mut self) {
+        while self.pending > 0 {
+            // cannot disconnect, as `self` holds a sender
+            let std::result::Result::Ok((ok, buf)) = self.rx.recv() else {
+                break;
+            };
+            self.pending -= 1;
+            self.received += ok as usize;
+            self.spare.push(buf);
+        }
+    }
+}
+
+impl Drop for Lz4Decoder {
+    fn drop(&mut self) {
+        self.wait();
+    }
+}
+
+impl Decoder for Lz4Decoder {
+    fn begin(&mut self, header: RegionHeader) {
+        self.wait();
+        self.current.update(header);
+        self.seen.clear();
+        self.seen
+            .resize(header.l().div_ceil(self.chunk_size), false);
+        self.received = 0;
+        self.base = match header.base() {
+            0 => None,
+            base => self
+                .refs
+                .iter()
+                .position(|(seq, data)| *seq == base && data.len() == header.l()),
+        };
+        self.missing = header.base() != 0 && self.base.is_none();
+    }
+
+    fn chunk(&mut self, idx: u16, payload: &[u8]) -> bool {
+        // duplicates would race on the same range
+        let Some(seen) = self.seen.get_mut(idx as usize) else {
+            return false;
+        };
+        if std::mem::replace(seen, true) {
+            return false;
+        }
+        if self.missing {
+            return true; // undecodable either way
+        }
+
+        let start = self.chunk_size * idx as usize;
+        let len = self.chunk_size.min(self.current.l() - start);
+        let dst = RawSlice(self.current.data_mut()[start..].as_mut_ptr(), len);
+        let base = self
+            .base
+            .map(|i| RawSlice(self.refs[i].1[start..].as_mut_ptr(), len));
+
+        let mut buf = self.spare.pop().unwrap_or_default();
+        buf.clear();
+        buf.extend_from_slice(payload);
+
+        let tx = self.tx.clone();
+        self.pending += 1;
+        self.pool.spawn(move || {
+            // SAFETY: see `RawSlice`; `seen` keeps the ranges disjoint
+            let ok = unsafe { decompress_into(&buf, dst, base) };
+            _ = tx.send((ok, buf));
+        });
+        true
+    }
+
+    // chunks are independent, so even a partial frame is worth showing
+    fn finish(&mut self, frame: &mut Region) -> Result<bool> {
+        self.wait();
+        if self.missing {
+            bail!("Reference frame {} is gone", self.current.base());
+        }
+        let complete = self.received >= self.seen.len();
+        if complete {
+            // keep it as a reference for later deltas
+            let mut data = recycle(&mut self.refs);
+            data.clear();
+            data.extend_from_slice(self.current.data());
+            self.refs.push_back((self.current.seq(), data));
+        }
+        std::mem::swap(frame, &mut self.current);
+        Ok(complete)
+    }
+}
+
+/// Encodes [`PixelFormat::Yuv420`] frames as H.264, splitting the bitstream
+/// across chunks.
+pub struct H264FrameEncoder {
+    chunk_size: usize,
+    seq: u32,
+    inner: H264Encoder,
+    bitstream: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl H264FrameEncoder {
+    pub fn new(chunk_size: usize, max_bitrate: Option<u64>) -> Result<Self> {
+        let bitrate = max_bitrate.unwrap_or(H264_BITRATE).min(u32::MAX as u64) as u32;
+        let config = EncoderConfig::new()
+            .usage_type(UsageType::ScreenContentRealTime)
+            .bitrate(BitRate::from_bps(bitrate));
+        Ok(Self {
+            chunk_size,
+            seq: 0,
+            inner: H264Encoder::with_api_config(OpenH264API::from_source(), config)?,
+            bitstream: Vec::new(),
+            buf: Vec::new(),
+        })
+    }
+}
+
+impl Encoder for H264FrameEncoder {
+    fn encode(
+        &mut self,
+        frame: &Region,
+        opts: EncodeOptions,
+        send: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<Option<RegionHeader>> {
+        let (w, h) = (frame.w() as usize, frame.h() as usize);
+        if w % 2 != 0 || h % 2 != 0 {
+            bail!("H.264 frames must have even dimensions (got {w}x{h})");
+        }
+        let (cw, ch) = PixelFormat::chroma_size((w, h));
+        let (y, uv) = frame.data().split_at(w * h);
+        let (u, v) = uv.split_at(cw * ch);
+        let yuv = YUVSlices::new((y, u, v), (w, h), (w, cw, cw));
+
+        if opts.keyframe {
+            self.inner.force_intra_frame();
+        }
+        let stream = self.inner.encode(&yuv)?;
+        if stream.frame_type() == FrameType::Skip {
+            return Ok(None);
+        }
+        self.bitstream.clear();
+        stream.write_vec(&mut self.bitstream);
+        check_chunks(self.bitstream.len(), self.chunk_size)?;
+
+        self.seq += 1;
+        let mut header = frame.header();
+        header.set_seq(self.seq);
+        header.set_l(self.bitstream.len());
+        send(bytemuck::bytes_of(&header))?;
+
+        for (i, chunk) in self.bitstream.chunks(self.chunk_size).enumerate() {
+            send_chunk(&mut self.buf, i, chunk, send)?;
+        }
+        Ok(Some(header))
+    }
+}
+
+/// Decodes H.264 frames into [`PixelFormat::Yuv420`].
+///
+/// Each frame depends on the previous ones, so once a frame is lost nothing
+/// is shown until the next keyframe arrives.
+pub struct H264FrameDecoder {
+    chunk_size: usize,
+    inner: H264Decoder,
+    header: RegionHeader,
+    bitstream: Vec<u8>,
+    seen: Vec<bool>, // chunks of the current frame copied in
+    received: usize,
+    synced: bool,
+}
+
+impl H264FrameDecoder {
+    pub fn new(chunk_size: usize) -> Result<Self> {
+        Ok(Self {
+            chunk_size,
+            inner: H264Decoder::new()?,
+            header: RegionHeader::default(),
+            bitstream: Vec::new(),
+            seen: Vec::new(),
+            received: 0,
+            synced: false,
+        })
+    }
+}
+
+impl Decoder for H264FrameDecoder {
+    fn begin(&mut self, header: RegionHeader) {
+        self.header = header;
+        self.bitstream.clear();
+        self.bitstream.resize(header.l(), 0);
+        self.seen.clear();
+        self.seen
+            .resize(header.l().div_ceil(self.chunk_size), false);
+        self.received = 0;
+    }
+
+    fn chunk(&mut self, idx: u16, payload: &[u8]) -> bool {
+        // duplicates must not stand in for lost chunks
+        let Some(seen) = self.seen.get_mut(idx as usize) else {
+            return false;
+        };
+        if std::mem::replace(seen, true) {
+            return false;
+        }
+
+        let start = self.chunk_size * idx as usize;
+        let end = (start + payload.len()).min(self.bitstream.len());
+        if start >= end {
+            return false;
+        }
+        self.bitstream[start..end].copy_from_slice(&payload[..end - start]);
+        self.received += 1;
+        true
+    }
+
+    fn finish(&mut self, frame: &mut Region) -> Result<bool> {
+        let expected = self.seen.len();
+        if self.received < expected {
+            self.synced = false;
+            bail!("Frame incomplete ({}/{expected} chunks)", self.received);
+        }
+        if !self.synced && !is_keyframe(&self.bitstream) {
+            bail!("Waiting for a keyframe");
+        }
+
+        let yuv = match self.inner.decode(&self.bitstream) {
+            std::result::Result::Ok(Some(yuv)) => yuv,
+            std::result::Result::Ok(None) => bail!("No picture decoded"),
+            Err(e) => {
+                self.synced = false;
+                return Err(e.into());
+            }
+        };
+        self.synced = true;
+
+        let (w, h) = yuv.dimensions();
+        let (cw, ch) = PixelFormat::chroma_size((w, h));
+        let mut header = self.header;
+        header.set_w(w as i32);
+        header.set_h(h as i32);
+        header.set_l(PixelFormat::Yuv420.frame_len((w, h)));
+        frame.update(header);
+
+        // strip the decoder's row padding
+        let (y_stride, u_stride, v_stride) = yuv.strides();
+        let (dst_y, dst_uv) = frame.data_mut().split_at_mut(w * h);
+        let (dst_u, dst_v) = dst_uv.split_at_mut(cw * ch);
+        copy_plane(yuv.y(), y_stride, dst_y, w);
+        copy_plane(yuv.u(), u_stride, dst_u, cw);
+        copy_plane(yuv.v(), v_stride, dst_v, cw);
+        Ok(true)
+    }
+}
+
+fn copy_plane(src: &[u8], stride: usize, dst: &mut [u8], w: usize) {
+    for (src, dst) in src.chunks(stride).zip(dst.chunks_exact_mut(w)) {
+        dst.copy_from_slice(&src[..w]);
+    }
+}
+
+/// Whether an Annex B bitstream contains an IDR slice.
+pub fn is_keyframe(bitstream: &[u8]) -> bool {
+    bitstream
+        .windows(4)
+        .any(|w| w[..3] == [0, 0, 1] && w[3] & 0x1F == 5)
+}
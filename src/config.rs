@@ -0,0 +1,260 @@
+use crate::{util::config_dir, *};
+use clap::{
+    Arg, ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches,
+    error::{ContextKind, ContextValue},
+};
+use std::{
+    ffi::OsString,
+    fmt::Write,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+
+/// Table of per-server profiles, in files of binaries taking `--profile`.
+const PROFILES: &str = "profiles";
+
+/// Options that only make sense on the command line.
+const CLI_ONLY: &[&str] = &["config", "print_config", "profile", "help", "version"];
+
+/// Value standing for a flag's that was not given.
+const UNSET: &str = "false";
+
+/// `arg` taking `=true` or `=false` if it is a flag, so that the command line
+/// can clear one the config file sets.
+fn settable(arg: Arg) -> Arg {
+    match arg.get_action() {
+        ArgAction::SetTrue => arg
+            .action(ArgAction::Set)
+            .num_args(0..=1)
+            .require_equals(true)
+            .value_name("BOOL")
+            .hide_possible_values(true)
+            .default_value(UNSET)
+            .hide_default_value(true)
+            .default_missing_value("true"),
+        _ => arg,
+    }
+}
+
+/// Whether `arg` is a flag made [`settable`].
+fn is_flag(arg: &Arg) -> bool {
+    arg.is_require_equals_set() && arg.get_default_values() == [std::ffi::OsStr::new(UNSET)]
+}
+
+/// `T`'s command with the options every configurable binary takes, and
+/// `--profile` if `profiles` is set.
+fn command<T: CommandFactory>(profiles: bool) -> Command {
+    let cmd = T::command()
+        .mut_args(settable)
+        .args_override_self(true)
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Read settings from this TOML file instead of the default one"),
+        )
+        .arg(
+            Arg::new("print_config")
+                .long("print-config")
+                .action(ArgAction::SetTrue)
+                .help("Print the effective settings as TOML and exit"),
+        );
+    match profiles {
+        true => cmd.arg(
+            Arg::new("profile")
+                .long("profile")
+                .value_name("PROFILE")
+                .help("Apply this profile of the config file (by default, the one named after --host, if any)"),
+        ),
+        false => cmd,
+    }
+}
+
+/// Why `value` is not fit for `arg`, in a few words.
+fn reject(arg: &Arg, value: &str) -> Option<String> {
+    let check = Command::new("check").no_binary_name(true).arg(
+        Arg::new(arg.get_id().clone())
+            .value_parser(arg.get_value_parser().clone())
+            .allow_hyphen_values(true),
+    );
+    let e = check.try_get_matches_from([value]).err()?;
+    Some(
+        match (
+            std::error::Error::source(&e),
+            e.get(ContextKind::ValidValue),
+        ) {
+            (Some(cause), _) => cause.to_string(),
+            (None, Some(ContextValue::Strings(valid))) => {
+                format!("expected one of {}", valid.join(", "))
+            }
+            (None, _) => e.kind().to_string(),
+        },
+    )
+}
+
+/// The command line options `table` stands for, checked as the options would
+/// check them. Errors name `path` and the key, after `prefix`.
+fn to_args(cmd: &Command, table: &Table, (path, prefix): (&Path, &str)) -> Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let fail = |why: &str| anyhow!("{}: `{prefix}{key}`: {why}", path.display());
+        let long = key.replace('_', "-");
+        let arg = cmd
+            .get_arguments()
+            .filter(|arg| !CLI_ONLY.contains(&arg.get_id().as_str()))
+            .find(|arg| arg.get_long() == Some(&long))
+            .ok_or_else(|| fail("unknown setting"))?;
+
+        let value = match value {
+            _ if is_flag(arg) && !value.is_bool() => return Err(fail("expected true or false")),
+            Value::String(s) => s.clone(),
+            Value::Integer(n) => n.to_string(),
+            Value::Float(x) => x.to_string(),
+            Value::Boolean(b) => b.to_string(),
+            _ => return Err(fail("expected a single value")),
+        };
+        if let Some(why) = reject(arg, &value) {
+            return Err(fail(&format!("invalid value '{value}': {why}")));
+        }
+        args.push(format!("--{long}={value}").into());
+    }
+    Ok(args)
+}
+
+/// The settings `matches` ended up with, as a config file would hold them.
+fn render(cmd: &Command, matches: &ArgMatches, sources: &[String]) -> String {
+    let mut out = String::new();
+    for source in sources {
+        _ = writeln!(out, "# from {source}");
+    }
+    for arg in cmd.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Some(long) = arg.get_long().filter(|_| !CLI_ONLY.contains(&id)) else {
+            continue;
+        };
+        let value = if let Some(raw) = matches.get_raw(id).and_then(|mut raw| raw.next()) {
+            // bare where TOML reads it back the same, quoted otherwise
+            let raw = raw.to_string_lossy();
+            match format!("v = {raw}").parse::<Table>() {
+                std::result::Result::Ok(t) if !matches!(t["v"], Value::String(_)) => raw.into(),
+                _ => Value::String(raw.into_owned()).to_string(),
+            }
+        } else {
+            continue;
+        };
+        _ = writeln!(out, "{long} = {value}");
+    }
+    out
+}
+
+/// Parse the command line over the settings in `file` of the config directory
+/// (or the file `--config` names). Any top-level key of the file stands for
+/// the option of the same name; with `profiles`, a `[profiles.<name>]` table
+/// picked by `--profile` (or named after `--host`) goes on top of those.
+///
+/// Like [`clap::Parser::parse`], exits on `--help` and on errors in the
+/// command line, and on `--print-config` once the settings are printed.
+pub fn parse<T: CommandFactory + FromArgMatches>(file: &str, profiles: bool) -> Result<T> {
+    let default = config_dir().map(|dir| dir.join(file));
+    parse_from(std::env::args_os(), (default, profiles))
+}
+
+/// [`parse`] `args`, with the file at `default` unless `--config` names one.
+pub fn parse_from<T: CommandFactory + FromArgMatches>(
+    args: impl IntoIterator<Item = impl Into<OsString>>,
+    (default, profiles): (Option<PathBuf>, bool),
+) -> Result<T> {
+    let mut cmd = command::<T>(profiles);
+    let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+
+    // find out where settings come from before parsing for real
+    let early = cmd
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+        .ok();
+    let given = |id: &str| {
+        let matches = early.as_ref()?;
+        matches.try_get_one::<String>(id).ok().flatten().cloned()
+    };
+    let explicit = (early.as_ref()).and_then(|matches| {
+        matches
+            .try_get_one::<PathBuf>("config")
+            .ok()
+            .flatten()
+            .cloned()
+    });
+    let wanted = given("profile");
+
+    let mut settings = Vec::new();
+    let mut sources = Vec::new();
+    let path = explicit.clone().or(default);
+    let text = match &path {
+        Some(path) => match std::fs::read_to_string(path) {
+            std::result::Result::Ok(text) => Some(text),
+            Err(e) if e.kind() == ErrorKind::NotFound && explicit.is_none() => None,
+            Err(e) => bail!("Cannot read {}: {e}", path.display()),
+        },
+        None => None,
+    };
+
+    if let (Some(path), Some(text)) = (&path, text) {
+        let mut table: Table = text
+            .parse()
+            .map_err(|e| anyhow!("{}: {e}", path.display()))?;
+        let all = match table.remove(PROFILES) {
+            Some(Value::Table(all)) if profiles => all,
+            Some(_) if profiles => bail!("{}: `{PROFILES}` must be a table", path.display()),
+            Some(value) => {
+                table.insert(PROFILES.to_owned(), value); // reported as unknown
+                Table::new()
+            }
+            None => Table::new(),
+        };
+        settings.extend(to_args(&cmd, &table, (path, ""))?);
+        sources.push(path.display().to_string());
+
+        // check every profile, not only the one used
+        let name = wanted
+            .clone()
+            .or_else(|| given("host").filter(|h| all.contains_key(h)));
+        for (key, profile) in &all {
+            let prefix = format!("{PROFILES}.{key}.");
+            let Value::Table(profile) = profile else {
+                bail!("{}: `{PROFILES}.{key}` must be a table", path.display());
+            };
+            let profile = to_args(&cmd, profile, (path, &prefix))?;
+            if name.as_ref() == Some(key) {
+                settings.extend(profile);
+                sources.push(format!("profile {key}"));
+            }
+        }
+    }
+
+    if let Some(name) = &wanted
+        && !sources
+            .iter()
+            .any(|source| *source == format!("profile {name}"))
+    {
+        match &path {
+            Some(path) => bail!("No profile `{name}` in {}", path.display()),
+            None => bail!("No profile `{name}`: no config directory"),
+        }
+    }
+
+    // the command line has the last word
+    let tail = args.split_off(args.len().min(1));
+    args.extend(settings);
+    args.extend(tail);
+
+    let matches = cmd
+        .try_get_matches_from_mut(args)
+        .unwrap_or_else(|e| e.exit());
+    if matches.get_flag("print_config") {
+        print!("{}", render(&cmd, &matches, &sources));
+        std::process::exit(0);
+    }
+    Ok(T::from_arg_matches(&matches).unwrap_or_else(|e| e.format(&mut cmd).exit()))
+}
@@ -0,0 +1,252 @@
+use crate::*;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    time::Instant,
+};
+
+/// Prefix of every discovery datagram, setting them apart from whatever
+/// else is broadcast on the port.
+const MAGIC: &[u8] = b"remdes";
+
+/// Kinds of discovery datagram, following the [`MAGIC`].
+mod kind {
+    /// A client looking for servers: `[magic][kind]`.
+    pub const PROBE: u8 = b'?';
+    /// A server's reply: `[magic][kind][mode][port: u16 LE]*2` followed by
+    /// the hostname, window title and fingerprint, each `[len: u8][UTF-8]`.
+    pub const ANNOUNCE: u8 = b'!';
+}
+
+/// Time a client listens for servers by default.
+pub const DISCOVER_TIMEOUT: Duration = SECOND;
+
+/// Time between probes while listening, in case one is lost.
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Least time between two answers to the same host, so that a beacon
+/// cannot be used to flood anyone.
+pub const ANSWER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where probes go by default: every host on the local IPv4 network.
+pub const BROADCAST: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), DISCOVERY_PORT);
+
+/// How a server is reached, relative to the address it answered from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ports {
+    /// Control over TCP, media over UDP.
+    Split { tcp: u16, udp: u16 },
+    /// Control and media on one UDP port.
+    Single(u16),
+    /// QUIC on a UDP port, with the fingerprint of the server's certificate.
+    Quic { port: u16, fingerprint: String },
+}
+
+impl std::fmt::Display for Ports {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Split { tcp, udp } => write!(f, "TCP {tcp}, UDP {udp}"),
+            Self::Single(port) => write!(f, "UDP {port}"),
+            Self::Quic { port, .. } => write!(f, "QUIC {port}"),
+        }
+    }
+}
+
+/// A server's answer to a probe.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Announcement {
+    pub hostname: String,
+    /// Title of the window being captured.
+    pub window: String,
+    pub ports: Ports,
+}
+
+/// Append `s` with a length prefix, cut short (at a character) to fit one.
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    let mut len = s.len().min(u8::MAX as usize);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    buf.push(len as u8);
+    buf.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+/// Take a length-prefixed string off the front of `buf`.
+fn take_str(buf: &mut &[u8]) -> Result<String> {
+    let Some((&len, rest)) = buf.split_first() else {
+        bail!("Truncated announcement");
+    };
+    if rest.len() < len as usize {
+        bail!("Truncated announcement");
+    }
+    let (s, rest) = rest.split_at(len as usize);
+    *buf = rest;
+    Ok(std::str::from_utf8(s)?.to_owned())
+}
+
+impl Announcement {
+    pub fn encode(&self) -> Vec<u8> {
+        let (mode, ports, fingerprint) = match &self.ports {
+            Ports::Split { tcp, udp } => (0, [*tcp, *udp], ""),
+            Ports::Single(port) => (1, [0, *port], ""),
+            Ports::Quic { port, fingerprint } => (2, [0, *port], fingerprint.as_str()),
+        };
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&[kind::ANNOUNCE, mode]);
+        for port in ports {
+            buf.extend_from_slice(&port.to_le_bytes());
+        }
+        put_str(&mut buf, &self.hostname);
+        put_str(&mut buf, &self.window);
+        put_str(&mut buf, fingerprint);
+        buf
+    }
+
+    pub fn decode(datagram: &[u8]) -> Result<Self> {
+        let Some([kind::ANNOUNCE, mode, t0, t1, u0, u1, rest @ ..]) = datagram.strip_prefix(MAGIC)
+        else {
+            bail!("Not an announcement");
+        };
+        let (tcp, udp) = (
+            u16::from_le_bytes([*t0, *t1]),
+            u16::from_le_bytes([*u0, *u1]),
+        );
+
+        let mut rest = rest;
+        let hostname = take_str(&mut rest)?;
+        let window = take_str(&mut rest)?;
+        let fingerprint = take_str(&mut rest)?;
+        let ports = match mode {
+            0 => Ports::Split { tcp, udp },
+            1 => Ports::Single(udp),
+            2 => Ports::Quic {
+                port: udp,
+                fingerprint,
+            },
+            _ => bail!("Unknown mode {mode}"),
+        };
+        Ok(Self {
+            hostname,
+            window,
+            ports,
+        })
+    }
+}
+
+/// Whether a probe from `ip` may come from the local network: a private,
+/// link-local or loopback address. Announcements name the window on screen,
+/// so they are not for anyone else.
+pub const fn on_lan(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(_) => false, // probes are IPv4 broadcasts
+    }
+}
+
+/// Answers clients probing the local network for servers, at most once per
+/// [`ANSWER_INTERVAL`] for each host, and only those [`on_lan`].
+#[derive(Debug)]
+pub struct Beacon {
+    udp: UdpSocket,
+}
+
+impl Beacon {
+    /// Listen for probes on `port` of every IPv4 interface, alongside any
+    /// other server on this host.
+    pub fn new(port: u16) -> Result<Self> {
+        let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        sock.set_reuse_address(true)?;
+        sock.bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port).into())?;
+        Ok(Self { udp: sock.into() })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.udp.local_addr()?)
+    }
+
+    /// Answer probes with the server's current announcement. Returns only if
+    /// the socket fails.
+    pub fn run(&self, announcement: impl Fn() -> Announcement) -> Result<()> {
+        let mut buf = [0; 64];
+        let mut answered: HashMap<IpAddr, Instant> = HashMap::new();
+        loop {
+            let (n, addr) = match self.udp.recv_from(&mut buf) {
+                std::result::Result::Ok(received) => received,
+                // ICMP errors left by clients that are gone
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if buf[..n].strip_prefix(MAGIC) != Some(&[kind::PROBE]) || !on_lan(addr.ip()) {
+                continue;
+            }
+            answered.retain(|_, t| t.elapsed() < ANSWER_INTERVAL);
+            if answered.contains_key(&addr.ip()) {
+                continue;
+            }
+            answered.insert(addr.ip(), Instant::now());
+            _ = self.udp.send_to(&announcement().encode(), addr);
+        }
+    }
+}
+
+/// Probe `targets` (typically [`BROADCAST`]) for `timeout`, returning each
+/// server that answered with the address it answered from, in order.
+pub fn discover(targets: &[SocketAddr], timeout: Duration) -> Result<Vec<(IpAddr, Announcement)>> {
+    let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    udp.set_broadcast(true)?;
+    udp.set_read_timeout(Some(PROBE_INTERVAL))?;
+
+    let probe = [MAGIC, &[kind::PROBE]].concat();
+    let mut servers: Vec<(IpAddr, Announcement)> = Vec::new();
+    let mut buf = [0; 1024];
+    let t = Instant::now();
+    let mut t_probe: Option<Instant> = None;
+
+    while t.elapsed() < timeout {
+        if t_probe.is_none_or(|t| t.elapsed() >= PROBE_INTERVAL) {
+            for target in targets {
+                udp.send_to(&probe, target)?;
+            }
+            t_probe = Some(Instant::now());
+        }
+
+        let (n, addr) = match udp.recv_from(&mut buf) {
+            std::result::Result::Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock
+                        | ErrorKind::TimedOut
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionRefused
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // one entry per server, however many probes it answered; servers
+        // sharing a host share the beacon port, but not their own
+        if let std::result::Result::Ok(announcement) = Announcement::decode(&buf[..n]) {
+            let same = |(ip, known): &(IpAddr, Announcement)| {
+                *ip == addr.ip() && known.ports == announcement.ports
+            };
+            match servers.iter().position(same) {
+                Some(i) => servers[i].1 = announcement,
+                None => servers.push((addr.ip(), announcement)),
+            }
+        }
+    }
+    Ok(servers)
+}
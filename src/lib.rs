@@ -1,3 +1,18 @@
+pub mod arq;
+pub mod caps;
+pub mod codec;
+pub mod config;
+pub mod discovery;
+pub mod pace;
+pub mod pixel;
+pub mod proto;
+pub mod quic;
+pub mod rate;
+pub mod relay;
+pub mod rendezvous;
+pub mod scale;
+pub mod session;
+pub mod sock;
 pub mod util;
 
 pub use anyhow::*;
@@ -10,6 +25,9 @@ use std::{
 
 pub const TCP_PORT: u16 = 54277;
 pub const UDP_PORT: u16 = 54287;
+pub const RENDEZVOUS_PORT: u16 = 54297;
+pub const RELAY_PORT: u16 = 54307;
+pub const DISCOVERY_PORT: u16 = 54317;
 pub const UDP_CHUNK_SIZE: usize = 36_864;
 
 pub const SECOND: Duration = Duration::from_secs(1);
@@ -19,9 +37,13 @@ pub const SECOND: Duration = Duration::from_secs(1);
 pub struct RegionHeader {
     x: u16,
     y: u16,
-    w: u16,
-    h: u16,
+    w: u16, // encoded
+    h: u16, // encoded
     l: u32,
+    src_w: u16,
+    src_h: u16,
+    seq: u32,
+    base: u32,
 }
 
 impl RegionHeader {
@@ -45,6 +67,26 @@ impl RegionHeader {
         self.l as usize
     }
 
+    /// Width of the captured frame, before any downscaling.
+    pub const fn src_w(&self) -> i32 {
+        self.src_w as i32
+    }
+
+    /// Height of the captured frame, before any downscaling.
+    pub const fn src_h(&self) -> i32 {
+        self.src_h as i32
+    }
+
+    /// Position of the frame within the session, starting at 1.
+    pub const fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Frame whose pixels the payload is an XOR delta against (0 = none).
+    pub const fn base(&self) -> u32 {
+        self.base
+    }
+
     pub const fn set_x(&mut self, x: u16) {
         self.x = x
     }
@@ -64,6 +106,22 @@ impl RegionHeader {
     pub const fn set_l(&mut self, l: usize) {
         self.l = l as u32
     }
+
+    pub const fn set_src_w(&mut self, w: i32) {
+        self.src_w = w as u16
+    }
+
+    pub const fn set_src_h(&mut self, h: i32) {
+        self.src_h = h as u16
+    }
+
+    pub const fn set_seq(&mut self, seq: u32) {
+        self.seq = seq
+    }
+
+    pub const fn set_base(&mut self, base: u32) {
+        self.base = base
+    }
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
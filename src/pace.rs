@@ -0,0 +1,104 @@
+use crate::*;
+use std::{thread::sleep, time::Instant};
+
+/// Fraction of the frame interval over which a frame's chunks are spread,
+/// leaving headroom for encoding and jitter.
+pub const SPREAD: f64 = 0.8;
+
+/// Slowest rate a frame is paced at (bytes/sec), so that a small frame is
+/// never dribbled out over far longer than it needs.
+pub const MIN_RATE: f64 = 1_000_000.0;
+
+/// Token-bucket pacer limiting the rate at which datagrams are sent.
+#[derive(Debug)]
+pub struct Pacer {
+    rate: f64,   // bytes/sec (0 = unpaced)
+    burst: f64,  // bucket capacity in bytes
+    tokens: f64, // may go negative while paying off a datagram
+    last: Instant,
+}
+
+impl Pacer {
+    /// A pacer admitting bursts of up to `burst` bytes.
+    pub fn new(burst: usize) -> Self {
+        Self {
+            rate: 0.0,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Current rate in bytes/sec (0 = unpaced).
+    pub const fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.refill();
+        self.rate = rate;
+    }
+
+    /// Pick the rate for a frame: enough to send `frame_len` bytes within
+    /// [`SPREAD`] of `interval` but no slower than [`MIN_RATE`], capped at
+    /// `max_rate` (both in bytes/sec).
+    pub fn set_frame_rate(
+        &mut self,
+        frame_len: usize,
+        interval: Option<Duration>,
+        max_rate: Option<f64>,
+    ) {
+        let spread = interval
+            .filter(|_| frame_len != 0)
+            .map(|dur| (frame_len as f64 / (dur.as_secs_f64() * SPREAD)).max(MIN_RATE));
+        let rate = match (spread, max_rate) {
+            (Some(spread), Some(max)) => spread.min(max),
+            (rate, None) | (None, rate) => rate.unwrap_or_default(),
+        };
+        self.set_rate(rate);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// Take `len` bytes from the bucket, returning how long to hold off
+    /// before sending them.
+    pub fn delay(&mut self, len: usize) -> Duration {
+        if self.rate == 0.0 {
+            return Duration::ZERO;
+        }
+        self.refill();
+        self.tokens -= len as f64;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Take `len` bytes only if the bucket holds them, for policing a rate
+    /// rather than pacing to it.
+    pub fn admit(&mut self, len: usize) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+        self.refill();
+        let admitted = self.tokens >= len as f64;
+        if admitted {
+            self.tokens -= len as f64;
+        }
+        admitted
+    }
+
+    /// Block until `len` more bytes may be sent.
+    pub fn wait(&mut self, len: usize) {
+        let delay = self.delay(len);
+        if !delay.is_zero() {
+            sleep(delay);
+        }
+    }
+}
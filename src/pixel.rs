@@ -0,0 +1,79 @@
+use crate::caps::PixelFormat;
+
+impl PixelFormat {
+    /// Size of the chroma planes of a `w`x`h` [`PixelFormat::Yuv420`] frame.
+    pub const fn chroma_size((w, h): (usize, usize)) -> (usize, usize) {
+        (w.div_ceil(2), h.div_ceil(2))
+    }
+
+    /// Encoded size of a `w`x`h` frame in this format.
+    pub const fn frame_len(self, (w, h): (usize, usize)) -> usize {
+        match self {
+            Self::Bgra8 => w * h * 4,
+            Self::Bgrx8 => w * h * 3,
+            Self::Rgb565 => w * h * 2,
+            Self::Yuv420 => {
+                let (cw, ch) = Self::chroma_size((w, h));
+                w * h + 2 * cw * ch
+            }
+            Self::Gray8 => w * h,
+        }
+    }
+}
+
+/// BT.601 full-range luma.
+const fn luma([b, g, r]: [i32; 3]) -> u8 {
+    ((77 * r + 150 * g + 29 * b + 128) >> 8) as u8
+}
+
+/// BT.601 limited-range Y, U and V.
+const fn yuv([b, g, r]: [i32; 3]) -> [u8; 3] {
+    [
+        (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8,
+        (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8,
+        (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8,
+    ]
+}
+
+fn bgr(px: &[u8]) -> [i32; 3] {
+    [px[0] as i32, px[1] as i32, px[2] as i32]
+}
+
+/// Convert a `w`x`h` BGRA frame into `format`, replacing the contents of `dst`.
+pub fn convert(format: PixelFormat, src: &[u8], (w, h): (usize, usize), dst: &mut Vec<u8>) {
+    dst.clear();
+    dst.reserve(format.frame_len((w, h)));
+    let pixels = src[..w * h * 4].chunks_exact(4);
+
+    match format {
+        PixelFormat::Bgra8 => dst.extend_from_slice(&src[..w * h * 4]),
+        PixelFormat::Bgrx8 => pixels.for_each(|px| dst.extend_from_slice(&px[..3])),
+        PixelFormat::Rgb565 => pixels.for_each(|px| {
+            let [b, g, r] = [px[0] as u16, px[1] as u16, px[2] as u16];
+            let packed = (r >> 3) << 11 | (g >> 2) << 5 | b >> 3;
+            dst.extend_from_slice(&packed.to_le_bytes());
+        }),
+        PixelFormat::Gray8 => dst.extend(pixels.map(|px| luma(bgr(px)))),
+        PixelFormat::Yuv420 => {
+            dst.extend(pixels.map(|px| yuv(bgr(px))[0]));
+
+            // average each 2x2 block (clamped at odd edges) before converting
+            let (cw, ch) = PixelFormat::chroma_size((w, h));
+            let mut v_plane = Vec::with_capacity(cw * ch);
+            for cy in 0..ch {
+                for cx in 0..cw {
+                    let mut sum = [0; 3];
+                    for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let (x, y) = ((2 * cx + x).min(w - 1), (2 * cy + y).min(h - 1));
+                        let px = bgr(&src[(y * w + x) * 4..]);
+                        sum = [0, 1, 2].map(|c| sum[c] + px[c]);
+                    }
+                    let [_, u, v] = yuv(sum.map(|c| (c + 2) / 4));
+                    dst.push(u);
+                    v_plane.push(v);
+                }
+            }
+            dst.extend_from_slice(&v_plane);
+        }
+    }
+}
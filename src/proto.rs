@@ -0,0 +1,495 @@
+use crate::{caps::*, scale::Scale, *};
+use bytemuck::Pod;
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    io::{Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::spawn,
+    time::Instant,
+};
+
+/// Upper bound on the encoded size of a single control message.
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Wire identifiers of each [`Message`] kind.
+mod tag {
+    pub const HELLO: u8 = 0;
+    pub const PING: u8 = 1;
+    pub const SET_FPS: u8 = 2;
+    pub const REQUEST_KEYFRAME: u8 = 3;
+    pub const RESIZE: u8 = 4;
+    pub const STATS: u8 = 5;
+    pub const GOODBYE: u8 = 6;
+    pub const SESSION: u8 = 7;
+    pub const PONG: u8 = 8;
+    pub const SCALE: u8 = 9;
+    pub const ACK: u8 = 10;
+}
+
+/// Periodic receiver-side statistics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Frames received during the last report interval.
+    pub frames: u32,
+    /// Bytes received during the last report interval.
+    pub bytes: u32,
+    /// Chunks announced by the frame headers received.
+    pub chunks: u32,
+    /// Announced chunks that never arrived.
+    pub lost: u32,
+    /// Smoothed variation of frame inter-arrival times, in microseconds.
+    pub jitter: u32,
+    /// Datagrams the client's kernel dropped because its receive buffer was
+    /// full, as opposed to losses on the network.
+    pub overflow: u32,
+}
+
+impl Stats {
+    /// Encode the statistics as sent on the wire, each field little-endian.
+    pub fn to_bytes(&self) -> [u8; 24] {
+        let fields = [
+            self.frames,
+            self.bytes,
+            self.chunks,
+            self.lost,
+            self.jitter,
+            self.overflow,
+        ];
+        let mut bytes = [0; 24];
+        for (out, field) in bytes.chunks_exact_mut(4).zip(fields) {
+            out.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; 24]) -> Self {
+        let mut fields = bytes
+            .chunks_exact(4)
+            .map(|field| u32::from_le_bytes(field.try_into().unwrap()));
+        let mut next = || fields.next().unwrap();
+        Self {
+            frames: next(),
+            bytes: next(),
+            chunks: next(),
+            lost: next(),
+            jitter: next(),
+            overflow: next(),
+        }
+    }
+
+    /// Fraction of announced chunks that were lost.
+    pub fn loss(&self) -> f32 {
+        match self.chunks {
+            0 => 0.0,
+            chunks => self.lost.min(chunks) as f32 / chunks as f32,
+        }
+    }
+
+    pub const fn jitter(&self) -> Duration {
+        Duration::from_micros(self.jitter as u64)
+    }
+}
+
+/// Reason attached to a [`Message::Goodbye`].
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GoodbyeReason {
+    /// The user closed the session.
+    Quit = 0,
+    /// The peer stopped responding.
+    Timeout = 1,
+    /// The peers could not agree on a protocol.
+    Incompatible = 2,
+    /// An unrecoverable error occurred.
+    Error = 3,
+}
+
+impl TryFrom<u8> for GoodbyeReason {
+    type Error = Error;
+
+    fn try_from(v: u8) -> Result<Self> {
+        Ok(match v {
+            0 => Self::Quit,
+            1 => Self::Timeout,
+            2 => Self::Incompatible,
+            3 => Self::Error,
+            _ => bail!("Unknown goodbye reason: {v}"),
+        })
+    }
+}
+
+/// Typed control message exchanged over the control channel.
+///
+/// Each message is framed as `[len: u32 LE][tag: u8][payload]`, where `len`
+/// covers the tag and payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Message {
+    Hello(Hello),
+    Session(SessionParams),
+    /// Heartbeat carrying the sender's timestamp.
+    Ping(u64),
+    /// Reply to a [`Message::Ping`], echoing its timestamp.
+    Pong(u64),
+    SetFps(u8),
+    /// Downscaling requested by the client.
+    Scale(Scale),
+    RequestKeyframe,
+    /// Acknowledges a frame received in full, by its sequence number.
+    Ack(u32),
+    Resize {
+        w: u16,
+        h: u16,
+    },
+    Stats(Stats),
+    Goodbye(GoodbyeReason),
+}
+
+impl Message {
+    /// Serialize the message, including its length prefix, into `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.extend_from_slice(&[0; 4]); // length placeholder
+
+        match self {
+            Self::Hello(hello) => {
+                buf.push(tag::HELLO);
+                buf.extend_from_slice(&hello.to_bytes());
+            }
+            Self::Session(params) => {
+                buf.push(tag::SESSION);
+                buf.extend_from_slice(&params.to_bytes());
+            }
+            Self::Ping(t) => {
+                buf.push(tag::PING);
+                buf.extend_from_slice(&t.to_le_bytes());
+            }
+            Self::Pong(t) => {
+                buf.push(tag::PONG);
+                buf.extend_from_slice(&t.to_le_bytes());
+            }
+            Self::SetFps(fps) => buf.extend_from_slice(&[tag::SET_FPS, *fps]),
+            Self::Scale(scale) => {
+                buf.push(tag::SCALE);
+                buf.extend_from_slice(&scale.to_bytes());
+            }
+            Self::RequestKeyframe => buf.push(tag::REQUEST_KEYFRAME),
+            Self::Ack(seq) => {
+                buf.push(tag::ACK);
+                buf.extend_from_slice(&seq.to_le_bytes());
+            }
+            Self::Resize { w, h } => {
+                buf.push(tag::RESIZE);
+                buf.extend_from_slice(&w.to_le_bytes());
+                buf.extend_from_slice(&h.to_le_bytes());
+            }
+            Self::Stats(stats) => {
+                buf.push(tag::STATS);
+                buf.extend_from_slice(&stats.to_bytes());
+            }
+            Self::Goodbye(reason) => buf.extend_from_slice(&[tag::GOODBYE, *reason as u8]),
+        }
+
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// Deserialize a message from its tag and payload (without length prefix).
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let Some((&tag, payload)) = bytes.split_first() else {
+            bail!("Empty control message");
+        };
+
+        let msg = match tag {
+            tag::HELLO => Self::Hello(Hello::decode(payload)?),
+            tag::SESSION => Self::Session(SessionParams::from_bytes(read_pod(payload)?)),
+            tag::PING => Self::Ping(u64::from_le(read_pod(payload)?)),
+            tag::PONG => Self::Pong(u64::from_le(read_pod(payload)?)),
+            tag::SET_FPS => Self::SetFps(read_pod(payload)?),
+            tag::SCALE => Self::Scale(Scale::from_bytes(read_pod(payload)?)?),
+            tag::REQUEST_KEYFRAME => Self::RequestKeyframe,
+            tag::ACK => Self::Ack(u32::from_le(read_pod(payload)?)),
+            tag::RESIZE => {
+                let [w, h]: [u16; 2] = read_pod(payload)?;
+                Self::Resize {
+                    w: u16::from_le(w),
+                    h: u16::from_le(h),
+                }
+            }
+            tag::STATS => Self::Stats(Stats::from_bytes(read_pod(payload)?)),
+            tag::GOODBYE => Self::Goodbye(GoodbyeReason::try_from(read_pod::<u8>(payload)?)?),
+            _ => bail!("Unknown control message tag: {tag}"),
+        };
+        Ok(msg)
+    }
+
+    /// Write a single framed message to `w`.
+    pub fn write_to(&self, w: &mut impl Write) -> Result<()> {
+        let mut buf = Vec::with_capacity(16);
+        self.encode(&mut buf);
+        w.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Block until a single framed message is read from `r`.
+    pub fn read_from(r: &mut impl Read) -> Result<Self> {
+        let mut len = [0; 4];
+        r.read_exact(&mut len)?;
+
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_MESSAGE_LEN {
+            bail!("Control message too large ({len} bytes)");
+        }
+
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        r.read_exact(&mut buf[..len])?;
+        Self::decode(&buf[..len])
+    }
+}
+
+/// Read a plain-old-data payload whose size must match exactly.
+fn read_pod<T: Pod>(payload: &[u8]) -> Result<T> {
+    bytemuck::try_pod_read_unaligned(payload)
+        .map_err(|e| anyhow!("Malformed control payload: {e:?}"))
+}
+
+/// Carries [`Message`]s between the peers of a control channel.
+pub trait Transport: Send + Sync + fmt::Debug {
+    fn send(&self, msg: &Message) -> Result<()>;
+
+    /// Block until the next message arrives (or the read timeout passes).
+    fn recv(&self) -> Result<Message>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+
+    fn peer_addr(&self) -> Result<SocketAddr>;
+
+    /// Close both directions, unblocking any reader.
+    fn shutdown(&self);
+}
+
+/// Control messages framed over a TCP connection.
+#[derive(Debug)]
+pub struct TcpTransport {
+    writer: Mutex<TcpStream>,
+    reader: Mutex<TcpStream>,
+}
+
+impl TcpTransport {
+    pub fn new(stream: TcpStream) -> Result<Self> {
+        Ok(Self {
+            reader: Mutex::new(stream.try_clone()?),
+            writer: Mutex::new(stream),
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, msg: &Message) -> Result<()> {
+        msg.write_to(&mut *self.writer.lock())
+    }
+
+    fn recv(&self) -> Result<Message> {
+        Message::read_from(&mut *self.reader.lock())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.writer.lock().set_read_timeout(timeout)?)
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.writer.lock().peer_addr()?)
+    }
+
+    fn shutdown(&self) {
+        _ = self.writer.lock().shutdown(Shutdown::Both);
+    }
+}
+
+/// Shareable control channel over any [`Transport`].
+#[derive(Clone, Debug)]
+pub struct ControlChannel {
+    transport: Arc<dyn Transport>,
+}
+
+impl ControlChannel {
+    pub fn new(transport: impl Transport + 'static) -> Self {
+        Self {
+            transport: Arc::new(transport),
+        }
+    }
+
+    /// A channel over an established TCP connection.
+    pub fn tcp(stream: TcpStream) -> Result<Self> {
+        Ok(Self::new(TcpTransport::new(stream)?))
+    }
+
+    pub fn send(&self, msg: Message) -> Result<()> {
+        self.transport.send(&msg)
+    }
+
+    pub fn recv(&self) -> Result<Message> {
+        self.transport.recv()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.transport.set_read_timeout(timeout)
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.transport.peer_addr()
+    }
+
+    /// Close both directions, unblocking any reader of the same channel.
+    pub fn shutdown(&self) {
+        self.transport.shutdown();
+    }
+}
+
+/// Liveness and round-trip time of the control channel peer.
+#[derive(Debug)]
+pub struct Heartbeat {
+    epoch: Instant,
+    interval: Duration,
+    timeout: Duration,
+    last_seen: AtomicU64, // micros since epoch
+    srtt: AtomicU64,      // smoothed rtt in micros (0 = unmeasured)
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            epoch: Instant::now(),
+            interval,
+            timeout,
+            last_seen: AtomicU64::new(0),
+            srtt: AtomicU64::new(0),
+        }
+    }
+
+    pub const fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub const fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_micros() as u64
+    }
+
+    /// A ping stamped with the current time.
+    pub fn ping(&self) -> Message {
+        Message::Ping(self.now())
+    }
+
+    /// Record activity from the peer.
+    pub fn touch(&self) {
+        self.last_seen.store(self.now(), Ordering::Relaxed);
+    }
+
+    /// Record a message from the peer, returning the reply owed to it (if any).
+    pub fn on_message(&self, msg: &Message) -> Option<Message> {
+        self.touch();
+        let now = self.now();
+
+        match *msg {
+            Message::Ping(t) => Some(Message::Pong(t)),
+            Message::Pong(t) => {
+                // smooth as in RFC 6298: srtt = 7/8 srtt + 1/8 sample
+                let sample = now.saturating_sub(t).max(1);
+                let srtt = match self.srtt.load(Ordering::Relaxed) {
+                    0 => sample,
+                    srtt => (srtt * 7 + sample) / 8,
+                };
+                self.srtt.store(srtt, Ordering::Relaxed);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Smoothed round-trip time, once at least one pong has arrived.
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.srtt.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+
+    /// Whether the peer has been silent for longer than the timeout.
+    pub fn is_expired(&self) -> bool {
+        let silent = self
+            .now()
+            .saturating_sub(self.last_seen.load(Ordering::Relaxed));
+        Duration::from_micros(silent) > self.timeout
+    }
+}
+
+/// Why [`run_control`] returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlEnd {
+    /// The connection was closed or the handler asked to stop.
+    Closed,
+    /// The peer did not send anything within the heartbeat timeout.
+    TimedOut,
+    /// The local side stopped the session.
+    Stopped,
+}
+
+/// Drive a control connection until it ends.
+///
+/// Incoming messages are dispatched to `handle` on a separate thread (pings
+/// are answered automatically), while this thread pings the peer every
+/// heartbeat interval. `wait` sleeps for up to the given duration and
+/// returns `false` to stop the session locally.
+pub fn run_control(
+    ctrl: &ControlChannel,
+    heartbeat: &Arc<Heartbeat>,
+    mut handle: impl FnMut(Message) -> bool + Send + 'static,
+    mut wait: impl FnMut(Duration) -> bool,
+) -> Result<ControlEnd> {
+    heartbeat.touch(); // the connection itself counts as activity
+
+    let reader = {
+        let heartbeat = heartbeat.clone();
+        let ctrl = ctrl.clone();
+        spawn(move || {
+            while let std::result::Result::Ok(msg) = ctrl.recv() {
+                if let Some(reply) = heartbeat.on_message(&msg)
+                    && ctrl.send(reply).is_err()
+                {
+                    break;
+                }
+                if !handle(msg) {
+                    break;
+                }
+            }
+        })
+    };
+
+    let end = loop {
+        if reader.is_finished() {
+            break ControlEnd::Closed;
+        }
+        if heartbeat.is_expired() {
+            break ControlEnd::TimedOut;
+        }
+        if ctrl.send(heartbeat.ping()).is_err() {
+            break ControlEnd::Closed;
+        }
+        if !wait(heartbeat.interval()) {
+            break ControlEnd::Stopped;
+        }
+    };
+
+    // unblock the reader, which may be stuck on a half-open connection
+    ctrl.shutdown();
+    _ = reader.join();
+    Ok(end)
+}
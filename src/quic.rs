@@ -0,0 +1,365 @@
+use crate::{
+    proto::*,
+    sock::{BATCH, DatagramSink, DatagramSource},
+    *,
+};
+use bytes::Bytes;
+use parking_lot::Mutex;
+use quinn::{
+    Connection, ConnectionError, Endpoint, EndpointConfig, RecvStream, SendDatagramError,
+    SendStream, ServerConfig, TokioRuntime, TransportConfig,
+    crypto::rustls::QuicClientConfig,
+    rustls::{
+        self, DigitallySignedStruct, SignatureScheme,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature},
+        pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    },
+};
+use std::{
+    io::{self, ErrorKind, Read},
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, LazyLock},
+};
+use tokio::runtime::Runtime;
+
+/// Name the server's self-signed certificate is issued to.
+const SERVER_NAME: &str = "remdes";
+
+/// Time allowed for the QUIC handshake and the opening of the control stream.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Datagram bytes buffered in each direction; beyond this the oldest are
+/// dropped, which suits frames that are stale by then anyway.
+const DATAGRAM_BUFFER: usize = 8 << 20;
+
+/// Drives every endpoint in the process, while callers block on it from
+/// their own threads.
+static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("quic")
+        .enable_all()
+        .build()
+        .expect("failed to start the QUIC runtime")
+});
+
+/// Run `fut` to completion, giving up after `timeout` if one is set.
+fn block_on<T>(fut: impl Future<Output = T>, timeout: Option<Duration>) -> io::Result<T> {
+    RUNTIME.block_on(async {
+        match timeout {
+            Some(dur) => tokio::time::timeout(dur, fut)
+                .await
+                .map_err(|_| io::Error::from(ErrorKind::TimedOut)),
+            None => std::result::Result::Ok(fut.await),
+        }
+    })
+}
+
+fn endpoint(udp: UdpSocket, server: Option<ServerConfig>) -> Result<Endpoint> {
+    let _guard = RUNTIME.enter();
+    let runtime = Arc::new(TokioRuntime);
+    Ok(Endpoint::new(
+        EndpointConfig::default(),
+        server,
+        udp,
+        runtime,
+    )?)
+}
+
+fn transport() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport
+        .datagram_receive_buffer_size(Some(DATAGRAM_BUFFER))
+        .datagram_send_buffer_size(DATAGRAM_BUFFER);
+    Arc::new(transport)
+}
+
+/// SHA-256 of a certificate, in hex.
+pub fn fingerprint(cert: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert);
+    digest.as_ref().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Accepts the server's self-signed certificate, provided it matches the
+/// pinned fingerprint (any certificate if none is pinned).
+#[derive(Debug)]
+struct Pinned {
+    fingerprint: Option<String>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        cert: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        match &self.fingerprint {
+            Some(pinned) if !pinned.eq_ignore_ascii_case(&fingerprint(cert)) => Err(
+                rustls::Error::General("server certificate fingerprint mismatch".into()),
+            ),
+            _ => std::result::Result::Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Control messages on a QUIC connection's bidirectional stream, whose
+/// unreliable datagrams carry the frames (see [`QuicStream::datagrams`]).
+///
+/// The connection is encrypted and congestion controlled, and survives the
+/// client changing address. The server's certificate is self-signed, so it
+/// is only authenticated when the client pins its fingerprint.
+#[derive(Debug)]
+pub struct QuicStream {
+    conn: Connection,
+    send: Mutex<SendStream>,
+    recv: Mutex<RecvStream>,
+    timeout: Mutex<Option<Duration>>,
+    _endpoint: Endpoint, // keeps a client's socket open
+}
+
+impl QuicStream {
+    fn new(endpoint: Endpoint, conn: Connection, (send, recv): (SendStream, RecvStream)) -> Self {
+        Self {
+            conn,
+            send: Mutex::new(send),
+            recv: Mutex::new(recv),
+            timeout: Mutex::new(None),
+            _endpoint: endpoint,
+        }
+    }
+
+    /// Connect from `udp` to the server at `server`, checking its certificate
+    /// against `fingerprint` if given.
+    pub fn connect(udp: UdpSocket, server: SocketAddr, fingerprint: Option<&str>) -> Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = Pinned {
+            fingerprint: fingerprint.map(str::to_owned),
+            algorithms: provider.signature_verification_algorithms,
+        };
+        let crypto = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+        config.transport_config(transport());
+
+        let endpoint = endpoint(udp, None)?;
+        let (conn, streams) = block_on(
+            async {
+                let conn = endpoint.connect_with(config, server, SERVER_NAME)?.await?;
+                // the server only learns of the stream once something is sent on it
+                let streams = conn.open_bi().await?;
+                Ok((conn, streams))
+            },
+            Some(HANDSHAKE_TIMEOUT),
+        )??;
+        Ok(Self::new(endpoint, conn, streams))
+    }
+
+    /// The connection's datagrams, for the frames of the session.
+    pub fn datagrams(&self) -> QuicDatagrams {
+        QuicDatagrams {
+            conn: self.conn.clone(),
+            received: Vec::with_capacity(BATCH),
+            timeout: None,
+        }
+    }
+}
+
+impl Transport for QuicStream {
+    fn send(&self, msg: &Message) -> Result<()> {
+        let mut buf = Vec::with_capacity(16);
+        msg.encode(&mut buf);
+        let mut send = self.send.lock();
+        block_on(send.write_all(&buf), None)??;
+        Ok(())
+    }
+
+    fn recv(&self) -> Result<Message> {
+        let timeout = *self.timeout.lock();
+        let mut recv = self.recv.lock();
+        Message::read_from(&mut Reader {
+            recv: &mut recv,
+            timeout,
+        })
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        *self.timeout.lock() = timeout;
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.conn.remote_address())
+    }
+
+    fn shutdown(&self) {
+        self.conn.close(0u32.into(), b"");
+    }
+}
+
+/// Blocking reads from a receive stream.
+struct Reader<'a> {
+    recv: &'a mut RecvStream,
+    timeout: Option<Duration>,
+}
+
+impl Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = block_on(self.recv.read(buf), self.timeout)?.map_err(io::Error::from)?;
+        std::result::Result::Ok(n.unwrap_or(0)) // finished streams read as EOF
+    }
+}
+
+/// A QUIC connection's unreliable datagrams.
+///
+/// Unlike raw UDP these are encrypted and subject to congestion control, and
+/// cannot be fragmented, so chunks must fit [`QuicDatagrams::max_datagram`].
+#[derive(Clone, Debug)]
+pub struct QuicDatagrams {
+    conn: Connection,
+    received: Vec<Bytes>,
+    timeout: Option<Duration>,
+}
+
+impl QuicDatagrams {
+    /// Largest datagram the path currently allows.
+    pub fn max_datagram(&self) -> usize {
+        self.conn.max_datagram_size().unwrap_or_default()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.conn.remote_address()
+    }
+
+    pub const fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+}
+
+impl DatagramSink for QuicDatagrams {
+    /// Hand `datagram` to the connection, which sends it as soon as
+    /// congestion control allows.
+    fn push(&mut self, datagram: &[u8]) -> io::Result<()> {
+        self.conn
+            .send_datagram(Bytes::copy_from_slice(datagram))
+            .map_err(|e| match e {
+                SendDatagramError::ConnectionLost(e) => e.into(),
+                e => io::Error::other(e),
+            })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        std::result::Result::Ok(())
+    }
+}
+
+impl DatagramSource for QuicDatagrams {
+    fn recv(&mut self) -> io::Result<()> {
+        self.received.clear();
+        let (conn, received) = (&self.conn, &mut self.received);
+        let res = block_on(
+            async {
+                received.push(conn.read_datagram().await?);
+                // then whatever is already queued
+                while received.len() < BATCH {
+                    match tokio::time::timeout(Duration::ZERO, conn.read_datagram()).await {
+                        std::result::Result::Ok(std::result::Result::Ok(datagram)) => {
+                            received.push(datagram)
+                        }
+                        _ => break,
+                    }
+                }
+                std::result::Result::Ok::<_, ConnectionError>(())
+            },
+            self.timeout,
+        )?;
+        res.map_err(io::Error::from)
+    }
+
+    fn datagrams(&self) -> impl Iterator<Item = &[u8]> {
+        self.received.iter().map(|datagram| &datagram[..])
+    }
+
+    /// Always 0: quinn discards the oldest datagrams without counting them.
+    fn drops(&self) -> u32 {
+        0
+    }
+}
+
+/// Accepts [`QuicStream`]s on a UDP socket.
+#[derive(Debug)]
+pub struct QuicListener {
+    endpoint: Endpoint,
+    fingerprint: String,
+}
+
+impl QuicListener {
+    /// Serve QUIC on `udp` under a freshly generated self-signed certificate.
+    pub fn new(udp: UdpSocket) -> Result<Self> {
+        let key = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()])?;
+        let cert = key.cert.der().clone();
+        let fingerprint = fingerprint(&cert);
+        let key = PrivatePkcs8KeyDer::from(key.signing_key.serialize_der());
+
+        let mut config = ServerConfig::with_single_cert(vec![cert], key.into())?;
+        config.transport_config(transport());
+        Ok(Self {
+            endpoint: endpoint(udp, Some(config))?,
+            fingerprint,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// SHA-256 of the certificate, for clients to pin.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Block until the next client has connected and opened its control
+    /// stream.
+    pub fn accept(&self) -> Result<QuicStream> {
+        let incoming =
+            block_on(self.endpoint.accept(), None)?.ok_or_else(|| anyhow!("Endpoint closed"))?;
+        let (conn, streams) = block_on(
+            async {
+                let conn = incoming.await?;
+                let streams = conn.accept_bi().await?;
+                std::result::Result::Ok::<_, ConnectionError>((conn, streams))
+            },
+            Some(HANDSHAKE_TIMEOUT),
+        )??;
+        Ok(QuicStream::new(self.endpoint.clone(), conn, streams))
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{proto::Stats, *};
+
+/// Loss above which the link is considered congested (as in GCC).
+pub const LOSS_HIGH: f32 = 0.10;
+
+/// Loss below which the link is considered to have headroom (as in GCC).
+pub const LOSS_LOW: f32 = 0.02;
+
+/// Frame-arrival jitter above which the quality is held rather than raised.
+pub const JITTER_HIGH: Duration = Duration::from_millis(30);
+
+/// RTT growth over the path's minimum that is treated as queue build-up.
+pub const RTT_SLACK: Duration = Duration::from_millis(20);
+
+/// Consecutive clean reports required before probing the next quality up.
+pub const PROBE_REPORTS: u32 = 3;
+
+/// Encoding settings chosen for the link.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quality {
+    /// Frame-rate cap (0 = uncapped).
+    pub fps: u8,
+    /// LZ4 high-compression level (0 = fast mode).
+    pub level: i32,
+    /// Resolution divisor applied to each frame (1 = full size).
+    pub scale: u8,
+}
+
+impl Quality {
+    pub const fn new(fps: u8, level: i32, scale: u8) -> Self {
+        Self { fps, level, scale }
+    }
+}
+
+impl Default for Quality {
+    fn default() -> Self {
+        LADDER[0]
+    }
+}
+
+/// Qualities in order of preference, each cheaper on the link than the last.
+pub const LADDER: &[Quality] = &[
+    Quality::new(0, 0, 1),
+    Quality::new(60, 0, 1),
+    Quality::new(60, 3, 1),
+    Quality::new(30, 3, 1),
+    Quality::new(30, 3, 2),
+    Quality::new(20, 6, 2),
+    Quality::new(15, 9, 4),
+];
+
+/// Loss- and delay-based controller stepping through the [`LADDER`].
+///
+/// Like GCC, heavy loss or a growing RTT backs off quickly (two steps), while
+/// a run of clean reports probes one step up.
+#[derive(Debug, Default)]
+pub struct RateController {
+    step: usize,
+    clean: u32,
+    min_rtt: Option<Duration>,
+}
+
+impl RateController {
+    pub fn quality(&self) -> Quality {
+        LADDER[self.step]
+    }
+
+    /// Feed a receiver report, returning the new quality if it changed.
+    pub fn on_stats(&mut self, stats: &Stats, rtt: Option<Duration>) -> Option<Quality> {
+        // queueing shows up as rtt growth over the path's baseline
+        let bloated = rtt.is_some_and(|rtt| {
+            let min = *self.min_rtt.get_or_insert(rtt);
+            self.min_rtt = Some(min.min(rtt));
+            rtt.saturating_sub(min) > RTT_SLACK.max(min)
+        });
+
+        let prev = self.step;
+        let loss = stats.loss();
+
+        if loss > LOSS_HIGH || bloated {
+            self.step = (self.step + 2).min(LADDER.len() - 1);
+            self.clean = 0;
+        } else if loss > LOSS_LOW || stats.jitter() > JITTER_HIGH {
+            self.clean = 0;
+        } else {
+            self.clean += 1;
+            if self.clean >= PROBE_REPORTS {
+                self.step = self.step.saturating_sub(1);
+                self.clean = 0;
+            }
+        }
+
+        (self.step != prev).then(|| self.quality())
+    }
+}
@@ -0,0 +1,412 @@
+use crate::{arq::IDLE_TIMEOUT, pace::Pacer, rendezvous::*, *};
+use parking_lot::Mutex;
+use ring::hmac;
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+    thread::spawn,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Kind of the datagram asking a relay to forward a peer's datagrams:
+/// `[kind][role][timestamp: u64 LE][cookie][session ID][HMAC-SHA256 tag]`.
+const ALLOCATE: u8 = b'A';
+
+/// Kind of the relay's reply to an allocation without a valid cookie for
+/// the endpoint it came from: `[kind][cookie]`.
+const CHALLENGE: u8 = b'N';
+
+/// Size of the HMAC-SHA256 tag ending an allocation.
+const TAG_LEN: usize = 32;
+
+/// Size of the cookie binding an allocation to an endpoint.
+pub const COOKIE_LEN: usize = 16;
+
+/// Offset of the session ID within an allocation.
+const SESSION_AT: usize = 10 + COOKIE_LEN;
+
+/// How far an allocation's timestamp may be from the relay's clock, and how
+/// long a cookie is issued for (it is accepted for one more window).
+pub const ALLOCATION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Proof, issued by a relay, that a peer receives datagrams at the endpoint
+/// it sends from; all zeros before the relay issued one.
+pub type Cookie = [u8; COOKIE_LEN];
+
+/// Relayed ports per session; a client takes one per socket.
+const MAX_LANES: usize = 8;
+
+/// How often a lane wakes up to notice it has gone quiet.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Burst admitted beyond a session's cap, enough for a few large datagrams.
+const MIN_BURST: usize = 4 * UDP_CHUNK_SIZE;
+
+/// Shared secret of a relay and the peers allowed to use it.
+#[derive(Clone, Debug)]
+pub struct Secret(hmac::Key);
+
+impl Secret {
+    pub fn new(secret: &str) -> Self {
+        Self(hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()))
+    }
+
+    /// The cookie for `addr` in the `window`th [`ALLOCATION_WINDOW`], which
+    /// only the relay and peers knowing the secret can make.
+    fn cookie(&self, addr: SocketAddr, window: u64) -> Cookie {
+        let mut buf = vec![CHALLENGE];
+        buf.extend_from_slice(&window.to_le_bytes());
+        buf.extend_from_slice(addr.to_string().as_bytes());
+        let tag = hmac::sign(&self.0, &buf);
+        let mut cookie = Cookie::default();
+        cookie.copy_from_slice(&tag.as_ref()[..COOKIE_LEN]);
+        cookie
+    }
+
+    /// Whether `cookie` was issued to `addr` in the window `now` falls in,
+    /// or the one before.
+    fn admits(&self, cookie: &Cookie, addr: SocketAddr, now: SystemTime) -> bool {
+        let window = window(now);
+        [window, window.saturating_sub(1)]
+            .into_iter()
+            .any(|window| self.cookie(addr, window) == *cookie)
+    }
+}
+
+/// Index of the [`ALLOCATION_WINDOW`] `now` falls in.
+fn window(now: SystemTime) -> u64 {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    secs / ALLOCATION_WINDOW.as_secs()
+}
+
+/// A relay's answer to an allocation that lacks a valid cookie, carrying the
+/// cookie to sign into the next one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Challenge(pub Cookie);
+
+impl Challenge {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![CHALLENGE];
+        buf.extend_from_slice(&self.0);
+        buf
+    }
+
+    pub fn decode(datagram: &[u8]) -> Option<Self> {
+        match datagram {
+            [CHALLENGE, cookie @ ..] => Some(Self(cookie.try_into().ok()?)),
+            _ => None,
+        }
+    }
+}
+
+/// A peer's signed request for a relay to forward its datagrams.
+///
+/// The relay only acts on it with a [`Cookie`] it issued to the endpoint the
+/// allocation comes from, so that one captured on the way cannot be replayed
+/// from elsewhere.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    pub role: Role,
+    pub session: String,
+    pub cookie: Cookie,
+}
+
+impl Allocation {
+    pub fn new(role: Role, session: &str) -> Self {
+        Self {
+            role,
+            session: session.to_owned(),
+            cookie: Cookie::default(),
+        }
+    }
+
+    /// Answer the relay's [`Challenge`] with `cookie`.
+    pub const fn with_cookie(mut self, cookie: Cookie) -> Self {
+        self.cookie = cookie;
+        self
+    }
+
+    /// Sign with `secret`, as made at `now`.
+    pub fn encode(&self, secret: &Secret, now: SystemTime) -> Vec<u8> {
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut buf = vec![ALLOCATE, self.role.to_byte()];
+        buf.extend_from_slice(&timestamp.to_le_bytes());
+        buf.extend_from_slice(&self.cookie);
+        buf.extend_from_slice(self.session.as_bytes());
+        let tag = hmac::sign(&secret.0, &buf);
+        buf.extend_from_slice(tag.as_ref());
+        buf
+    }
+
+    /// Verify against `secret`, rejecting allocations made too far from `now`.
+    pub fn decode(datagram: &[u8], secret: &Secret, now: SystemTime) -> Result<Self> {
+        if datagram.len() < SESSION_AT + TAG_LEN || datagram[0] != ALLOCATE {
+            bail!("Not an allocation");
+        }
+        let (signed, tag) = datagram.split_at(datagram.len() - TAG_LEN);
+        hmac::verify(&secret.0, signed, tag).map_err(|_| anyhow!("Invalid signature"))?;
+
+        let timestamp = u64::from_le_bytes(signed[2..10].try_into()?);
+        let now = now.duration_since(UNIX_EPOCH)?.as_secs();
+        if now.abs_diff(timestamp) > ALLOCATION_WINDOW.as_secs() {
+            bail!("Allocation expired");
+        }
+
+        let role = Role::from_byte(signed[1]).ok_or_else(|| anyhow!("Invalid role"))?;
+        let session = &signed[SESSION_AT..];
+        if session.is_empty() || session.len() > MAX_SESSION_LEN {
+            bail!("Session ID must be 1 to {MAX_SESSION_LEN} bytes");
+        }
+        let cookie = signed[10..SESSION_AT].try_into()?;
+        Ok(Self::new(role, std::str::from_utf8(session)?).with_cookie(cookie))
+    }
+}
+
+/// The port relaying one client socket, and the server's replies to it.
+#[derive(Debug)]
+struct Lane {
+    client: SocketAddr,
+    session: String,
+    udp: UdpSocket,
+    last_active: Mutex<Instant>,
+}
+
+impl Lane {
+    fn touch(&self) {
+        *self.last_active.lock() = Instant::now();
+    }
+}
+
+#[derive(Debug)]
+struct RelaySession {
+    server: Option<(SocketAddr, Instant)>, // last registered
+    lanes: Vec<Arc<Lane>>,
+    pacer: Pacer, // both directions
+}
+
+impl RelaySession {
+    fn new(max_rate: Option<f64>) -> Self {
+        // a quarter second's worth of burst
+        let mut pacer =
+            Pacer::new(max_rate.map_or(MIN_BURST, |rate| MIN_BURST.max(rate as usize / 4)));
+        if let Some(rate) = max_rate {
+            pacer.set_rate(rate);
+        }
+        Self {
+            server: None,
+            lanes: Vec::new(),
+            pacer,
+        }
+    }
+
+    /// The server's endpoint, unless it stopped registering.
+    fn server(&self) -> Option<SocketAddr> {
+        self.server
+            .filter(|(_, t)| t.elapsed() <= REGISTRATION_TTL)
+            .map(|(server, _)| server)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    sessions: HashMap<String, RelaySession>,
+    lanes: HashMap<SocketAddr, Arc<Lane>>, // by client endpoint
+}
+
+/// Forwards datagrams between the server and clients of a session when they
+/// cannot punch through to each other, both having registered outwards with
+/// an [`Allocation`] signed by the shared [`Secret`].
+///
+/// Clients talk to the relay's own port and each of their sockets is given a
+/// port of its own (a lane), so the server sees them as distinct endpoints
+/// and answers each through its lane. Only the registered endpoints may use
+/// a session, whose traffic in both directions is held to `max_rate`.
+///
+/// Registrations are answered like a [`Rendezvous`]'s, so peers punch
+/// through to the relay just as they would to each other.
+#[derive(Debug)]
+pub struct Relay {
+    udp: UdpSocket,
+    secret: Secret,
+    max_rate: Option<f64>, // bytes/sec per session
+    state: Arc<Mutex<State>>,
+}
+
+impl Relay {
+    /// Relay on `udp`, capping each session at `max_bitrate` bits/sec.
+    pub fn new(udp: UdpSocket, secret: Secret, max_bitrate: Option<u64>) -> Self {
+        Self {
+            udp,
+            secret,
+            max_rate: max_bitrate.map(|bits| bits as f64 / 8.0),
+            state: Default::default(),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.udp.local_addr()?)
+    }
+
+    /// Relay forever, reporting each new endpoint.
+    pub fn run(&self, mut on_allocate: impl FnMut(Role, &str, SocketAddr)) -> Result<()> {
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            let (n, addr) = match self.udp.recv_from(&mut buf) {
+                std::result::Result::Ok(received) => received,
+                // ICMP errors left by peers that are gone
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let datagram = &buf[..n];
+
+            if datagram.first() == Some(&ALLOCATE) {
+                let now = SystemTime::now();
+                if let std::result::Result::Ok(allocation) =
+                    Allocation::decode(datagram, &self.secret, now)
+                {
+                    if self.secret.admits(&allocation.cookie, addr, now) {
+                        self.allocate(allocation, addr, &mut on_allocate)?;
+                    } else {
+                        // prove the peer is at `addr` before acting for it
+                        let challenge = Challenge(self.secret.cookie(addr, window(now)));
+                        _ = self.udp.send_to(&challenge.encode(), addr);
+                    }
+                }
+                continue;
+            }
+
+            // on from a client to the server, through the client's lane
+            let mut state = self.state.lock();
+            let State { sessions, lanes } = &mut *state;
+            let Some(lane) = lanes.get(&addr) else {
+                continue;
+            };
+            if let Some(session) = sessions.get_mut(&lane.session)
+                && let Some(server) = session.server()
+                && session.pacer.admit(n)
+            {
+                _ = lane.udp.send_to(datagram, server);
+                lane.touch();
+            }
+        }
+    }
+
+    fn allocate(
+        &self,
+        Allocation {
+            role, session: id, ..
+        }: Allocation,
+        addr: SocketAddr,
+        on_allocate: &mut impl FnMut(Role, &str, SocketAddr),
+    ) -> Result<()> {
+        let local = self.udp.local_addr()?;
+        let mut state = self.state.lock();
+
+        // forget sessions nobody uses
+        state
+            .sessions
+            .retain(|_, session| session.server().is_some() || !session.lanes.is_empty());
+
+        let State { sessions, lanes } = &mut *state;
+        let session = sessions
+            .entry(id.clone())
+            .or_insert_with(|| RelaySession::new(self.max_rate));
+        let lane_addr = |lane: &Lane| -> Result<SocketAddr> { Ok(lane.udp.local_addr()?) };
+
+        // failed sends are not retried; the peers register again shortly
+        match role {
+            Role::Server => {
+                if session.server.is_none_or(|(server, _)| server != addr) {
+                    on_allocate(role, &id, addr);
+                }
+                session.server = Some((addr, Instant::now()));
+                let clients = session.lanes.iter().map(|lane| lane_addr(lane));
+                let reply = Signal::Peers(clients.collect::<Result<_>>()?).encode();
+                _ = self.udp.send_to(&reply, addr);
+            }
+            Role::Client => {
+                let lane = match lanes.get(&addr) {
+                    Some(lane) if lane.session == id => lane.clone(),
+                    Some(_) => return Ok(()),
+                    None if session.lanes.len() < MAX_LANES => {
+                        let lane = Arc::new(Lane {
+                            client: addr,
+                            session: id.clone(),
+                            udp: UdpSocket::bind((local.ip(), 0))?,
+                            last_active: Mutex::new(Instant::now()),
+                        });
+                        session.lanes.push(lane.clone());
+                        lanes.insert(addr, lane.clone());
+                        on_allocate(role, &id, addr);
+
+                        let (udp, state) = (self.udp.try_clone()?, self.state.clone());
+                        {
+                            let lane = lane.clone();
+                            spawn(move || serve_lane(&lane, &udp, &state));
+                        }
+                        lane
+                    }
+                    None => return Ok(()),
+                };
+                lane.touch();
+
+                if let Some(server) = session.server() {
+                    _ = self.udp.send_to(&Signal::Peers(vec![local]).encode(), addr);
+                    let reply = Signal::Peers(vec![lane_addr(&lane)?]).encode();
+                    _ = self.udp.send_to(&reply, server);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Forward the server's datagrams on `lane` back to its client through the
+/// relay's socket, until the lane goes quiet.
+fn serve_lane(lane: &Arc<Lane>, udp: &UdpSocket, state: &Mutex<State>) {
+    let mut buf = vec![0; 1 << 16];
+    if lane.udp.set_read_timeout(Some(TICK)).is_ok() {
+        while lane.last_active.lock().elapsed() <= IDLE_TIMEOUT {
+            let (n, addr) = match lane.udp.recv_from(&mut buf) {
+                std::result::Result::Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock
+                            | ErrorKind::TimedOut
+                            | ErrorKind::ConnectionReset
+                            | ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    continue;
+                }
+                Err(_) => break,
+            };
+
+            // only the session's server may use its lanes
+            let mut state = state.lock();
+            if let Some(session) = state.sessions.get_mut(&lane.session)
+                && session.server() == Some(addr)
+                && session.pacer.admit(n)
+            {
+                _ = udp.send_to(&buf[..n], lane.client);
+                lane.touch();
+            }
+        }
+    }
+
+    let mut state = state.lock();
+    state.lanes.remove(&lane.client);
+    if let Some(session) = state.sessions.get_mut(&lane.session) {
+        session.lanes.retain(|other| !Arc::ptr_eq(other, lane));
+    }
+}
@@ -0,0 +1,446 @@
+use crate::{arq::ArqListener, relay::*, util::resolve_endpoint, *};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Instant, SystemTime},
+};
+
+/// Kinds of rendezvous datagram, chosen not to collide with the control
+/// and media protocols sharing a server's port.
+mod kind {
+    /// Announce a peer: `[kind][role][session ID]`.
+    pub const REGISTER: u8 = b'R';
+    /// Public endpoints of the other side: `[kind]([family][ip][port: u16 LE])*`.
+    pub const PEERS: u8 = b'P';
+}
+
+/// Datagram the peers send each other to open their NATs. A single byte, so
+/// that one arriving late is skipped like any other runt.
+pub const PUNCH: [u8; 1] = [b'H'];
+
+/// How often a peer registers again, which also keeps its NAT mapping alive.
+pub const REGISTER_INTERVAL: Duration = SECOND;
+
+/// Time a registration is kept without being renewed.
+pub const REGISTRATION_TTL: Duration = Duration::from_secs(5);
+
+/// Time a client has to punch through to the server.
+pub const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time between punches while waiting for one to come back.
+const PUNCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest session ID accepted.
+pub const MAX_SESSION_LEN: usize = 64;
+
+/// Client endpoints kept per session; a client registers one per socket.
+const MAX_CLIENTS: usize = 8;
+
+/// Side of a session a peer is on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+impl Role {
+    pub(crate) const fn to_byte(self) -> u8 {
+        match self {
+            Self::Server => b'S',
+            Self::Client => b'C',
+        }
+    }
+
+    pub(crate) const fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            b'S' => Some(Self::Server),
+            b'C' => Some(Self::Client),
+            _ => None,
+        }
+    }
+}
+
+/// A datagram exchanged with the rendezvous server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Signal {
+    /// A peer joining (or staying in) a session.
+    Register(Role, String),
+    /// Endpoints the rendezvous server observed for the other side. An
+    /// unspecified address stands for the sender's own, at the given port.
+    Peers(Vec<SocketAddr>),
+}
+
+impl Signal {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Register(role, session) => {
+                let mut buf = vec![kind::REGISTER, role.to_byte()];
+                buf.extend_from_slice(session.as_bytes());
+                buf
+            }
+            Self::Peers(addrs) => {
+                let mut buf = vec![kind::PEERS];
+                for addr in addrs {
+                    match addr.ip() {
+                        IpAddr::V4(ip) => {
+                            buf.push(4);
+                            buf.extend_from_slice(&ip.octets());
+                        }
+                        IpAddr::V6(ip) => {
+                            buf.push(6);
+                            buf.extend_from_slice(&ip.octets());
+                        }
+                    }
+                    buf.extend_from_slice(&addr.port().to_le_bytes());
+                }
+                buf
+            }
+        }
+    }
+
+    pub fn decode(datagram: &[u8]) -> Result<Self> {
+        match datagram {
+            [kind::REGISTER, role, session @ ..] => {
+                let role = Role::from_byte(*role).ok_or_else(|| anyhow!("Invalid role {role}"))?;
+                if session.is_empty() || session.len() > MAX_SESSION_LEN {
+                    bail!("Session ID must be 1 to {MAX_SESSION_LEN} bytes");
+                }
+                Ok(Self::Register(
+                    role,
+                    std::str::from_utf8(session)?.to_owned(),
+                ))
+            }
+            [kind::PEERS, rest @ ..] => {
+                let (mut rest, mut addrs) = (rest, Vec::new());
+                while let [family, tail @ ..] = rest {
+                    let (ip, tail) = match family {
+                        4 if tail.len() >= 6 => {
+                            let (ip, tail) = tail.split_at(4);
+                            let ip: [u8; 4] = ip.try_into()?;
+                            (IpAddr::V4(Ipv4Addr::from(ip)), tail)
+                        }
+                        6 if tail.len() >= 18 => {
+                            let (ip, tail) = tail.split_at(16);
+                            let ip: [u8; 16] = ip.try_into()?;
+                            (IpAddr::V6(Ipv6Addr::from(ip)), tail)
+                        }
+                        _ => bail!("Truncated peer address"),
+                    };
+                    let port = u16::from_le_bytes([tail[0], tail[1]]);
+                    addrs.push(SocketAddr::new(ip, port));
+                    rest = &tail[2..];
+                }
+                Ok(Self::Peers(addrs))
+            }
+            _ => bail!("Not a rendezvous datagram"),
+        }
+    }
+}
+
+/// Endpoints registered under one session ID, with when each was last renewed.
+#[derive(Debug, Default)]
+struct Registrations {
+    server: Option<(SocketAddr, Instant)>,
+    clients: Vec<(SocketAddr, Instant)>,
+}
+
+impl Registrations {
+    fn expire(&mut self) {
+        if self
+            .server
+            .is_some_and(|(_, t)| t.elapsed() > REGISTRATION_TTL)
+        {
+            self.server = None;
+        }
+        self.clients
+            .retain(|(_, t)| t.elapsed() <= REGISTRATION_TTL);
+    }
+
+    fn clients(&self) -> Vec<SocketAddr> {
+        self.clients.iter().map(|&(addr, _)| addr).collect()
+    }
+}
+
+/// Introduces the server and clients of a session to each other by the
+/// public endpoints their registrations arrive from.
+///
+/// A server registers periodically and is answered with the clients waiting
+/// for it. A client's registration is answered with the server, which is
+/// told about the client at the same time, so that both start punching
+/// together.
+#[derive(Debug)]
+pub struct Rendezvous {
+    udp: UdpSocket,
+    sessions: HashMap<String, Registrations>,
+}
+
+impl Rendezvous {
+    pub fn new(udp: UdpSocket) -> Self {
+        Self {
+            udp,
+            sessions: HashMap::new(),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.udp.local_addr()?)
+    }
+
+    /// Serve registrations forever, reporting each new endpoint.
+    pub fn run(&mut self, mut on_register: impl FnMut(Role, &str, SocketAddr)) -> Result<()> {
+        let mut buf = [0; 256];
+        loop {
+            let (n, addr) = match self.udp.recv_from(&mut buf) {
+                std::result::Result::Ok(received) => received,
+                // ICMP errors left by peers that are gone
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let std::result::Result::Ok(Signal::Register(role, session)) =
+                Signal::decode(&buf[..n])
+            else {
+                continue;
+            };
+
+            let regs = self.sessions.entry(session.clone()).or_default();
+            regs.expire();
+            let now = Instant::now();
+            // failed sends are not retried; the peers register again shortly
+            match role {
+                Role::Server => {
+                    if regs.server.is_none_or(|(server, _)| server != addr) {
+                        on_register(role, &session, addr);
+                    }
+                    regs.server = Some((addr, now));
+                    let reply = Signal::Peers(regs.clients()).encode();
+                    _ = self.udp.send_to(&reply, addr);
+                }
+                Role::Client => {
+                    let full = regs.clients.len() >= MAX_CLIENTS;
+                    match regs.clients.iter_mut().find(|(client, _)| *client == addr) {
+                        Some((_, t)) => *t = now,
+                        None if !full => {
+                            on_register(role, &session, addr);
+                            regs.clients.push((addr, now));
+                        }
+                        None => continue,
+                    }
+                    if let Some((server, _)) = regs.server {
+                        _ = self
+                            .udp
+                            .send_to(&Signal::Peers(vec![server]).encode(), addr);
+                        _ = self
+                            .udp
+                            .send_to(&Signal::Peers(vec![addr]).encode(), server);
+                    }
+                }
+            }
+
+            // forget sessions nobody renews
+            self.sessions.retain(|_, regs| {
+                regs.expire();
+                regs.server.is_some() || !regs.clients.is_empty()
+            });
+        }
+    }
+}
+
+/// A server the peers of a session register with to find each other.
+#[derive(Clone, Debug)]
+pub enum Registrar {
+    /// A [`Rendezvous`], after which the peers talk directly.
+    Rendezvous(SocketAddr),
+    /// A [`Relay`], which forwards everything between the peers and only
+    /// admits registrations signed with its secret.
+    Relay(SocketAddr, Secret),
+}
+
+impl Registrar {
+    /// Resolve a rendezvous server and a relay (with its secret), each
+    /// `host[:port]`, in the order peers should try them.
+    pub fn resolve_hosts(
+        rendezvous: Option<&str>,
+        relay: Option<(&str, &str)>,
+    ) -> Result<Vec<Self>> {
+        let mut registrars = Vec::new();
+        if let Some(host) = rendezvous {
+            registrars.push(Self::Rendezvous(resolve_endpoint(host, RENDEZVOUS_PORT)?));
+        }
+        if let Some((host, secret)) = relay {
+            let addr = resolve_endpoint(host, RELAY_PORT)?;
+            registrars.push(Self::Relay(addr, Secret::new(secret)));
+        }
+        Ok(registrars)
+    }
+
+    pub const fn addr(&self) -> SocketAddr {
+        match self {
+            Self::Rendezvous(addr) | Self::Relay(addr, _) => *addr,
+        }
+    }
+
+    /// The datagram registering `role` in `session`, built afresh each time
+    /// since signatures expire. A relay also needs the `cookie` it last
+    /// issued to the socket sending it.
+    fn register(&self, role: Role, session: &str, cookie: Cookie) -> Vec<u8> {
+        match self {
+            Self::Rendezvous(_) => Signal::Register(role, session.to_owned()).encode(),
+            Self::Relay(_, secret) => Allocation::new(role, session)
+                .with_cookie(cookie)
+                .encode(secret, SystemTime::now()),
+        }
+    }
+
+    /// Resolve an endpoint received from this registrar.
+    fn resolve(&self, addr: SocketAddr) -> SocketAddr {
+        match addr.ip().is_unspecified() {
+            true => SocketAddr::new(self.addr().ip(), addr.port()),
+            false => addr,
+        }
+    }
+}
+
+impl std::fmt::Display for Registrar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rendezvous(addr) => write!(f, "rendezvous {addr}"),
+            Self::Relay(addr, _) => write!(f, "relay {addr}"),
+        }
+    }
+}
+
+/// Keep the server of `session` registered with each of `registrars`,
+/// punching back towards every client it is told about. Returns only if
+/// sending fails.
+pub fn advertise(
+    listener: &ArqListener,
+    (registrars, session): (&[Registrar], &str),
+) -> Result<()> {
+    let mut cookies = vec![Cookie::default(); registrars.len()];
+    let mut t_register: Option<Instant> = None;
+    loop {
+        if t_register.is_none_or(|t| t.elapsed() >= REGISTER_INTERVAL) {
+            for (registrar, &cookie) in registrars.iter().zip(&cookies) {
+                let datagram = registrar.register(Role::Server, session, cookie);
+                listener.send_to(&datagram, registrar.addr())?;
+            }
+            t_register = Some(Instant::now());
+        }
+
+        let Some((datagram, addr)) = listener.recv_other(PUNCH_INTERVAL) else {
+            continue;
+        };
+        let from = registrars.iter().position(|r| r.addr() == addr);
+        if datagram == PUNCH {
+            // the client's own punch made it through; confirm the path
+            _ = listener.send_to(&PUNCH, addr);
+        } else if let Some(i) = from
+            && let Some(Challenge(cookie)) = Challenge::decode(&datagram)
+        {
+            // register again at once, proving this is where the server is
+            cookies[i] = cookie;
+            listener.send_to(&registrars[i].register(Role::Server, session, cookie), addr)?;
+        } else if let Some(i) = from
+            && let std::result::Result::Ok(Signal::Peers(clients)) = Signal::decode(&datagram)
+        {
+            for client in clients {
+                _ = listener.send_to(&PUNCH, registrars[i].resolve(client));
+            }
+        }
+    }
+}
+
+/// Find the server of `session` through `registrar`, and punch through to
+/// it from each of `sockets` (their timeouts are changed), returning the
+/// endpoint to reach it at: its public one, or the relay's.
+pub fn punch_through(
+    sockets: &[&UdpSocket],
+    (registrar, session): (&Registrar, &str),
+) -> Result<SocketAddr> {
+    let rendezvous = registrar.addr();
+    for udp in sockets {
+        udp.set_read_timeout(Some(PUNCH_INTERVAL / sockets.len().max(1) as u32))?;
+    }
+
+    let mut server = None;
+    let mut punched = vec![false; sockets.len()];
+    let mut cookies = vec![Cookie::default(); sockets.len()];
+    let mut t_register: Option<Instant> = None;
+    let mut buf = [0; 256];
+    let t = Instant::now();
+
+    while t.elapsed() < PUNCH_TIMEOUT {
+        // ask again until the server is known, and keep punching until it answers
+        let register_due = t_register.is_none_or(|t| t.elapsed() >= REGISTER_INTERVAL);
+        for ((udp, &done), &cookie) in sockets.iter().zip(&punched).zip(&cookies) {
+            if server.is_none() && register_due {
+                udp.send_to(
+                    &registrar.register(Role::Client, session, cookie),
+                    rendezvous,
+                )?;
+            }
+            if let (Some(server), false) = (server, done) {
+                _ = udp.send_to(&PUNCH, server);
+            }
+        }
+        if register_due {
+            t_register = Some(Instant::now());
+        }
+
+        for ((udp, done), cookie) in sockets.iter().zip(&mut punched).zip(&mut cookies) {
+            let (n, addr) = match udp.recv_from(&mut buf) {
+                std::result::Result::Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock
+                            | ErrorKind::TimedOut
+                            | ErrorKind::ConnectionReset
+                            | ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            // a relay forwards the server's punches from its own address
+            let datagram = &buf[..n];
+            if datagram == PUNCH && Some(addr) == server {
+                *done = true;
+            } else if addr == rendezvous
+                && let Some(Challenge(issued)) = Challenge::decode(datagram)
+            {
+                // register again at once, proving this is where the socket is
+                *cookie = issued;
+                udp.send_to(
+                    &registrar.register(Role::Client, session, issued),
+                    rendezvous,
+                )?;
+            } else if addr == rendezvous
+                && let std::result::Result::Ok(Signal::Peers(peers)) = Signal::decode(datagram)
+                && let Some(&addr) = peers.first()
+            {
+                server = Some(registrar.resolve(addr));
+            }
+        }
+
+        if let Some(server) = server
+            && punched.iter().all(|&done| done)
+        {
+            return Ok(server);
+        }
+    }
+
+    match server {
+        Some(server) => bail!("Could not punch through to {server}"),
+        None => bail!("No server registered as {session:?} at {rendezvous}"),
+    }
+}
@@ -0,0 +1,221 @@
+use crate::*;
+use std::{fmt, str::FromStr};
+
+/// Requested size of the encoded frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scale {
+    /// Encode at the source size.
+    #[default]
+    Full,
+    /// Shrink each dimension to the given percentage of the source.
+    Percent(u8),
+    /// Shrink to fit within the given size, preserving the aspect ratio.
+    Fit(u16, u16),
+}
+
+impl Scale {
+    /// Encoded size of a `w`x`h` source. Never upscales.
+    pub fn apply(self, (w, h): (usize, usize)) -> (usize, usize) {
+        let (dw, dh) = match self {
+            Self::Full => (w, h),
+            Self::Percent(p) => (w * p as usize / 100, h * p as usize / 100),
+            Self::Fit(fw, fh) => {
+                let (fw, fh) = (fw as usize, fh as usize);
+                // the tighter of the two bounds decides
+                if w * fh > h * fw {
+                    (fw, h * fw / w.max(1))
+                } else {
+                    (w * fh / h.max(1), fh)
+                }
+            }
+        };
+        (dw.clamp(1, w.max(1)), dh.clamp(1, h.max(1)))
+    }
+
+    /// Encode as `[kind: u8][a: u16 LE][b: u16 LE]`.
+    pub fn to_bytes(self) -> [u8; 5] {
+        let (kind, a, b) = match self {
+            Self::Full => (0, 0, 0),
+            Self::Percent(p) => (1, p as u16, 0),
+            Self::Fit(w, h) => (2, w, h),
+        };
+        let [a0, a1] = a.to_le_bytes();
+        let [b0, b1] = b.to_le_bytes();
+        [kind, a0, a1, b0, b1]
+    }
+
+    pub fn from_bytes([kind, a0, a1, b0, b1]: [u8; 5]) -> Result<Self> {
+        let (a, b) = (u16::from_le_bytes([a0, a1]), u16::from_le_bytes([b0, b1]));
+        match kind {
+            0 => Ok(Self::Full),
+            1 => Self::percent(a),
+            2 => Self::fit(a, b),
+            _ => bail!("Unknown scale kind: {kind}"),
+        }
+    }
+
+    fn percent(p: u16) -> Result<Self> {
+        match p {
+            100 => Ok(Self::Full),
+            1..100 => Ok(Self::Percent(p as u8)),
+            _ => bail!("Scale must be within (0, 1]."),
+        }
+    }
+
+    fn fit(w: u16, h: u16) -> Result<Self> {
+        if w == 0 || h == 0 {
+            bail!("Scale size must be non-zero.");
+        }
+        Ok(Self::Fit(w, h))
+    }
+}
+
+impl FromStr for Scale {
+    type Err = Error;
+
+    /// Parse a factor (`0.5`) or a size to fit within (`1280x720`).
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('x') {
+            Some((w, h)) => Self::fit(w.parse()?, h.parse()?),
+            None => {
+                let factor = s.parse::<f32>()?;
+                if !(factor > 0.0 && factor <= 1.0) {
+                    bail!("Scale must be within (0, 1].");
+                }
+                Self::percent((factor * 100.0).round().max(1.0) as u16)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Scale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => write!(f, "1"),
+            Self::Percent(p) => write!(f, "{}", *p as f32 / 100.0),
+            Self::Fit(w, h) => write!(f, "{w}x{h}"),
+        }
+    }
+}
+
+/// Resamples BGRA frames, reusing its buffers between frames.
+#[derive(Debug, Default)]
+pub struct Scaler {
+    bufs: [Vec<u8>; 2],
+}
+
+impl Scaler {
+    /// Shrink `src` into `dst` at `size`, keeping its source dimensions.
+    ///
+    /// Halves with a 2x2 box filter while possible, then finishes with a
+    /// bilinear resample to the exact size.
+    pub fn scale(&mut self, src: &Region, dst: &mut Region, (dw, dh): (usize, usize)) {
+        let mut header = src.header();
+        header.set_w(dw as i32);
+        header.set_h(dh as i32);
+        header.set_l(dw * dh * 4);
+        dst.update(header);
+
+        // ping-pong between the two buffers
+        let [mut front, mut back] = self.bufs.each_mut();
+        let (mut w, mut h) = (src.w() as usize, src.h() as usize);
+        let mut halved = false;
+
+        while dw * 2 <= w && dh * 2 <= h {
+            let input = if halved { front.as_slice() } else { src.data() };
+            halve(input, (w, h), back);
+            std::mem::swap(&mut front, &mut back);
+            (w, h) = (w / 2, h / 2);
+            halved = true;
+        }
+
+        let input = if halved { front.as_slice() } else { src.data() };
+        if (w, h) == (dw, dh) {
+            dst.data_mut().copy_from_slice(input);
+        } else {
+            bilinear(input, (w, h), dst.data_mut(), (dw, dh));
+        }
+    }
+}
+
+/// Halve a `w`x`h` frame with a 2x2 box filter, dropping any odd row/column.
+pub fn halve(src: &[u8], (w, h): (usize, usize), dst: &mut Vec<u8>) {
+    let (dw, dh) = (w / 2, h / 2);
+    dst.resize(dw * dh * 4, 0);
+
+    for y in 0..dh {
+        let r0 = &src[2 * y * w * 4..][..w * 4];
+        let r1 = &src[(2 * y + 1) * w * 4..][..w * 4];
+        let out = &mut dst[y * dw * 4..][..dw * 4];
+
+        // vectorized bulk, then the remainder
+        for x in halve_row_simd(r0, r1, out)..dw {
+            for c in 0..4 {
+                let i = x * 8 + c;
+                let sum = r0[i] as u16 + r0[i + 4] as u16 + r1[i] as u16 + r1[i + 4] as u16;
+                out[x * 4 + c] = sum.div_ceil(4) as u8;
+            }
+        }
+    }
+}
+
+/// Halve rows `r0` and `r1` into `out`, four pixels at a time. Returns the
+/// number of pixels written.
+#[cfg(target_arch = "x86_64")]
+fn halve_row_simd(r0: &[u8], r1: &[u8], out: &mut [u8]) -> usize {
+    use std::arch::x86_64::*;
+
+    let n = out.len() / 16;
+    for i in 0..n {
+        // SAFETY: sse2 is baseline on x86_64, and each group of 4 output
+        // pixels reads 8 pixels (32 bytes) of each row, all within bounds.
+        unsafe {
+            let load = |row: &[u8], off| _mm_loadu_si128(row.as_ptr().add(i * 32 + off).cast());
+            let zero = _mm_setzero_si128();
+
+            // sum vertically in 16 bits, then add even pixels to odd ones,
+            // rounding up exactly as the scalar remainder does
+            let quad = |off| {
+                let (a, b) = (load(r0, off), load(r1, off));
+                let lo = _mm_add_epi16(_mm_unpacklo_epi8(a, zero), _mm_unpacklo_epi8(b, zero));
+                let hi = _mm_add_epi16(_mm_unpackhi_epi8(a, zero), _mm_unpackhi_epi8(b, zero));
+                let sum = _mm_add_epi16(_mm_unpacklo_epi64(lo, hi), _mm_unpackhi_epi64(lo, hi));
+                _mm_srli_epi16::<2>(_mm_add_epi16(sum, _mm_set1_epi16(3)))
+            };
+
+            let avg = _mm_packus_epi16(quad(0), quad(16));
+            _mm_storeu_si128(out.as_mut_ptr().add(i * 16).cast(), avg);
+        }
+    }
+    n * 4
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn halve_row_simd(_: &[u8], _: &[u8], _: &mut [u8]) -> usize {
+    0
+}
+
+/// Resample a `w`x`h` frame to `dw`x`dh`, sampling at pixel centres.
+pub fn bilinear(src: &[u8], (w, h): (usize, usize), dst: &mut [u8], (dw, dh): (usize, usize)) {
+    // 16.16 fixed-point source coordinate and 8-bit weight of the next pixel
+    let sample = |i: usize, n: usize, dn: usize| {
+        let pos = (((2 * i + 1) * n) << 16) / (2 * dn);
+        let pos = pos.saturating_sub(1 << 15).min((n - 1) << 16);
+        let i0 = pos >> 16;
+        (i0, (i0 + 1).min(n - 1), ((pos & 0xFFFF) >> 8) as u32)
+    };
+
+    for y in 0..dh {
+        let (y0, y1, wy) = sample(y, h, dh);
+        for x in 0..dw {
+            let (x0, x1, wx) = sample(x, w, dw);
+            for c in 0..4 {
+                let px = |x: usize, y: usize| src[(y * w + x) * 4 + c] as u32;
+                let top = px(x0, y0) * (256 - wx) + px(x1, y0) * wx;
+                let bottom = px(x0, y1) * (256 - wx) + px(x1, y1) * wx;
+                dst[(y * dw + x) * 4 + c] =
+                    ((top * (256 - wy) + bottom * wy + (1 << 15)) >> 16) as u8;
+            }
+        }
+    }
+}
@@ -0,0 +1,496 @@
+use crate::{
+    caps::*,
+    codec::EncodeOptions,
+    pace::Pacer,
+    pixel,
+    proto::GoodbyeReason,
+    rate::Quality,
+    scale::*,
+    sock::{BatchSender, DatagramSink},
+    *,
+};
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use std::{
+    cell::UnsafeCell,
+    fmt,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering},
+    thread::sleep,
+    time::Instant,
+};
+
+/// Upper bound on how long the sender waits for a frame before re-checking
+/// the session, which bounds teardown latency for static windows.
+pub const FRAME_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Datagrams the sender may emit back-to-back before pacing kicks in.
+pub const BURST_DATAGRAMS: usize = 4;
+
+/// Phase of the (single) client session served at a time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SessionState {
+    /// No client; ready to accept a new one.
+    #[default]
+    Idle,
+    /// A client is connected and frames are being captured and sent.
+    Active,
+    /// The client is gone; capture and sending are winding down.
+    Ending,
+}
+
+/// Lifecycle of a client session, shared by the control, capture and sender
+/// threads.
+#[derive(Debug, Default)]
+pub struct Session {
+    state: Mutex<SessionState>,
+    reason: Mutex<Option<GoodbyeReason>>, // why the session last ended
+    cv: Condvar,
+    fps: AtomicU8, // client frame-rate target (0 = unlimited)
+    quality: Mutex<Quality>,
+    scale: Mutex<Scale>,  // requested by the client
+    keyframe: AtomicBool, // requested by the client
+    acked: AtomicU32,     // latest frame received in full by the client
+}
+
+impl Session {
+    pub fn state(&self) -> SessionState {
+        *self.state.lock()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state() == SessionState::Active
+    }
+
+    /// Frame-rate target requested by the client (0 = unlimited).
+    pub fn fps(&self) -> u8 {
+        self.fps.load(Ordering::Relaxed)
+    }
+
+    pub fn set_fps(&self, fps: u8) {
+        self.fps.store(fps, Ordering::Relaxed);
+    }
+
+    /// Encoding settings picked by the rate controller.
+    pub fn quality(&self) -> Quality {
+        *self.quality.lock()
+    }
+
+    pub fn set_quality(&self, quality: Quality) {
+        *self.quality.lock() = quality;
+    }
+
+    /// Downscaling requested by the client.
+    pub fn scale(&self) -> Scale {
+        *self.scale.lock()
+    }
+
+    pub fn set_scale(&self, scale: Scale) {
+        *self.scale.lock() = scale;
+    }
+
+    /// Ask for the next frame to be a keyframe.
+    pub fn request_keyframe(&self) {
+        self.keyframe.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether a keyframe was requested since the last call.
+    pub fn take_keyframe(&self) -> bool {
+        self.keyframe.swap(false, Ordering::Relaxed)
+    }
+
+    /// Record that the client received frame `seq` in full.
+    pub fn ack(&self, seq: u32) {
+        self.acked.fetch_max(seq, Ordering::Relaxed);
+    }
+
+    /// Latest frame the client received in full (0 = none yet).
+    pub fn acked(&self) -> u32 {
+        self.acked.load(Ordering::Relaxed)
+    }
+
+    /// Minimum time between frames implied by the client's target and the
+    /// link quality, if either caps the rate.
+    pub fn frame_interval(&self) -> Option<Duration> {
+        let fps = [self.fps(), self.quality().fps]
+            .into_iter()
+            .filter(|&fps| fps != 0)
+            .min()?;
+        Some(util::tick_dur(fps as f32))
+    }
+
+    /// Start a new session once the previous one has been released.
+    pub fn begin(&self) {
+        let mut state = self.state.lock();
+        self.cv.wait_while(&mut state, |s| *s != SessionState::Idle);
+        self.set_fps(0);
+        self.set_quality(Quality::default());
+        self.set_scale(Scale::Full);
+        self.take_keyframe();
+        self.acked.store(0, Ordering::Relaxed);
+        *self.reason.lock() = None;
+        *state = SessionState::Active;
+        self.cv.notify_all();
+    }
+
+    /// Request teardown because the session was closed on purpose. Returns
+    /// `false` if the session was not active.
+    pub fn end(&self) -> bool {
+        self.end_with(GoodbyeReason::Quit)
+    }
+
+    /// Request teardown for `reason`, which is kept unless the session had
+    /// already ended. Returns `false` if the session was not active.
+    pub fn end_with(&self, reason: GoodbyeReason) -> bool {
+        let mut state = self.state.lock();
+        if *state != SessionState::Active {
+            return false;
+        }
+        *state = SessionState::Ending;
+        *self.reason.lock() = Some(reason);
+        self.cv.notify_all();
+        true
+    }
+
+    /// Why the current or last session ended, if it did.
+    pub fn end_reason(&self) -> Option<GoodbyeReason> {
+        *self.reason.lock()
+    }
+
+    /// Mark the session as fully torn down, allowing a new one to begin.
+    pub fn release(&self) {
+        *self.state.lock() = SessionState::Idle;
+        self.cv.notify_all();
+    }
+
+    /// Block until a session becomes active.
+    pub fn wait_active(&self) {
+        let mut state = self.state.lock();
+        self.cv
+            .wait_while(&mut state, |s| *s != SessionState::Active);
+    }
+
+    /// Wait up to `timeout` for the session to stop being active.
+    /// Returns `true` if it is still active.
+    pub fn wait_ended(&self, timeout: Duration) -> bool {
+        let mut state = self.state.lock();
+        self.cv
+            .wait_while_for(&mut state, |s| *s == SessionState::Active, timeout);
+        *state == SessionState::Active
+    }
+}
+
+/// Frames handed over through a [`FrameSlot`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Frames published by the capture side.
+    pub published: u64,
+    /// Frames overwritten before the sender took them.
+    pub dropped: u64,
+}
+
+impl FrameStats {
+    /// Frames handed over since `earlier` was taken.
+    pub const fn since(&self, earlier: Self) -> Self {
+        Self {
+            published: self.published - earlier.published,
+            dropped: self.dropped - earlier.dropped,
+        }
+    }
+}
+
+/// Marks the middle buffer as not yet taken by the sender.
+const FRESH: u8 = 0b100;
+
+/// Selects the index of the middle buffer.
+const INDEX: u8 = 0b011;
+
+/// Latest-frame handoff between the capture and sender threads.
+///
+/// A triple buffer: the capture side fills its back buffer and the sender
+/// reads its front one, neither waiting on the other, while the finished
+/// frame in the middle is swapped atomically with either. Buffers keep their
+/// allocations as they rotate, and a frame the sender never took before the
+/// next one replaced it is counted as dropped.
+pub struct FrameSlot {
+    bufs: [UnsafeCell<Region>; 3],
+    state: AtomicU8,     // index of the middle buffer, plus FRESH
+    back: Mutex<usize>,  // index owned by the capture side
+    front: Mutex<usize>, // index owned by the sender
+    signal: Mutex<()>,   // guards sleeping on `cv`
+    cv: Condvar,
+    published: AtomicU64,
+    dropped: AtomicU64,
+}
+
+// SAFETY: each buffer index is held by exactly one of `back`, `front` and
+// `state` at any time, and a buffer is only accessed by whoever holds its
+// index (through the matching lock), so references to it never alias.
+unsafe impl Sync for FrameSlot {}
+
+impl Default for FrameSlot {
+    fn default() -> Self {
+        Self {
+            bufs: Default::default(),
+            state: AtomicU8::new(1),
+            back: Mutex::new(0),
+            front: Mutex::new(2),
+            signal: Mutex::new(()),
+            cv: Condvar::new(),
+            published: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+}
+
+impl fmt::Debug for FrameSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameSlot")
+            .field("state", &self.state)
+            .field("stats", &self.stats())
+            .finish_non_exhaustive()
+    }
+}
+
+impl FrameSlot {
+    /// Fill the back buffer through `f`, then swap it into the middle and
+    /// wake the sender.
+    pub fn publish(&self, f: impl FnOnce(&mut Region)) {
+        let mut back = self.back.lock();
+        // SAFETY: the back index is only held here, under its lock
+        f(unsafe { &mut *self.bufs[*back].get() });
+
+        let prev = self.state.swap(*back as u8 | FRESH, Ordering::AcqRel);
+        *back = (prev & INDEX) as usize;
+        drop(back);
+
+        self.published.fetch_add(1, Ordering::Relaxed);
+        if prev & FRESH != 0 {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // taken briefly so that a sender about to sleep cannot miss this
+        let _signal = self.signal.lock();
+        self.cv.notify_one();
+    }
+
+    /// Wait up to `timeout` for a frame the sender has not taken yet, and
+    /// swap it to the front. Returns `None` on timeout.
+    pub fn take(&self, timeout: Duration) -> Option<FrameGuard<'_>> {
+        let mut front = self.front.lock();
+        let fresh = || self.state.load(Ordering::Acquire) & FRESH != 0;
+        if !fresh() {
+            let mut signal = self.signal.lock();
+            self.cv.wait_while_for(&mut signal, |_| !fresh(), timeout);
+        }
+        if !fresh() {
+            return None;
+        }
+
+        // only the capture side replaces the middle, always with a fresh one
+        let prev = self.state.swap(*front as u8, Ordering::AcqRel);
+        *front = (prev & INDEX) as usize;
+        Some(FrameGuard { slot: self, front })
+    }
+
+    /// Frames handed over so far.
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            published: self.published.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The sender's view of the frame it took from a [`FrameSlot`].
+pub struct FrameGuard<'a> {
+    slot: &'a FrameSlot,
+    front: MutexGuard<'a, usize>,
+}
+
+impl Deref for FrameGuard<'_> {
+    type Target = Region;
+
+    fn deref(&self) -> &Region {
+        // SAFETY: the front index is held by this guard
+        unsafe { &*self.slot.bufs[*self.front].get() }
+    }
+}
+
+impl DerefMut for FrameGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Region {
+        // SAFETY: the front index is held by this guard
+        unsafe { &mut *self.slot.bufs[*self.front].get() }
+    }
+}
+
+/// Server-side limits applied to every session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SendOptions {
+    /// Maximum send rate in bits/sec.
+    pub max_bitrate: Option<u64>,
+    /// Downscaling applied to every frame.
+    pub scale: Scale,
+    /// Time between forced keyframes (never if `None`).
+    pub keyframe_interval: Option<Duration>,
+    /// Workers compressing chunks in parallel (0 = one per core).
+    pub threads: usize,
+    /// Coalesce equal-sized datagrams with UDP GSO (Linux only).
+    pub gso: bool,
+}
+
+/// Wait up to `timeout` for the client's initial UDP datagram.
+pub fn accept_peer(udp: &UdpSocket, timeout: Duration) -> Result<Option<SocketAddr>> {
+    let t = Instant::now();
+    while let Some(rem) = timeout
+        .checked_sub(t.elapsed())
+        .filter(|rem| !rem.is_zero())
+    {
+        udp.set_read_timeout(Some(rem))?;
+        match udp.recv_from(&mut [0; 1]) {
+            std::result::Result::Ok((_, addr)) => return Ok(Some(addr)),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            // a previous peer's port is gone (Windows reports this on recv)
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(None)
+}
+
+/// Send frames from `slot` to `addr` until the session ends.
+///
+/// Frames are paced to the session's frame-rate target and encoded at its
+/// current [`Quality`]; those published in between are superseded rather than
+/// queued. Each frame's chunks are spread across the frame interval, and
+/// frames are shrunk to the smallest of the server's, client's and link's
+/// scale, then converted to the session's pixel format and encoded with its
+/// codec, against the latest frame the client acknowledged where possible.
+/// Keyframes are forced every `keyframe_interval` and on request, and
+/// datagrams leave in batches between the pacer's pauses. `on_frame` is
+/// invoked with each sent header and the time spent on it.
+pub fn stream_frames(
+    udp: &UdpSocket,
+    addr: SocketAddr,
+    (params, opts): (SessionParams, SendOptions),
+    (slot, session): (&FrameSlot, &Session),
+    on_frame: impl FnMut(&RegionHeader, Duration),
+) -> Result<()> {
+    let mut batch = BatchSender::new(udp, addr, params.max_datagram(), opts.gso);
+    send_frames(&mut batch, (params, opts), (slot, session), on_frame)
+}
+
+/// [`stream_frames`] into any [`DatagramSink`], such as a QUIC connection.
+pub fn send_frames(
+    batch: &mut (impl DatagramSink + ?Sized),
+    (params, opts): (SessionParams, SendOptions),
+    (slot, session): (&FrameSlot, &Session),
+    mut on_frame: impl FnMut(&RegionHeader, Duration),
+) -> Result<()> {
+    let codec = params.codec()?;
+    let format = params.pixel_format()?;
+    let mut encoder = codec::encoder(params, &opts)?;
+    let mut pacer = Pacer::new(params.max_datagram() * BURST_DATAGRAMS);
+    let max_rate = opts.max_bitrate.map(|bits| bits as f64 / 8.0);
+    let mut scaler = Scaler::default();
+    let mut datagrams = Vec::new(); // the current frame, encoded back to back
+    let mut ends = Vec::new(); // where each of its datagrams ends
+    let mut scaled_region = Region::default();
+    let mut converted_region = Region::default();
+    let mut last_sent: Option<Instant> = None;
+    let mut last_keyframe = Instant::now();
+
+    while session.is_active() {
+        // hold off until the client's frame interval has passed
+        let rem = last_sent
+            .zip(session.frame_interval())
+            .map_or(Duration::ZERO, |(t, dur)| dur.saturating_sub(t.elapsed()));
+        if !rem.is_zero() {
+            sleep(rem.min(FRAME_TIMEOUT));
+            continue;
+        }
+
+        // wait for the next frame, re-checking the session periodically
+        let Some(mut current_region) = slot.take(FRAME_TIMEOUT) else {
+            continue;
+        };
+        let t = Instant::now();
+        last_sent = Some(t);
+
+        // record the captured size, then shrink to the tightest scale
+        let (w, h) = (current_region.w(), current_region.h());
+        current_region.set_src_w(w);
+        current_region.set_src_h(h);
+
+        let quality = session.quality();
+        let link = Scale::Percent(100 / quality.scale.max(1));
+        let size = [opts.scale, session.scale(), link]
+            .map(|scale| scale.apply((w as usize, h as usize)))
+            .into_iter()
+            .min_by_key(|(w, h)| w * h)
+            .unwrap_or_default();
+        let size = codec.frame_size(size);
+
+        let region = if size == (w as usize, h as usize) {
+            &current_region
+        } else {
+            scaler.scale(&current_region, &mut scaled_region, size);
+            &scaled_region
+        };
+
+        // convert to the session's wire pixel format
+        let region = match format {
+            PixelFormat::Bgra8 => region,
+            format => {
+                pixel::convert(format, region.data(), size, converted_region.data_mut());
+                let l = converted_region.data().len();
+                *converted_region.header_mut() = region.header();
+                converted_region.set_l(l);
+                &converted_region
+            }
+        };
+        let periodic = opts
+            .keyframe_interval
+            .is_some_and(|dur| last_keyframe.elapsed() >= dur);
+        let keyframe = session.take_keyframe() || periodic;
+        if keyframe {
+            last_keyframe = t;
+        }
+        let encode_opts = EncodeOptions {
+            level: quality.level,
+            keyframe,
+            acked: session.acked(),
+        };
+
+        // encode the whole frame first, so that it is paced by its own size
+        datagrams.clear();
+        ends.clear();
+        let header = encoder.encode(region, encode_opts, &mut |datagram| {
+            datagrams.extend_from_slice(datagram);
+            ends.push(datagrams.len());
+            Ok(())
+        })?;
+        pacer.set_frame_rate(datagrams.len(), session.frame_interval(), max_rate);
+
+        // send the frame to the client
+        let mut start = 0;
+        for &end in &ends {
+            let datagram = &datagrams[start..end];
+            let delay = pacer.delay(datagram.len());
+            if !delay.is_zero() {
+                // release what the pacer already admitted before holding off
+                batch.flush()?;
+                sleep(delay);
+            }
+            batch.push(datagram)?;
+            start = end;
+        }
+        batch.flush()?;
+
+        if let Some(header) = header {
+            on_frame(&header, t.elapsed());
+        }
+    }
+    Ok(())
+}
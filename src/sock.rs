@@ -0,0 +1,441 @@
+use socket2::SockRef;
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+};
+
+/// Datagrams moved per system call.
+pub const BATCH: usize = 32;
+
+/// Largest UDP payload, and so the largest GSO send or GRO buffer.
+pub const MAX_UDP_PAYLOAD: usize = 65_507;
+
+/// Ask the kernel for `send` and `recv` bytes of socket buffer (the default
+/// where `None`), returning the sizes actually granted, which the OS may cap
+/// (e.g. at `net.core.rmem_max` on Linux).
+pub fn set_buffer_sizes(
+    udp: &UdpSocket,
+    (send, recv): (Option<usize>, Option<usize>),
+) -> io::Result<(usize, usize)> {
+    let sock = SockRef::from(udp);
+    if let Some(size) = send {
+        sock.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = recv {
+        sock.set_recv_buffer_size(size)?;
+    }
+    Ok((sock.send_buffer_size()?, sock.recv_buffer_size()?))
+}
+
+/// Destination of a session's media datagrams.
+pub trait DatagramSink {
+    /// Queue a copy of `datagram`, sending it whenever the sink sees fit.
+    fn push(&mut self, datagram: &[u8]) -> io::Result<()>;
+
+    /// Send every queued datagram, in order.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Source of a session's media datagrams.
+pub trait DatagramSource {
+    /// Block (up to the read timeout) until at least one datagram arrives,
+    /// then take as many as are queued without blocking again.
+    fn recv(&mut self) -> io::Result<()>;
+
+    /// Datagrams taken by the last [`DatagramSource::recv`], in arrival order.
+    fn datagrams(&self) -> impl Iterator<Item = &[u8]>;
+
+    /// Datagrams dropped on arrival for want of buffer space so far.
+    fn drops(&self) -> u32;
+}
+
+/// Outgoing datagrams for one peer, queued and sent in batches.
+///
+/// On Linux a batch leaves in a single `sendmmsg` call, and with GSO enabled
+/// runs of equal-sized datagrams are further coalesced into one buffer each
+/// for the kernel (or NIC) to split. Elsewhere datagrams are sent one by one.
+#[derive(Debug)]
+pub struct BatchSender<'a> {
+    udp: &'a UdpSocket,
+    addr: SocketAddr,
+    data: Vec<u8>,
+    ends: Vec<usize>, // end of each datagram within `data`
+    gso: bool,
+}
+
+impl<'a> BatchSender<'a> {
+    /// Sender of datagrams up to `max_datagram` bytes to `addr`, coalescing
+    /// them with UDP GSO if `gso` is set and the platform supports it.
+    pub fn new(udp: &'a UdpSocket, addr: SocketAddr, max_datagram: usize, gso: bool) -> Self {
+        Self {
+            udp,
+            addr,
+            data: Vec::with_capacity(max_datagram * BATCH),
+            ends: Vec::with_capacity(BATCH),
+            gso: gso && cfg!(target_os = "linux"),
+        }
+    }
+
+    /// Whether datagrams are coalesced with GSO; turned off for good if the
+    /// path rejects it.
+    pub const fn gso(&self) -> bool {
+        self.gso
+    }
+
+    /// Datagrams queued but not yet sent.
+    pub const fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    fn datagrams(&self) -> impl Iterator<Item = &[u8]> {
+        let starts = std::iter::once(0).chain(self.ends.iter().copied());
+        starts.zip(&self.ends).map(|(a, &b)| &self.data[a..b])
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send(&mut self) -> io::Result<()> {
+        let mut datagrams = [&[][..]; BATCH];
+        for (dst, src) in datagrams.iter_mut().zip(self.datagrams()) {
+            *dst = src;
+        }
+        let datagrams = &datagrams[..self.ends.len()];
+
+        let (mut sent, mut gso) = (0, self.gso);
+        let res = loop {
+            if sent == datagrams.len() {
+                break Ok(());
+            }
+            match sys::send(self.udp, &self.addr, &datagrams[sent..], gso) {
+                std::result::Result::Ok(n) => sent += n,
+                // the segments do not fit the path (or the NIC lacks support)
+                Err(e) if gso && matches!(e.raw_os_error(), Some(libc::EINVAL | libc::EIO)) => {
+                    gso = false
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.gso = gso;
+        res
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn send(&mut self) -> io::Result<()> {
+        for datagram in self.datagrams() {
+            self.udp.send_to(datagram, self.addr)?;
+        }
+        Ok(())
+    }
+}
+
+impl DatagramSink for BatchSender<'_> {
+    /// Queue a copy of `datagram`, sending the batch once it is full.
+    fn push(&mut self, datagram: &[u8]) -> io::Result<()> {
+        self.data.extend_from_slice(datagram);
+        self.ends.push(self.data.len());
+        if self.ends.len() == BATCH {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let res = self.send();
+        self.data.clear();
+        self.ends.clear();
+        res
+    }
+}
+
+/// Incoming datagrams, received in batches.
+///
+/// On Linux up to [`BATCH`] datagrams are taken per `recvmmsg` call, and with
+/// GRO enabled each may hold several coalesced segments, which are split apart
+/// again by [`DatagramSource::datagrams`]. The kernel also reports how many
+/// datagrams it dropped for want of buffer space. Elsewhere one datagram is
+/// received at a time.
+#[derive(Debug)]
+pub struct BatchReceiver<'a> {
+    udp: &'a UdpSocket,
+    data: Vec<u8>, // one slot per buffer
+    slot: usize,
+    filled: Vec<(usize, usize)>, // length and segment size per buffer
+    gro: bool,
+    drops: Option<(u32, u32)>, // the kernel's first and latest drop counts
+}
+
+impl<'a> BatchReceiver<'a> {
+    /// Receiver of datagrams up to `max_datagram` bytes, asking the kernel to
+    /// coalesce them with UDP GRO if `gro` is set and the platform supports it.
+    pub fn new(udp: &'a UdpSocket, max_datagram: usize, gro: bool) -> io::Result<Self> {
+        #[cfg(target_os = "linux")]
+        {
+            if gro {
+                sys::set_gro(udp)?;
+            }
+            sys::set_rxq_ovfl(udp)?;
+        }
+        let gro = gro && cfg!(target_os = "linux");
+        let slot = if gro {
+            max_datagram.max(MAX_UDP_PAYLOAD)
+        } else {
+            max_datagram
+        };
+        let slots = if cfg!(target_os = "linux") { BATCH } else { 1 };
+
+        Ok(Self {
+            udp,
+            data: vec![0; slot * slots],
+            slot,
+            filled: Vec::with_capacity(slots),
+            gro,
+            drops: None,
+        })
+    }
+
+    pub const fn gro(&self) -> bool {
+        self.gro
+    }
+}
+
+impl DatagramSource for BatchReceiver<'_> {
+    /// Datagrams the kernel dropped since the first one this receiver took,
+    /// because the socket's receive buffer was full (always 0 outside Linux).
+    /// Drops only become known with the next datagram to arrive.
+    fn drops(&self) -> u32 {
+        self.drops
+            .map_or(0, |(first, latest)| latest.wrapping_sub(first))
+    }
+
+    fn recv(&mut self) -> io::Result<()> {
+        self.filled.clear();
+
+        #[cfg(target_os = "linux")]
+        {
+            let (first, last) = sys::recv(self.udp, &mut self.data, self.slot, &mut self.filled)?;
+            self.drops.get_or_insert((first, last)).1 = last;
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let n = self.udp.recv(&mut self.data)?;
+            self.filled.push((n, n));
+        }
+        Ok(())
+    }
+
+    fn datagrams(&self) -> impl Iterator<Item = &[u8]> {
+        self.data
+            .chunks(self.slot)
+            .zip(&self.filled)
+            .flat_map(|(buf, &(len, segment))| buf[..len].chunks(segment.max(1)))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::*;
+    use std::{mem, os::fd::AsRawFd, ptr};
+
+    /// Most segments the kernel accepts in a single GSO send.
+    const MAX_SEGMENTS: usize = 64;
+
+    /// Room for two control messages carrying an int each, aligned for
+    /// `cmsghdr`.
+    type CmsgBuf = [u64; 6];
+
+    fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        // SAFETY: all-zero is a valid sockaddr, and both variants fit in the storage
+        unsafe {
+            let mut storage: libc::sockaddr_storage = mem::zeroed();
+            let len = match addr {
+                SocketAddr::V4(addr) => {
+                    let sin = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                    sin.sin_family = libc::AF_INET as _;
+                    sin.sin_port = addr.port().to_be();
+                    sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                    size_of::<libc::sockaddr_in>()
+                }
+                SocketAddr::V6(addr) => {
+                    let sin6 = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                    sin6.sin6_family = libc::AF_INET6 as _;
+                    sin6.sin6_port = addr.port().to_be();
+                    sin6.sin6_flowinfo = addr.flowinfo();
+                    sin6.sin6_addr.s6_addr = addr.ip().octets();
+                    sin6.sin6_scope_id = addr.scope_id();
+                    size_of::<libc::sockaddr_in6>()
+                }
+            };
+            (storage, len as _)
+        }
+    }
+
+    fn enable(udp: &UdpSocket, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        // SAFETY: `on` outlives the call and its size is passed along
+        let ret = unsafe {
+            libc::setsockopt(
+                udp.as_raw_fd(),
+                level,
+                name,
+                &on as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as _,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn set_gro(udp: &UdpSocket) -> io::Result<()> {
+        enable(udp, libc::SOL_UDP, libc::UDP_GRO)
+    }
+
+    /// Have each received datagram carry the socket's running drop count.
+    pub fn set_rxq_ovfl(udp: &UdpSocket) -> io::Result<()> {
+        enable(udp, libc::SOL_SOCKET, libc::SO_RXQ_OVFL)
+    }
+
+    /// Send `datagrams` with one `sendmmsg`, returning how many left.
+    pub fn send(
+        udp: &UdpSocket,
+        addr: &SocketAddr,
+        datagrams: &[&[u8]],
+        gso: bool,
+    ) -> io::Result<usize> {
+        let (name, namelen) = sockaddr(addr);
+        // SAFETY: all-zero is valid for these plain C structs
+        let mut iovs: [libc::iovec; BATCH] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH] = unsafe { mem::zeroed() };
+        let mut cmsgs: [CmsgBuf; BATCH] = [[0; 6]; BATCH];
+        let mut counts = [0; BATCH]; // datagrams per message
+
+        let (mut i, mut n) = (0, 0);
+        while i < datagrams.len() {
+            // a run of equal sizes, optionally ended by one shorter datagram
+            let size = datagrams[i].len();
+            let (mut j, mut total) = (i + 1, size);
+            while gso && size != 0 && j < datagrams.len() && j - i < MAX_SEGMENTS {
+                let len = datagrams[j].len();
+                if len > size || total + len > MAX_UDP_PAYLOAD {
+                    break;
+                }
+                total += len;
+                j += 1;
+                if len < size {
+                    break;
+                }
+            }
+
+            for (iov, datagram) in iovs[i..j].iter_mut().zip(&datagrams[i..j]) {
+                iov.iov_base = datagram.as_ptr() as *mut _;
+                iov.iov_len = datagram.len();
+            }
+            let hdr = &mut msgs[n].msg_hdr;
+            hdr.msg_name = &name as *const _ as *mut _;
+            hdr.msg_namelen = namelen;
+            hdr.msg_iov = iovs[i..].as_mut_ptr();
+            hdr.msg_iovlen = (j - i) as _;
+            if j - i > 1 {
+                hdr.msg_control = cmsgs[n].as_mut_ptr() as *mut _;
+                // SAFETY: the buffer has room for a u16 control message
+                unsafe {
+                    hdr.msg_controllen = libc::CMSG_SPACE(size_of::<u16>() as _) as _;
+                    let cmsg = libc::CMSG_FIRSTHDR(hdr);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as _) as _;
+                    ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, size as u16);
+                }
+            }
+            counts[n] = j - i;
+            (i, n) = (j, n + 1);
+        }
+
+        // SAFETY: every message points into `name`, `iovs` and `cmsgs`,
+        // which outlive the call
+        let ret = unsafe { libc::sendmmsg(udp.as_raw_fd(), msgs.as_mut_ptr(), n as _, 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(counts[..ret as usize].iter().sum())
+    }
+
+    /// Receive into `slot`-sized buffers of `data` with one `recvmmsg`,
+    /// recording each one's length and segment size in `filled`, and
+    /// returning the socket's running drop count as of the first and last
+    /// datagram taken.
+    pub fn recv(
+        udp: &UdpSocket,
+        data: &mut [u8],
+        slot: usize,
+        filled: &mut Vec<(usize, usize)>,
+    ) -> io::Result<(u32, u32)> {
+        // SAFETY: all-zero is valid for these plain C structs
+        let mut iovs: [libc::iovec; BATCH] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH] = unsafe { mem::zeroed() };
+        let mut cmsgs: [CmsgBuf; BATCH] = [[0; 6]; BATCH];
+
+        let mut n = 0;
+        for (((iov, msg), cmsg), buf) in iovs
+            .iter_mut()
+            .zip(&mut msgs)
+            .zip(&mut cmsgs)
+            .zip(data.chunks_exact_mut(slot))
+        {
+            iov.iov_base = buf.as_mut_ptr() as *mut _;
+            iov.iov_len = buf.len();
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_control = cmsg.as_mut_ptr() as *mut _;
+            msg.msg_hdr.msg_controllen = size_of::<CmsgBuf>() as _;
+            n += 1;
+        }
+
+        // SAFETY: every message points into `iovs`, `cmsgs` and `data`, which
+        // outlive the call; only the first receive may block
+        let ret = unsafe {
+            libc::recvmmsg(
+                udp.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                n as _,
+                libc::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut drops = None;
+        for msg in &msgs[..ret as usize] {
+            let len = msg.msg_len as usize;
+            let mut segment = len;
+            let mut count = 0; // datagrams queued before any drop carry none
+            // SAFETY: the kernel filled in valid control messages
+            unsafe {
+                let mut cmsg = libc::CMSG_FIRSTHDR(&msg.msg_hdr);
+                while !cmsg.is_null() {
+                    let data = libc::CMSG_DATA(cmsg);
+                    match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                        (libc::SOL_UDP, libc::UDP_GRO) => {
+                            segment = ptr::read_unaligned(data as *const libc::c_int) as usize;
+                        }
+                        (libc::SOL_SOCKET, libc::SO_RXQ_OVFL) => {
+                            count = ptr::read_unaligned(data as *const u32);
+                        }
+                        _ => {}
+                    }
+                    cmsg = libc::CMSG_NXTHDR(&msg.msg_hdr, cmsg);
+                }
+            }
+            filled.push((len, segment));
+            drops.get_or_insert((count, count)).1 = count;
+        }
+        Ok(drops.unwrap_or_default())
+    }
+}
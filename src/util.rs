@@ -1,15 +1,94 @@
 use std::{
     net::{SocketAddr, ToSocketAddrs},
+    path::PathBuf,
     time::Duration,
 };
 
-/// Retrieve a default socket with specified port number.
-pub fn get_socket_addr(port: u16) -> SocketAddr {
-    ("127.0.0.1", port)
+/// Resolve `host` (a hostname, or an IPv4 or IPv6 address, optionally in
+/// brackets) to every matching socket address with the given port.
+pub fn resolve_all(host: &str, port: u16) -> crate::Result<Vec<SocketAddr>> {
+    let name = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let addrs: Vec<_> = (name, port)
         .to_socket_addrs()
-        .expect("Failed to retrieve socket address(s)")
-        .next()
-        .expect("No available socket address(s)")
+        .map_err(|e| crate::anyhow!("Failed to resolve {host}: {e}"))?
+        .collect();
+    if addrs.is_empty() {
+        crate::bail!("No addresses found for {host}")
+    }
+    Ok(addrs)
+}
+
+/// Resolve `host` to its first socket address with the given port.
+pub fn resolve(host: &str, port: u16) -> crate::Result<SocketAddr> {
+    Ok(resolve_all(host, port)?[0])
+}
+
+/// Resolve `host[:port]` (an IPv6 address needs brackets to take a port),
+/// falling back to `port` where none is given.
+pub fn resolve_endpoint(s: &str, port: u16) -> crate::Result<SocketAddr> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    match s.rsplit_once(':') {
+        // a bare IPv6 address has colons of its own
+        Some((host, p)) if !host.contains(':') || host.ends_with(']') => {
+            let p = p
+                .parse()
+                .map_err(|_| crate::anyhow!("Invalid port in {s}"))?;
+            resolve(host, p)
+        }
+        _ => resolve(s, port),
+    }
+}
+
+/// Name of this host, as far as the environment tells.
+pub fn hostname() -> String {
+    let name = std::env::var("COMPUTERNAME") // Windows
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| std::env::var("HOSTNAME").ok());
+    match name.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() => name.to_owned(),
+        _ => "unknown".to_owned(),
+    }
+}
+
+/// Directory remdes keeps its configuration in: `%APPDATA%\remdes` on
+/// Windows, `~/Library/Application Support/remdes` on macOS and
+/// `$XDG_CONFIG_HOME/remdes` (or `~/.config/remdes`) elsewhere.
+pub fn config_dir() -> Option<PathBuf> {
+    let env = |name| std::env::var_os(name).filter(|dir| !dir.is_empty());
+    let base = if cfg!(windows) {
+        PathBuf::from(env("APPDATA")?)
+    } else if cfg!(target_os = "macos") {
+        PathBuf::from(env("HOME")?).join("Library/Application Support")
+    } else {
+        env("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(env("HOME")?).join(".config")))?
+    };
+    Some(base.join("remdes"))
+}
+
+/// Parse a whole number of milliseconds into a non-zero [`Duration`].
+pub fn parse_millis(s: &str) -> crate::Result<Duration> {
+    let ms = s.parse::<u64>()?;
+    if ms == 0 {
+        crate::bail!("Duration must be greater than zero.")
+    }
+    Ok(Duration::from_millis(ms))
+}
+
+/// Check that a peer pinged every `heartbeat` is not declared dead after
+/// `timeout` between two pings.
+pub fn check_heartbeat((heartbeat, timeout): (Duration, Duration)) -> crate::Result<()> {
+    if heartbeat >= timeout {
+        crate::bail!("--heartbeat ({heartbeat:?}) must be shorter than --timeout ({timeout:?})")
+    }
+    Ok(())
 }
 
 /// the duration of a single interval based on the given rate.
@@ -0,0 +1,141 @@
+use remdes::{arq::*, proto::*};
+use std::{
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::spawn,
+    time::{Duration, Instant},
+};
+
+/// Upper bound for any exchange in these tests.
+const DEADLINE: Duration = Duration::from_secs(5);
+
+fn listen() -> (UdpSocket, ArqListener) {
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let listener = ArqListener::new(&udp).unwrap();
+    (udp, listener)
+}
+
+/// Forward datagrams between a client and `server`, dropping about one in
+/// `n` at random in either direction.
+fn lossy_proxy(server: SocketAddr, n: u64, stop: Arc<AtomicBool>) -> SocketAddr {
+    let proxy = UdpSocket::bind("127.0.0.1:0").unwrap();
+    proxy
+        .set_read_timeout(Some(Duration::from_millis(20)))
+        .unwrap();
+    let addr = proxy.local_addr().unwrap();
+
+    spawn(move || {
+        let mut buf = [0; 1 << 16];
+        let (mut client, mut rng) = (None, 0x9e37_79b9_7f4a_7c15u64);
+        while !stop.load(Ordering::Relaxed) {
+            let Ok((len, from)) = proxy.recv_from(&mut buf) else {
+                continue;
+            };
+            let to = if from == server {
+                match client {
+                    Some(client) => client,
+                    None => continue,
+                }
+            } else {
+                client = Some(from);
+                server
+            };
+            // xorshift, so that losses do not fall into step with retransmits
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            if rng % n != 0 {
+                _ = proxy.send_to(&buf[..len], to);
+            }
+        }
+    });
+    addr
+}
+
+#[test]
+fn messages_arrive_in_order() {
+    let (_udp, listener) = listen();
+    let client = ControlChannel::new(ArqStream::connect(listener.local_addr().unwrap()).unwrap());
+    for fps in 0..20 {
+        client.send(Message::SetFps(fps)).unwrap();
+    }
+
+    let server = ControlChannel::new(listener.accept().unwrap());
+    server.set_read_timeout(Some(DEADLINE)).unwrap();
+    for fps in 0..20 {
+        assert_eq!(server.recv().unwrap(), Message::SetFps(fps));
+    }
+
+    // and back the other way
+    server.send(Message::Ack(7)).unwrap();
+    client.set_read_timeout(Some(DEADLINE)).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::Ack(7));
+}
+
+#[test]
+fn losses_are_repaired() {
+    let (_udp, listener) = listen();
+    let stop = Arc::new(AtomicBool::new(false));
+    let proxy = lossy_proxy(listener.local_addr().unwrap(), 3, stop.clone());
+
+    let client = ControlChannel::new(ArqStream::connect(proxy).unwrap());
+    let sender = {
+        let client = client.clone();
+        spawn(move || {
+            for seq in 0..50 {
+                client.send(Message::Ack(seq)).unwrap();
+            }
+        })
+    };
+
+    let server = ControlChannel::new(listener.accept().unwrap());
+    server.set_read_timeout(Some(DEADLINE)).unwrap();
+    for seq in 0..50 {
+        assert_eq!(server.recv().unwrap(), Message::Ack(seq));
+    }
+    sender.join().unwrap();
+    stop.store(true, Ordering::Relaxed);
+}
+
+#[test]
+fn shutdown_unblocks_receiver() {
+    let (_udp, listener) = listen();
+    let client = ControlChannel::new(ArqStream::connect(listener.local_addr().unwrap()).unwrap());
+
+    let reader = {
+        let client = client.clone();
+        spawn(move || client.recv())
+    };
+    client.shutdown();
+    assert!(reader.join().unwrap().is_err());
+    assert!(client.send(Message::RequestKeyframe).is_err());
+}
+
+#[test]
+fn read_timeout_expires() {
+    let (_udp, listener) = listen();
+    let client = ControlChannel::new(ArqStream::connect(listener.local_addr().unwrap()).unwrap());
+    client
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+
+    let t = Instant::now();
+    assert!(client.recv().is_err());
+    assert!(t.elapsed() < DEADLINE);
+}
+
+#[test]
+fn media_hello_names_the_stream_target() {
+    let (_udp, listener) = listen();
+    let media = UdpSocket::bind("127.0.0.1:0").unwrap();
+    media.connect(listener.local_addr().unwrap()).unwrap();
+    media.send(&MEDIA_HELLO).unwrap();
+
+    let ip = media.local_addr().unwrap().ip();
+    let addr = listener.accept_media(ip, DEADLINE);
+    assert_eq!(addr, Some(media.local_addr().unwrap()));
+    assert_eq!(listener.accept_media(ip, Duration::from_millis(20)), None);
+}
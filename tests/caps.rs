@@ -0,0 +1,108 @@
+use remdes::{UDP_CHUNK_SIZE, caps::*, sock::MAX_UDP_PAYLOAD};
+
+/// A hello of this version advertising chunks of `max_chunk` bytes.
+fn with_max_chunk(max_chunk: u32) -> Hello {
+    let mut bytes = Hello::new().to_bytes();
+    bytes[8..].copy_from_slice(&max_chunk.to_le_bytes());
+    Hello::decode(&bytes).unwrap()
+}
+
+fn refusal(local: Hello, peer: Hello) -> String {
+    local.negotiate(&peer).unwrap_err().to_string()
+}
+
+#[test]
+fn matching_hellos_pick_the_preferred_options() {
+    let params = Hello::new().negotiate(&Hello::new()).unwrap();
+    assert_eq!(params.codec().unwrap(), Codec::ALL[0]);
+    assert_eq!(params.pixel_format().unwrap(), PixelFormat::ALL[0]);
+    assert_eq!(params.chunk_size(), UDP_CHUNK_SIZE);
+
+    // only H.264 left, which only carries YUV
+    let h264 = Hello::new().with_codecs(Codecs::of(&[Codec::H264]));
+    let params = Hello::new().negotiate(&h264).unwrap();
+    assert_eq!(params.codec().unwrap(), Codec::H264);
+    assert_eq!(params.pixel_format().unwrap(), PixelFormat::Yuv420);
+}
+
+#[test]
+fn versions_must_match() {
+    let old = Hello::decode(&(Hello::VERSION - 1).to_le_bytes()).unwrap();
+    let e = refusal(Hello::new(), old);
+    assert!(e.contains("Protocol version mismatch"), "{e}");
+}
+
+#[test]
+fn peers_need_a_common_codec_and_pixel_format() {
+    let lz4 = Hello::new().with_codecs(Codecs::of(&[Codec::Lz4]));
+    let h264 = Hello::new().with_codecs(Codecs::of(&[Codec::H264]));
+    let e = refusal(lz4, h264);
+    assert!(e.contains("No common codec"), "{e}");
+
+    let bgra = Hello::new().with_pixel_formats(PixelFormats::of(&[PixelFormat::Bgra8]));
+    let gray = Hello::new().with_pixel_formats(PixelFormats::of(&[PixelFormat::Gray8]));
+    let e = refusal(bgra, gray);
+    assert!(e.contains("No common pixel format"), "{e}");
+
+    // common, but not one the only common codec carries
+    let e = refusal(h264, bgra);
+    assert!(e.contains("No common pixel format"), "{e}");
+}
+
+#[test]
+fn chunk_sizes_must_be_usable() {
+    let e = refusal(Hello::new(), with_max_chunk(0));
+    assert!(e.contains("zero chunk size"), "{e}");
+
+    // the smaller side wins, so only both being too large fails
+    let huge = with_max_chunk(u32::MAX);
+    let params = Hello::new().negotiate(&huge).unwrap();
+    assert_eq!(params.chunk_size(), UDP_CHUNK_SIZE);
+    let e = refusal(huge, huge);
+    assert!(e.contains("does not fit in a datagram"), "{e}");
+
+    let largest = with_max_chunk((MAX_UDP_PAYLOAD as u32 - 18) * 255 / 256);
+    let params = largest.negotiate(&largest).unwrap();
+    assert!(params.max_datagram() <= MAX_UDP_PAYLOAD);
+}
+
+#[test]
+fn max_datagram_only_shrinks_chunks() {
+    for len in [576, 1200, 1472, 9000] {
+        let hello = Hello::new().with_max_datagram(len);
+        assert!(hello.max_chunk() < UDP_CHUNK_SIZE);
+        let params = hello.negotiate(&Hello::new()).unwrap();
+        assert!(params.max_datagram() <= len, "{len}");
+    }
+
+    let roomy = Hello::new().with_max_datagram(MAX_UDP_PAYLOAD);
+    assert_eq!(roomy.max_chunk(), UDP_CHUNK_SIZE);
+    let tight = Hello::new().with_max_datagram(1200).with_max_datagram(9000);
+    assert_eq!(
+        tight.max_chunk(),
+        Hello::new().with_max_datagram(1200).max_chunk()
+    );
+    assert_eq!(Hello::new().with_max_datagram(10).max_chunk(), 0);
+}
+
+#[test]
+fn clients_check_the_chosen_parameters() {
+    let local = Hello::new().with_max_datagram(1200);
+    let params = local.negotiate(&Hello::new()).unwrap();
+    local.accepts(&params).unwrap();
+
+    let with_chunk = |chunk_size: u32| {
+        let mut bytes = params.to_bytes();
+        bytes[4..].copy_from_slice(&chunk_size.to_le_bytes());
+        SessionParams::from_bytes(bytes)
+    };
+    for chunk_size in [0, local.max_chunk() as u32 + 1] {
+        let e = local.accepts(&with_chunk(chunk_size)).unwrap_err();
+        assert!(e.to_string().contains("chunk size"), "{chunk_size}: {e}");
+    }
+
+    let lz4 = Hello::new().with_codecs(Codecs::of(&[Codec::Lz4]));
+    let h264 = Hello::new().with_codecs(Codecs::of(&[Codec::H264]));
+    let e = lz4.accepts(&h264.negotiate(&h264).unwrap()).unwrap_err();
+    assert!(e.to_string().contains("unsupported"), "{e}");
+}
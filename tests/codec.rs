@@ -0,0 +1,414 @@
+use remdes::{caps::*, codec::*, pixel::convert, session::SendOptions, *};
+
+const SIZE: (usize, usize) = (64, 48);
+
+fn params(codec: Codec) -> SessionParams {
+    let hello = Hello::new().with_codecs(Codecs::of(&[codec]));
+    hello.negotiate(&hello).unwrap()
+}
+
+/// A `w`x`h` gradient frame in `format`, shifted by `n`.
+fn frame(format: PixelFormat, (w, h): (usize, usize), n: u8) -> Region {
+    let bgra: Vec<u8> = (0..w * h)
+        .flat_map(|i| {
+            let (x, y) = ((i % w) as u8, (i / w) as u8);
+            [x.wrapping_mul(4).wrapping_add(n), y.wrapping_mul(4), n, 255]
+        })
+        .collect();
+
+    let mut region = Region::default();
+    convert(format, &bgra, (w, h), region.data_mut());
+    let mut header = RegionHeader::default();
+    header.set_w(w as i32);
+    header.set_h(h as i32);
+    header.set_l(region.data().len());
+    *region.header_mut() = header;
+    region
+}
+
+/// Encode `src`, returning its datagrams.
+fn encode(encoder: &mut dyn Encoder, src: &Region, keyframe: bool) -> Vec<Vec<u8>> {
+    encode_with(
+        encoder,
+        src,
+        EncodeOptions {
+            keyframe,
+            ..Default::default()
+        },
+    )
+}
+
+fn encode_with(encoder: &mut dyn Encoder, src: &Region, opts: EncodeOptions) -> Vec<Vec<u8>> {
+    let mut datagrams = Vec::new();
+    encoder
+        .encode(src, opts, &mut |datagram| {
+            datagrams.push(datagram.to_vec());
+            Ok(())
+        })
+        .unwrap()
+        .expect("frame was skipped");
+    datagrams
+}
+
+fn header(datagrams: &[Vec<u8>]) -> RegionHeader {
+    bytemuck::pod_read_unaligned(&datagrams[0])
+}
+
+fn payload_len(datagrams: &[Vec<u8>]) -> usize {
+    datagrams[1..].iter().map(Vec::len).sum()
+}
+
+/// Feed `datagrams` into `decoder`, skipping the chunks in `drop`.
+fn decode(
+    decoder: &mut dyn Decoder,
+    datagrams: &[Vec<u8>],
+    drop: &[u16],
+    dst: &mut Region,
+) -> Result<bool> {
+    let (header, chunks) = datagrams.split_first().unwrap();
+    assert_eq!(header.len(), size_of::<RegionHeader>());
+    decoder.begin(bytemuck::pod_read_unaligned(header));
+
+    for chunk in chunks {
+        assert_ne!(chunk.len(), size_of::<RegionHeader>());
+        let (idx, payload) = unseal(chunk).unwrap();
+        if !drop.contains(&idx) {
+            assert!(decoder.chunk(idx, payload));
+        }
+    }
+    decoder.finish(dst)
+}
+
+/// Mean absolute difference between two equally sized buffers.
+fn distance(a: &[u8], b: &[u8]) -> f64 {
+    assert_eq!(a.len(), b.len());
+    let sum: u64 = a.iter().zip(b).map(|(&a, &b)| a.abs_diff(b) as u64).sum();
+    sum as f64 / a.len() as f64
+}
+
+#[test]
+fn h264_negotiates_yuv420() {
+    let params = params(Codec::H264);
+    assert_eq!(params.codec().unwrap(), Codec::H264);
+    assert_eq!(params.pixel_format().unwrap(), PixelFormat::Yuv420);
+
+    // the first codec wins, even when a later one is also common
+    let params = Hello::new().negotiate(&Hello::new()).unwrap();
+    assert_eq!(params.codec().unwrap(), Codec::Lz4);
+
+    // no format left for the codec
+    let bgra = Hello::new()
+        .with_codecs(Codecs::of(&[Codec::H264]))
+        .with_pixel_formats(PixelFormats::of(&[PixelFormat::Bgra8]));
+    assert!(Hello::new().negotiate(&bgra).is_err());
+}
+
+#[test]
+fn lz4_round_trips_exactly() {
+    let params = params(Codec::Lz4);
+    let mut encoder = encoder(params, &SendOptions::default()).unwrap();
+    let mut decoder = decoder(params, 0).unwrap();
+
+    let src = frame(PixelFormat::Bgra8, SIZE, 7);
+    let datagrams = encode(&mut *encoder, &src, false);
+    let mut dst = Region::default();
+    assert!(decode(&mut *decoder, &datagrams, &[], &mut dst).unwrap());
+    assert_eq!(dst.data(), src.data());
+}
+
+#[test]
+fn lz4_pads_chunks_the_size_of_a_header() {
+    // distinct bytes barely compress, so the payload grows with the frame
+    let bytes = |w: usize| (0..w * 4).map(|i| i as u8).collect::<Vec<_>>();
+    let target = size_of::<RegionHeader>() - 2;
+    let w = (1..64)
+        .find(|&w| lz4::block::compress(&bytes(w), None, false).unwrap().len() == target)
+        .expect("no frame compresses to a header-sized chunk");
+
+    let mut src = Region::default();
+    *src.data_mut() = bytes(w);
+    let mut header = RegionHeader::default();
+    header.set_w(w as i32);
+    header.set_h(1);
+    header.set_l(w * 4);
+    *src.header_mut() = header;
+
+    let params = params(Codec::Lz4);
+    let mut encoder = encoder(params, &SendOptions::default()).unwrap();
+    let mut decoder = decoder(params, 0).unwrap();
+    let datagrams = encode(&mut *encoder, &src, true);
+    assert_eq!(datagrams.len(), 2);
+    assert_eq!(datagrams[1].len(), size_of::<RegionHeader>() + 1);
+    assert_eq!(unseal(&datagrams[1]).unwrap().0, 0);
+
+    let mut dst = Region::default();
+    assert!(decode(&mut *decoder, &datagrams, &[], &mut dst).unwrap());
+    assert_eq!(dst.data(), src.data());
+}
+
+#[test]
+fn unseal_strips_flagged_padding_only() {
+    assert_eq!(unseal(&[3, 0, 9, 9]), Some((3, &[9, 9][..])));
+    assert_eq!(unseal(&[3, 0x80, 9, 0]), Some((3, &[9][..])));
+    assert_eq!(unseal(&[3, 0x80]), None);
+    assert_eq!(unseal(&[3]), None);
+}
+
+#[test]
+fn lz4_deltas_against_acked_frame() {
+    let params = params(Codec::Lz4);
+    let mut encoder = encoder(params, &SendOptions::default()).unwrap();
+    let mut decoder = decoder(params, 0).unwrap();
+    let mut dst = Region::default();
+
+    // nothing acknowledged yet
+    let first = encode(&mut *encoder, &frame(PixelFormat::Bgra8, SIZE, 0), false);
+    assert_eq!((header(&first).seq(), header(&first).base()), (1, 0));
+    assert!(decode(&mut *decoder, &first, &[], &mut dst).unwrap());
+
+    // a small change against the acknowledged frame is mostly zeros
+    let mut src = frame(PixelFormat::Bgra8, SIZE, 0);
+    src.data_mut()[100..120].fill(0xAB);
+    let opts = EncodeOptions {
+        acked: 1,
+        ..Default::default()
+    };
+    let delta = encode_with(&mut *encoder, &src, opts);
+    assert_eq!((header(&delta).seq(), header(&delta).base()), (2, 1));
+    assert!(payload_len(&delta) * 4 < payload_len(&first));
+
+    assert!(decode(&mut *decoder, &delta, &[], &mut dst).unwrap());
+    assert_eq!(dst.data(), src.data());
+
+    // a keyframe is forced when asked to, even with a usable ack
+    let opts = EncodeOptions {
+        keyframe: true,
+        acked: 2,
+        ..Default::default()
+    };
+    let key = encode_with(&mut *encoder, &src, opts);
+    assert_eq!(header(&key).base(), 0);
+}
+
+#[test]
+fn lz4_partial_frames_are_not_referenced() {
+    let params = params(Codec::Lz4);
+    let mut encoder = encoder(params, &SendOptions::default()).unwrap();
+    let mut decoder = decoder(params, 0).unwrap();
+    let mut dst = Region::default();
+    let size = (256, 256); // several chunks
+
+    let first = encode(&mut *encoder, &frame(PixelFormat::Bgra8, size, 0), false);
+    assert!(decode(&mut *decoder, &first, &[], &mut dst).unwrap());
+
+    // an incomplete frame is still shown, but not acknowledged
+    let opts = EncodeOptions {
+        acked: 1,
+        ..Default::default()
+    };
+    let lost = encode_with(&mut *encoder, &frame(PixelFormat::Bgra8, size, 1), opts);
+    assert!(!decode(&mut *decoder, &lost, &[1], &mut dst).unwrap());
+
+    // so the server keeps deltas against the last acknowledged one
+    let src = frame(PixelFormat::Bgra8, size, 2);
+    let next = encode_with(&mut *encoder, &src, opts);
+    assert_eq!(header(&next).base(), 1);
+    assert!(decode(&mut *decoder, &next, &[], &mut dst).unwrap());
+    assert_eq!(dst.data(), src.data());
+
+    // a reference the client never held cannot be decoded
+    let opts = EncodeOptions {
+        acked: 2,
+        ..Default::default()
+    };
+    let orphan = encode_with(&mut *encoder, &src, opts);
+    assert_eq!(header(&orphan).base(), 2);
+    assert!(decode(&mut *decoder, &orphan, &[], &mut dst).is_err());
+}
+
+#[test]
+fn lz4_forgets_old_references() {
+    let params = params(Codec::Lz4);
+    let mut encoder = encoder(params, &SendOptions::default()).unwrap();
+
+    for _ in 0..=HISTORY {
+        encode(&mut *encoder, &frame(PixelFormat::Bgra8, SIZE, 0), false);
+    }
+    let opts = EncodeOptions {
+        acked: 1,
+        ..Default::default()
+    };
+    let datagrams = encode_with(&mut *encoder, &frame(PixelFormat::Bgra8, SIZE, 0), opts);
+    assert_eq!(header(&datagrams).base(), 0);
+}
+
+#[test]
+fn h264_round_trips_closely() {
+    let params = params(Codec::H264);
+    let mut encoder = encoder(params, &SendOptions::default()).unwrap();
+    let mut decoder = decoder(params, 0).unwrap();
+    let mut dst = Region::default();
+
+    for n in 0..5 {
+        let src = frame(PixelFormat::Yuv420, SIZE, n * 8);
+        let datagrams = encode(&mut *encoder, &src, false);
+        assert_eq!(is_keyframe(&datagrams[1][2..]), n == 0);
+
+        assert!(decode(&mut *decoder, &datagrams, &[], &mut dst).unwrap());
+        assert_eq!((dst.w(), dst.h()), (SIZE.0 as i32, SIZE.1 as i32));
+        assert_eq!(dst.l(), PixelFormat::Yuv420.frame_len(SIZE));
+        assert!(distance(dst.data(), src.data()) < 4.0, "frame {n}");
+    }
+}
+
+#[test]
+fn h264_resyncs_on_keyframe() {
+    let params = params(Codec::H264);
+    let mut encoder = encoder(params, &SendOptions::default()).unwrap();
+    let mut decoder = decoder(params, 0).unwrap();
+    let mut dst = Region::default();
+
+    let first = encode(&mut *encoder, &frame(PixelFormat::Yuv420, SIZE, 0), false);
+    decode(&mut *decoder, &first, &[], &mut dst).unwrap();
+
+    // a lost chunk breaks the chain of predicted frames
+    let lost = encode(&mut *encoder, &frame(PixelFormat::Yuv420, SIZE, 8), false);
+    assert!(decode(&mut *decoder, &lost, &[0], &mut dst).is_err());
+    let next = encode(&mut *encoder, &frame(PixelFormat::Yuv420, SIZE, 16), false);
+    assert!(decode(&mut *decoder, &next, &[], &mut dst).is_err());
+
+    // until a requested keyframe arrives
+    let src = frame(PixelFormat::Yuv420, SIZE, 24);
+    let key = encode(&mut *encoder, &src, true);
+    assert!(is_keyframe(&key[1][2..]));
+    decode(&mut *decoder, &key, &[], &mut dst).unwrap();
+    assert!(distance(dst.data(), src.data()) < 4.0);
+}
+
+#[test]
+fn h264_duplicates_do_not_cover_losses() {
+    let hello = Hello::new()
+        .with_codecs(Codecs::of(&[Codec::H264]))
+        .with_max_datagram(200);
+    let params = hello.negotiate(&hello).unwrap();
+    let mut encoder = encoder(params, &SendOptions::default()).unwrap();
+    let mut decoder = decoder(params, 0).unwrap();
+    let mut dst = Region::default();
+
+    // noise, so that the keyframe spans several chunks
+    let mut src = frame(PixelFormat::Yuv420, SIZE, 0);
+    let mut x = 1u64;
+    for byte in src.data_mut() {
+        // xorshift64
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        *byte = x as u8;
+    }
+    let datagrams = encode(&mut *encoder, &src, true);
+    assert!(datagrams.len() > 3, "{} datagrams", datagrams.len());
+
+    // the last chunk arrives twice in place of the first
+    decoder.begin(header(&datagrams));
+    for chunk in &datagrams[2..] {
+        let (idx, payload) = unseal(chunk).unwrap();
+        assert!(decoder.chunk(idx, payload));
+    }
+    let (idx, payload) = unseal(datagrams.last().unwrap()).unwrap();
+    assert!(!decoder.chunk(idx, payload));
+    let e = decoder.finish(&mut dst).unwrap_err();
+    assert!(e.to_string().contains("incomplete"), "{e}");
+}
+
+#[test]
+fn h264_needs_even_dimensions() {
+    assert_eq!(Codec::H264.frame_size((1281, 721)), (1280, 720));
+    assert_eq!(Codec::H264.frame_size((1, 1)), (2, 2));
+    assert_eq!(Codec::Lz4.frame_size((1281, 721)), (1281, 721));
+
+    let mut encoder = encoder(params(Codec::H264), &SendOptions::default()).unwrap();
+    let odd = frame(PixelFormat::Yuv420, (63, 47), 0);
+    let opts = EncodeOptions::default();
+    assert!(encoder.encode(&odd, opts, &mut |_| Ok(())).is_err());
+}
+
+#[test]
+fn lz4_sends_parallel_chunks_in_order() {
+    let params = params(Codec::Lz4);
+    let mut decoder = decoder(params, 0).unwrap();
+    let src = frame(PixelFormat::Bgra8, (640, 480), 3);
+
+    for threads in [1, 4] {
+        let opts = SendOptions {
+            threads,
+            ..Default::default()
+        };
+        let mut encoder = encoder(params, &opts).unwrap();
+        let datagrams = encode(&mut *encoder, &src, false);
+
+        let indices: Vec<_> = datagrams[1..]
+            .iter()
+            .map(|d| u16::from_le_bytes([d[0], d[1]]))
+            .collect();
+        let expected = src.l().div_ceil(params.chunk_size()) as u16;
+        assert_eq!(indices, (0..expected).collect::<Vec<_>>());
+
+        let mut dst = Region::default();
+        assert!(decode(&mut *decoder, &datagrams, &[], &mut dst).unwrap());
+        assert_eq!(dst.data(), src.data());
+    }
+}
+
+#[test]
+fn lz4_decodes_on_workers() {
+    let params = params(Codec::Lz4);
+    let mut encoder = encoder(params, &SendOptions::default()).unwrap();
+    let mut decoder = decoder(params, 4).unwrap();
+    let mut dst = Region::default();
+
+    let first = encode(
+        &mut *encoder,
+        &frame(PixelFormat::Bgra8, (640, 480), 0),
+        false,
+    );
+    assert!(decode(&mut *decoder, &first, &[], &mut dst).unwrap());
+
+    let src = frame(PixelFormat::Bgra8, (640, 480), 9);
+    let opts = EncodeOptions {
+        acked: 1,
+        ..Default::default()
+    };
+    let delta = encode_with(&mut *encoder, &src, opts);
+    assert!(decode(&mut *decoder, &delta, &[], &mut dst).unwrap());
+    assert_eq!(dst.data(), src.data());
+}
+
+#[test]
+fn lz4_rejects_duplicate_and_corrupt_chunks() {
+    let params = params(Codec::Lz4);
+    let mut encoder = encoder(params, &SendOptions::default()).unwrap();
+    let mut decoder = decoder(params, 2).unwrap();
+    let mut dst = Region::default();
+
+    let datagrams = encode(
+        &mut *encoder,
+        &frame(PixelFormat::Bgra8, (256, 256), 0),
+        false,
+    );
+    decoder.begin(header(&datagrams));
+    assert!(decoder.chunk(0, &datagrams[1][2..]));
+    assert!(!decoder.chunk(0, &datagrams[1][2..]));
+    assert!(!decoder.chunk(u16::MAX, &datagrams[1][2..]));
+
+    // every chunk arrived, but one could not be decompressed
+    for chunk in &datagrams[2..] {
+        let idx = u16::from_le_bytes([chunk[0], chunk[1]]);
+        let payload = if idx == 1 {
+            &[0xFF; 8][..]
+        } else {
+            &chunk[2..]
+        };
+        assert!(decoder.chunk(idx, payload));
+    }
+    assert!(!decoder.finish(&mut dst).unwrap());
+}
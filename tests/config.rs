@@ -0,0 +1,134 @@
+use clap::Parser;
+use remdes::{config::parse_from, util::parse_millis};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+#[derive(Parser, Debug, PartialEq, Eq)]
+struct Settings {
+    #[arg(long)]
+    host: Option<String>,
+
+    #[arg(short, long, default_value_t = 120)]
+    fps: u8,
+
+    #[arg(long)]
+    quic: bool,
+
+    #[arg(long, default_value = "250", value_parser = parse_millis)]
+    heartbeat: Duration,
+}
+
+/// Write a config file unique to this test run.
+fn file(name: &str, text: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("remdes-{}-{name}.toml", std::process::id()));
+    std::fs::write(&path, text).unwrap();
+    path
+}
+
+fn parse(args: &[&str], path: &Path, profiles: bool) -> anyhow::Result<Settings> {
+    let args = ["remdes"].iter().chain(args);
+    parse_from(args, (Some(path.to_path_buf()), profiles))
+}
+
+#[test]
+fn command_line_overrides_the_file() {
+    let path = file("overrides", "fps = 60\nquic = true\nheartbeat = \"500\"\n");
+    let settings = parse(&["--fps", "90"], &path, false).unwrap();
+    assert_eq!(
+        settings,
+        Settings {
+            host: None,
+            fps: 90,
+            quic: true,
+            heartbeat: Duration::from_millis(500),
+        }
+    );
+}
+
+#[test]
+fn command_line_clears_flags_the_file_sets() {
+    let path = file("flags", "quic = true\n");
+    assert!(parse(&[], &path, false).unwrap().quic);
+    assert!(parse(&["--quic"], &path, false).unwrap().quic);
+    assert!(!parse(&["--quic=false"], &path, false).unwrap().quic);
+
+    let path = file("no-flags", "quic = false\n");
+    assert!(!parse(&[], &path, false).unwrap().quic);
+    assert!(parse(&["--quic"], &path, false).unwrap().quic);
+}
+
+#[test]
+fn missing_files_are_fine_unless_named() {
+    let path = std::env::temp_dir().join("remdes-no-such-file.toml");
+    assert_eq!(parse(&[], &path, false).unwrap().fps, 120);
+
+    let named = parse(&["--config", path.to_str().unwrap()], &path, false);
+    assert!(named.unwrap_err().to_string().contains("Cannot read"));
+}
+
+#[test]
+fn profiles_apply_by_name_or_host() {
+    let path = file(
+        "profiles",
+        "fps = 60\n\
+         [profiles.desk]\nhost = \"10.0.0.2\"\nfps = 30\n\
+         [profiles.\"10.0.0.9\"]\nquic = true\n",
+    );
+
+    let desk = parse(&["--profile", "desk"], &path, true).unwrap();
+    assert_eq!((desk.host.as_deref(), desk.fps), (Some("10.0.0.2"), 30));
+
+    let by_host = parse(&["--host", "10.0.0.9"], &path, true).unwrap();
+    assert_eq!((by_host.fps, by_host.quic), (60, true));
+
+    let plain = parse(&["--host", "10.0.0.5"], &path, true).unwrap();
+    assert_eq!((plain.fps, plain.quic), (60, false));
+
+    let missing = parse(&["--profile", "laptop"], &path, true).unwrap_err();
+    assert!(
+        missing.to_string().contains("No profile `laptop`"),
+        "{missing}"
+    );
+
+    // only binaries taking --profile have profiles
+    let unknown = parse(&[], &path, false).unwrap_err();
+    assert!(
+        unknown.to_string().contains("`profiles`: unknown setting"),
+        "{unknown}"
+    );
+}
+
+#[test]
+fn errors_name_the_file_and_key() {
+    for (name, text, key, why) in [
+        ("range", "fps = 300", "`fps`", "invalid value '300'"),
+        ("unknown", "colour = \"red\"", "`colour`", "unknown setting"),
+        ("flag", "quic = \"yes\"", "`quic`", "expected true or false"),
+        (
+            "array",
+            "fps = [30, 60]",
+            "`fps`",
+            "expected a single value",
+        ),
+        (
+            "profile",
+            "[profiles.desk]\nheartbeat = 0",
+            "`profiles.desk.heartbeat`",
+            "greater than zero",
+        ),
+    ] {
+        let path = file(name, text);
+        let e = parse(&[], &path, true).unwrap_err().to_string();
+        let file = path.display().to_string();
+        assert!(
+            e.starts_with(&file) && e.contains(key) && e.contains(why),
+            "{e}"
+        );
+    }
+
+    let path = file("syntax", "fps = ");
+    let e = parse(&[], &path, false).unwrap_err().to_string();
+    assert!(e.starts_with(&path.display().to_string()), "{e}");
+}
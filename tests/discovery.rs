@@ -0,0 +1,129 @@
+use remdes::discovery::*;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    thread::spawn,
+    time::Duration,
+};
+
+/// Answer probes on an ephemeral port in the background, as `announcement`.
+fn beacon(announcement: Announcement) -> SocketAddr {
+    let beacon = Beacon::new(0).unwrap();
+    let port = beacon.local_addr().unwrap().port();
+    spawn(move || beacon.run(|| announcement.clone()));
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+}
+
+fn announcement(window: &str, ports: Ports) -> Announcement {
+    Announcement {
+        hostname: "desk".to_owned(),
+        window: window.to_owned(),
+        ports,
+    }
+}
+
+#[test]
+fn announcements_round_trip() {
+    let all = [
+        announcement(
+            "Game",
+            Ports::Split {
+                tcp: 54277,
+                udp: 54287,
+            },
+        ),
+        announcement("", Ports::Single(9000)),
+        announcement(
+            "Spreadsheet — ünïcode",
+            Ports::Quic {
+                port: 9001,
+                fingerprint: "ab".repeat(32),
+            },
+        ),
+    ];
+    for announcement in all {
+        assert_eq!(
+            Announcement::decode(&announcement.encode()).unwrap(),
+            announcement
+        );
+    }
+}
+
+#[test]
+fn long_titles_are_cut_at_a_character() {
+    let long = announcement(&"é".repeat(200), Ports::Single(9000));
+    let decoded = Announcement::decode(&long.encode()).unwrap();
+    assert_eq!(decoded.window, "é".repeat(127));
+}
+
+#[test]
+fn malformed_announcements_are_rejected() {
+    let datagram = announcement("Game", Ports::Single(9000)).encode();
+    assert!(Announcement::decode(&datagram[..datagram.len() - 1]).is_err());
+    assert!(Announcement::decode(&datagram[1..]).is_err());
+    assert!(Announcement::decode(b"remdes?").is_err());
+}
+
+#[test]
+fn servers_answer_probes() {
+    let first = announcement(
+        "Game",
+        Ports::Split {
+            tcp: 54277,
+            udp: 54287,
+        },
+    );
+    let second = announcement("Editor", Ports::Single(9000));
+    let targets = [beacon(first.clone()), beacon(second.clone())];
+
+    // each listed once, however many probes it answered
+    let servers = discover(&targets, Duration::from_millis(600)).unwrap();
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    assert_eq!(servers.len(), 2);
+    assert!(servers.contains(&(localhost, first)));
+    assert!(servers.contains(&(localhost, second)));
+}
+
+#[test]
+fn nothing_answers_elsewhere() {
+    let target = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9);
+    assert!(
+        discover(&[target], Duration::from_millis(300))
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn only_the_lan_is_answered() {
+    for ip in [
+        "10.1.2.3",
+        "172.16.0.9",
+        "192.168.1.20",
+        "169.254.7.7",
+        "127.0.0.1",
+    ] {
+        assert!(on_lan(ip.parse().unwrap()), "{ip}");
+    }
+    for ip in ["8.8.8.8", "172.32.0.1", "100.64.0.1", "0.0.0.0"] {
+        assert!(!on_lan(ip.parse().unwrap()), "{ip}");
+    }
+    assert!(!on_lan(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+}
+
+#[test]
+fn answers_to_a_host_are_spaced_out() {
+    let target = beacon(announcement("Game", Ports::Single(9000)));
+    let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    udp.set_read_timeout(Some(ANSWER_INTERVAL / 2)).unwrap();
+
+    let answers = |probes: usize| {
+        for _ in 0..probes {
+            udp.send_to(b"remdes?", target).unwrap();
+        }
+        let mut buf = [0; 1024];
+        std::iter::from_fn(|| udp.recv_from(&mut buf).ok()).count()
+    };
+    assert_eq!(answers(5), 1);
+    std::thread::sleep(ANSWER_INTERVAL);
+    assert_eq!(answers(1), 1);
+}
@@ -0,0 +1,251 @@
+use remdes::{caps::*, pace::*, session::*, *};
+use std::{
+    net::UdpSocket,
+    sync::Arc,
+    thread::{JoinHandle, spawn},
+    time::{Duration, Instant},
+};
+
+/// Side of the square test frames, large enough to span many chunks.
+const SIDE: i32 = 512;
+
+fn params() -> SessionParams {
+    Hello::new().negotiate(&Hello::new()).unwrap()
+}
+
+/// Publish an incompressible BGRA frame.
+fn publish_noise(slot: &FrameSlot, seed: u64) {
+    let mut x = seed | 1;
+    slot.publish(|region| {
+        let l = (SIDE * SIDE * 4) as usize;
+        region.set_w(SIDE);
+        region.set_h(SIDE);
+        region.set_l(l);
+        *region.data_mut() = (0..l)
+            .map(|_| {
+                // xorshift64
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+    });
+}
+
+/// Publish an all-black BGRA frame, which compresses to almost nothing.
+fn publish_blank(slot: &FrameSlot) {
+    slot.publish(|region| {
+        let l = (SIDE * SIDE * 4) as usize;
+        region.set_w(SIDE);
+        region.set_h(SIDE);
+        region.set_l(l);
+        *region.data_mut() = vec![0; l];
+    });
+}
+
+/// Stream to a fresh loopback client until the session ends.
+fn serve(
+    slot: Arc<FrameSlot>,
+    session: Arc<Session>,
+    max_bitrate: Option<u64>,
+) -> (UdpSocket, JoinHandle<Result<()>>) {
+    let opts = SendOptions {
+        max_bitrate,
+        ..Default::default()
+    };
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = client.local_addr().unwrap();
+    client.connect(udp.local_addr().unwrap()).unwrap();
+
+    let server =
+        spawn(move || stream_frames(&udp, addr, (params(), opts), (&slot, &session), |_, _| ()));
+    (client, server)
+}
+
+/// Arrival time and size of the header and chunks of the next frame. Lost
+/// chunks end the frame after a short timeout.
+fn receive_frame(client: &UdpSocket) -> Vec<(Instant, usize)> {
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut buf = vec![0; params().max_datagram()];
+
+    let header: RegionHeader = loop {
+        let n = client.recv(&mut buf).unwrap();
+        if n == size_of::<RegionHeader>() {
+            break *bytemuck::from_bytes(&buf[..n]);
+        }
+    };
+    let mut arrivals = vec![(Instant::now(), size_of::<RegionHeader>())];
+
+    client
+        .set_read_timeout(Some(Duration::from_millis(250)))
+        .unwrap();
+    for _ in 0..header.l().div_ceil(params().chunk_size()) {
+        let std::result::Result::Ok(n) = client.recv(&mut buf) else {
+            break;
+        };
+        arrivals.push((Instant::now(), n));
+    }
+    arrivals
+}
+
+/// Most bytes that arrived within any `window`.
+fn peak_bytes(arrivals: &[(Instant, usize)], window: Duration) -> usize {
+    (0..arrivals.len())
+        .map(|i| {
+            arrivals[i..]
+                .iter()
+                .take_while(|(t, _)| *t - arrivals[i].0 < window)
+                .map(|(_, n)| n)
+                .sum()
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+fn span(arrivals: &[(Instant, usize)]) -> Duration {
+    arrivals.last().unwrap().0 - arrivals[0].0
+}
+
+#[test]
+fn pacer_limits_rate() {
+    let mut pacer = Pacer::new(4_000);
+    pacer.set_rate(1_000_000.0);
+
+    // 100 KB at 1 MB/s, less the initial burst
+    let t = Instant::now();
+    for _ in 0..100 {
+        pacer.wait(1_000);
+    }
+    let elapsed = t.elapsed();
+    assert!(elapsed >= Duration::from_millis(90), "{elapsed:?}");
+    assert!(elapsed < Duration::from_millis(200), "{elapsed:?}");
+}
+
+#[test]
+fn pacer_polices_beyond_burst() {
+    let mut pacer = Pacer::new(4_000);
+    pacer.set_rate(1_000.0);
+
+    // the burst passes, then nothing more until it refills
+    assert!((0..4).all(|_| pacer.admit(1_000)));
+    assert!(!pacer.admit(1_000));
+    // refused datagrams are not charged
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(pacer.admit(100));
+
+    let mut unpaced = Pacer::new(0);
+    assert!((0..100).all(|_| unpaced.admit(1 << 16)));
+}
+
+#[test]
+fn unpaced_pacer_never_blocks() {
+    let mut pacer = Pacer::new(0);
+    pacer.set_frame_rate(1 << 20, None, None);
+    assert_eq!(pacer.rate(), 0.0);
+
+    let t = Instant::now();
+    for _ in 0..1000 {
+        pacer.wait(1 << 16);
+    }
+    assert!(t.elapsed() < Duration::from_millis(10));
+}
+
+#[test]
+fn frame_rate_is_capped_by_max_bitrate() {
+    let mut pacer = Pacer::new(0);
+    let interval = Some(Duration::from_millis(100));
+
+    pacer.set_frame_rate(800_000, interval, None);
+    assert_eq!(pacer.rate(), 800_000.0 / (0.1 * SPREAD));
+
+    pacer.set_frame_rate(800_000, interval, Some(1_000_000.0));
+    assert_eq!(pacer.rate(), 1_000_000.0);
+
+    pacer.set_frame_rate(800_000, None, Some(1_000_000.0));
+    assert_eq!(pacer.rate(), 1_000_000.0);
+
+    // small frames are not dribbled out, but the cap still wins
+    pacer.set_frame_rate(100, interval, None);
+    assert_eq!(pacer.rate(), MIN_RATE);
+    pacer.set_frame_rate(100, interval, Some(MIN_RATE / 2.0));
+    assert_eq!(pacer.rate(), MIN_RATE / 2.0);
+}
+
+#[test]
+fn max_bitrate_spreads_frame_on_loopback() {
+    let (slot, session): (Arc<FrameSlot>, Arc<Session>) = Default::default();
+    session.begin();
+    publish_noise(&slot, 1);
+
+    // 80 Mbit/s = 10 MB/s, so the ~1 MB frame takes ~100ms
+    let (client, server) = serve(slot, session.clone(), Some(80_000_000));
+    client.send(&[0]).unwrap();
+    let arrivals = receive_frame(&client);
+
+    session.end();
+    server.join().unwrap().unwrap();
+
+    let total: usize = arrivals.iter().map(|(_, n)| n).sum();
+    assert!(total >= (SIDE * SIDE * 4) as usize);
+    assert!(
+        span(&arrivals) >= Duration::from_millis(70),
+        "{:?}",
+        span(&arrivals)
+    );
+
+    // no 10ms window carries much more than the burst plus 10ms worth
+    let limit = params().max_datagram() * BURST_DATAGRAMS + 100_000;
+    let peak = peak_bytes(&arrivals, Duration::from_millis(10));
+    assert!(peak <= limit * 3 / 2, "{peak} bytes in 10ms");
+}
+
+#[test]
+fn fps_target_spreads_frame_on_loopback() {
+    let (slot, session): (Arc<FrameSlot>, Arc<Session>) = Default::default();
+    session.begin();
+    session.set_fps(10);
+
+    let (client, server) = serve(slot.clone(), session.clone(), None);
+    client.send(&[0]).unwrap();
+
+    publish_noise(&slot, 1);
+    receive_frame(&client);
+    publish_noise(&slot, 2);
+    let arrivals = receive_frame(&client);
+
+    session.end();
+    server.join().unwrap().unwrap();
+
+    // spread over ~80% of the 100ms interval rather than bursted
+    let span = span(&arrivals);
+    assert!(span >= Duration::from_millis(50), "{span:?}");
+    assert!(span < Duration::from_millis(150), "{span:?}");
+}
+
+#[test]
+fn large_frame_after_small_one_keeps_up() {
+    let (slot, session): (Arc<FrameSlot>, Arc<Session>) = Default::default();
+    session.begin();
+    session.set_fps(60);
+
+    let (client, server) = serve(slot.clone(), session.clone(), None);
+    client.send(&[0]).unwrap();
+
+    // the small frame must not slow the ~1 MB one that follows
+    publish_blank(&slot);
+    receive_frame(&client);
+    publish_noise(&slot, 1);
+    let arrivals = receive_frame(&client);
+
+    session.end();
+    server.join().unwrap().unwrap();
+
+    let total: usize = arrivals.iter().map(|(_, n)| n).sum();
+    assert!(total >= (SIDE * SIDE * 4) as usize);
+    let span = span(&arrivals);
+    assert!(span < Duration::from_millis(40), "{span:?}");
+}
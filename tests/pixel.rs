@@ -0,0 +1,93 @@
+use remdes::{caps::*, pixel::convert};
+
+/// A `w`x`h` BGRA frame of a single colour.
+fn solid([b, g, r]: [u8; 3], (w, h): (usize, usize)) -> Vec<u8> {
+    [b, g, r, 255].repeat(w * h)
+}
+
+fn converted(format: PixelFormat, src: &[u8], size: (usize, usize)) -> Vec<u8> {
+    let mut dst = Vec::new();
+    convert(format, src, size, &mut dst);
+    assert_eq!(dst.len(), format.frame_len(size), "{format}");
+    dst
+}
+
+#[test]
+fn every_format_matches_its_frame_len() {
+    let src = solid([1, 2, 3], (7, 5));
+    for &format in PixelFormat::ALL {
+        converted(format, &src, (7, 5));
+    }
+    assert_eq!(PixelFormat::Yuv420.frame_len((7, 5)), 35 + 2 * 4 * 3);
+}
+
+#[test]
+fn packed_formats_keep_channels() {
+    let src = solid([0x12, 0x34, 0x56], (2, 2));
+    assert_eq!(
+        converted(PixelFormat::Bgrx8, &src, (2, 2)),
+        [0x12, 0x34, 0x56].repeat(4)
+    );
+
+    // red in the high bits, blue in the low bits
+    let white = solid([255, 255, 255], (1, 1));
+    assert_eq!(converted(PixelFormat::Rgb565, &white, (1, 1)), [0xff, 0xff]);
+    let red = solid([0, 0, 255], (1, 1));
+    assert_eq!(
+        converted(PixelFormat::Rgb565, &red, (1, 1)),
+        0xf800u16.to_le_bytes()
+    );
+}
+
+#[test]
+fn luma_spans_full_range() {
+    let size = (3, 3);
+    assert_eq!(
+        converted(PixelFormat::Gray8, &solid([0; 3], size), size),
+        [0; 9]
+    );
+    assert_eq!(
+        converted(PixelFormat::Gray8, &solid([255; 3], size), size),
+        [255; 9]
+    );
+}
+
+#[test]
+fn yuv_uses_limited_range_planes() {
+    let size = (4, 2);
+    let white = converted(PixelFormat::Yuv420, &solid([255; 3], size), size);
+    assert_eq!(white, [[235; 8].as_slice(), &[128; 2], &[128; 2]].concat());
+
+    let black = converted(PixelFormat::Yuv420, &solid([0; 3], size), size);
+    assert_eq!(black, [[16; 8].as_slice(), &[128; 2], &[128; 2]].concat());
+
+    // blue pushes U up and V down
+    let blue = converted(PixelFormat::Yuv420, &solid([255, 0, 0], size), size);
+    let (u, v) = (blue[8], blue[10]);
+    assert!(u > 200 && v < 128, "u {u}, v {v}");
+}
+
+#[test]
+fn pixel_formats_parse_by_name() {
+    assert_eq!(
+        "yuv420".parse::<PixelFormat>().unwrap(),
+        PixelFormat::Yuv420
+    );
+    assert_eq!(
+        "RGB565".parse::<PixelFormat>().unwrap(),
+        PixelFormat::Rgb565
+    );
+    assert_eq!(PixelFormat::Gray8.to_string(), "gray8");
+    assert!("rgba".parse::<PixelFormat>().is_err());
+}
+
+#[test]
+fn restricted_hellos_settle_on_a_shared_format() {
+    let server = Hello::new();
+    let client = Hello::new().with_pixel_formats(PixelFormats::of(&[PixelFormat::Gray8]));
+    let params = server.negotiate(&client).unwrap();
+    assert_eq!(params.pixel_format().unwrap(), PixelFormat::Gray8);
+
+    let server = server.with_pixel_formats(PixelFormats::of(&[PixelFormat::Yuv420]));
+    assert!(server.negotiate(&client).is_err());
+}
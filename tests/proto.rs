@@ -0,0 +1,103 @@
+use remdes::{
+    UDP_CHUNK_SIZE,
+    caps::*,
+    proto::{GoodbyeReason, MAX_MESSAGE_LEN, Message, Stats},
+    scale::Scale,
+};
+use std::io::Cursor;
+
+/// One of every kind of message.
+fn all() -> Vec<Message> {
+    let hello = Hello::new().with_max_datagram(1200);
+    vec![
+        Message::Hello(hello),
+        Message::Session(hello.negotiate(&Hello::new()).unwrap()),
+        Message::Ping(u64::MAX - 1),
+        Message::Pong(7),
+        Message::SetFps(60),
+        Message::Scale(Scale::Fit(1280, 720)),
+        Message::RequestKeyframe,
+        Message::Ack(0x0102_0304),
+        Message::Resize { w: 1920, h: 1080 },
+        Message::Stats(Stats {
+            frames: 1,
+            bytes: 2,
+            chunks: 3,
+            lost: 4,
+            jitter: 5,
+            overflow: 6,
+        }),
+        Message::Goodbye(GoodbyeReason::Incompatible),
+    ]
+}
+
+fn encode(msg: &Message) -> Vec<u8> {
+    let mut buf = Vec::new();
+    msg.encode(&mut buf);
+    buf
+}
+
+#[test]
+fn messages_round_trip() {
+    let mut stream = Vec::new();
+    for msg in all() {
+        let buf = encode(&msg);
+        let len = u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize;
+        assert_eq!(len, buf.len() - 4, "{msg:?}");
+        assert_eq!(Message::decode(&buf[4..]).unwrap(), msg);
+        msg.write_to(&mut stream).unwrap();
+    }
+
+    let mut r = Cursor::new(stream);
+    for msg in all() {
+        assert_eq!(Message::read_from(&mut r).unwrap(), msg);
+    }
+    assert!(Message::read_from(&mut r).is_err());
+}
+
+#[test]
+fn fields_are_little_endian() {
+    let stats = encode(&all()[9]);
+    assert_eq!(stats[5..9], [1, 0, 0, 0]);
+    assert_eq!(stats[25..29], [6, 0, 0, 0]);
+
+    let hello = encode(&Message::Hello(Hello::new()));
+    assert_eq!(hello[5..7], Hello::VERSION.to_le_bytes());
+    assert_eq!(hello[13..17], (UDP_CHUNK_SIZE as u32).to_le_bytes());
+}
+
+#[test]
+fn oversize_length_is_rejected() {
+    let mut buf = ((MAX_MESSAGE_LEN + 1) as u32).to_le_bytes().to_vec();
+    buf.resize(4 + MAX_MESSAGE_LEN + 1, 0);
+    let e = Message::read_from(&mut Cursor::new(buf)).unwrap_err();
+    assert!(e.to_string().contains("too large"), "{e}");
+}
+
+#[test]
+fn unknown_tags_and_empty_messages_are_rejected() {
+    let e = Message::decode(&[0xee, 1, 2]).unwrap_err();
+    assert!(e.to_string().contains("Unknown control message tag"), "{e}");
+    assert!(Message::decode(&[]).is_err());
+    assert!(Message::decode(&[6, 9]).is_err()); // unknown goodbye reason
+}
+
+#[test]
+fn truncated_payloads_are_rejected() {
+    for msg in all() {
+        let buf = encode(&msg);
+        if buf.len() > 5 && !matches!(msg, Message::Hello(_)) {
+            let e = Message::decode(&buf[4..buf.len() - 1]).unwrap_err();
+            assert!(e.to_string().contains("Malformed"), "{msg:?}: {e}");
+        }
+
+        // cut short on the stream, too
+        let mut r = Cursor::new(&buf[..buf.len() - 1]);
+        assert!(Message::read_from(&mut r).is_err(), "{msg:?}");
+    }
+
+    // a hello only needs its version to report a mismatch
+    let hello = Message::decode(&[0, 3, 0]).unwrap();
+    assert!(matches!(hello, Message::Hello(h) if h.version() == 3));
+    assert!(Message::decode(&[0, 3]).is_err());
+}
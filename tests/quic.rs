@@ -0,0 +1,133 @@
+use remdes::{caps::*, proto::*, quic::*, sock::*, *};
+use std::{
+    net::{SocketAddr, UdpSocket},
+    thread::{JoinHandle, spawn},
+    time::Duration,
+};
+
+/// Upper bound for any exchange in these tests.
+const DEADLINE: Duration = Duration::from_secs(5);
+
+fn bind() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").unwrap()
+}
+
+/// Accept a single connection on loopback in the background, returning the
+/// server's address and certificate fingerprint.
+fn listen() -> (SocketAddr, String, JoinHandle<QuicStream>) {
+    let listener = QuicListener::new(bind()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let fingerprint = listener.fingerprint().to_owned();
+    (addr, fingerprint, spawn(move || listener.accept().unwrap()))
+}
+
+#[test]
+fn messages_arrive_in_order() {
+    let (addr, fingerprint, server) = listen();
+    let client =
+        ControlChannel::new(QuicStream::connect(bind(), addr, Some(&fingerprint)).unwrap());
+    for fps in 0..20 {
+        client.send(Message::SetFps(fps)).unwrap();
+    }
+
+    let server = ControlChannel::new(server.join().unwrap());
+    server.set_read_timeout(Some(DEADLINE)).unwrap();
+    for fps in 0..20 {
+        assert_eq!(server.recv().unwrap(), Message::SetFps(fps));
+    }
+
+    // and back the other way
+    server.send(Message::Ack(7)).unwrap();
+    client.set_read_timeout(Some(DEADLINE)).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::Ack(7));
+}
+
+#[test]
+fn datagrams_carry_media() {
+    let (addr, _, server) = listen();
+    let client = QuicStream::connect(bind(), addr, None).unwrap();
+    client.send(&Message::SetFps(60)).unwrap();
+    let server = server.join().unwrap();
+
+    let mut sink = server.datagrams();
+    let mut source = client.datagrams();
+    source.set_read_timeout(Some(DEADLINE));
+    assert!(sink.max_datagram() >= 1000);
+
+    let sent: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 1000]).collect();
+    for datagram in &sent {
+        sink.push(datagram).unwrap();
+    }
+    sink.flush().unwrap();
+
+    let mut received = Vec::new();
+    while received.len() < sent.len() {
+        source.recv().unwrap();
+        received.extend(source.datagrams().map(<[u8]>::to_vec));
+    }
+    assert_eq!(received, sent);
+}
+
+#[test]
+fn chunks_fit_datagrams() {
+    for len in [1200, 1452, 9000] {
+        let params = Hello::new()
+            .with_max_datagram(len)
+            .negotiate(&Hello::new())
+            .unwrap();
+        assert!(params.max_datagram() <= len);
+        assert!(params.max_datagram() + 8 > len);
+    }
+
+    // never beyond what raw UDP uses
+    let hello = Hello::new().with_max_datagram(1 << 20);
+    assert_eq!(hello.max_chunk(), UDP_CHUNK_SIZE);
+}
+
+#[test]
+fn oversized_datagrams_are_refused() {
+    let (addr, _, server) = listen();
+    let client = QuicStream::connect(bind(), addr, None).unwrap();
+    client.send(&Message::SetFps(60)).unwrap();
+    let server = server.join().unwrap();
+
+    let mut sink = server.datagrams();
+    let len = sink.max_datagram() + 1;
+    assert!(sink.push(&vec![0; len]).is_err());
+}
+
+#[test]
+fn wrong_fingerprint_is_rejected() {
+    let (addr, fingerprint, _server) = listen();
+    let wrong = fingerprint.replace(|c| c != '0', "0");
+    assert!(QuicStream::connect(bind(), addr, Some(&wrong)).is_err());
+}
+
+#[test]
+fn shutdown_unblocks_receiver() {
+    let (addr, _, server) = listen();
+    let client = ControlChannel::new(QuicStream::connect(bind(), addr, None).unwrap());
+    client.send(Message::SetFps(60)).unwrap();
+    let server = ControlChannel::new(server.join().unwrap());
+    assert_eq!(server.recv().unwrap(), Message::SetFps(60));
+
+    let reader = {
+        let client = client.clone();
+        spawn(move || client.recv())
+    };
+    client.shutdown();
+    assert!(reader.join().unwrap().is_err());
+}
+
+#[test]
+fn read_timeout_expires() {
+    let (addr, _, server) = listen();
+    let client = ControlChannel::new(QuicStream::connect(bind(), addr, None).unwrap());
+    client.send(Message::SetFps(60)).unwrap();
+    let _server = server.join().unwrap();
+
+    client
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    assert!(client.recv().is_err());
+}
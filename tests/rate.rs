@@ -0,0 +1,77 @@
+use remdes::{proto::Stats, rate::*};
+use std::time::Duration;
+
+const RTT: Option<Duration> = Some(Duration::from_millis(5));
+
+fn report(chunks: u32, lost: u32) -> Stats {
+    Stats {
+        frames: 60,
+        bytes: 1 << 20,
+        chunks,
+        lost,
+        jitter: 1_000,
+        overflow: 0,
+    }
+}
+
+#[test]
+fn heavy_loss_backs_off() {
+    let mut rate = RateController::default();
+    let quality = rate.on_stats(&report(1000, 200), RTT).unwrap();
+    assert_eq!(quality, LADDER[2]);
+
+    // keeps backing off, but never past the cheapest quality
+    for _ in 0..LADDER.len() {
+        rate.on_stats(&report(1000, 500), RTT);
+    }
+    assert_eq!(rate.quality(), *LADDER.last().unwrap());
+}
+
+#[test]
+fn clean_reports_probe_up() {
+    let mut rate = RateController::default();
+    rate.on_stats(&report(1000, 200), RTT);
+
+    for _ in 1..PROBE_REPORTS {
+        assert_eq!(rate.on_stats(&report(1000, 0), RTT), None);
+    }
+    assert_eq!(rate.on_stats(&report(1000, 0), RTT), Some(LADDER[1]));
+}
+
+#[test]
+fn moderate_loss_or_jitter_holds() {
+    let mut rate = RateController::default();
+    rate.on_stats(&report(1000, 200), RTT);
+
+    // alternating clean and lossy reports never accumulate enough to probe
+    for _ in 0..PROBE_REPORTS * 2 {
+        assert_eq!(rate.on_stats(&report(1000, 0), RTT), None);
+        assert_eq!(rate.on_stats(&report(1000, 50), RTT), None);
+    }
+
+    let jittery = Stats {
+        jitter: JITTER_HIGH.as_micros() as u32 * 2,
+        ..report(1000, 0)
+    };
+    for _ in 0..PROBE_REPORTS * 2 {
+        assert_eq!(rate.on_stats(&jittery, RTT), None);
+    }
+    assert_eq!(rate.quality(), LADDER[2]);
+}
+
+#[test]
+fn growing_rtt_backs_off() {
+    let mut rate = RateController::default();
+    assert_eq!(rate.on_stats(&report(1000, 0), RTT), None);
+
+    // queueing delay without any loss yet
+    let bloated = Some(Duration::from_millis(80));
+    assert_eq!(rate.on_stats(&report(1000, 0), bloated), Some(LADDER[2]));
+}
+
+#[test]
+fn loss_is_a_fraction_of_announced_chunks() {
+    assert_eq!(report(0, 0).loss(), 0.0);
+    assert_eq!(report(200, 50).loss(), 0.25);
+    assert_eq!(report(10, 20).loss(), 1.0);
+}
@@ -0,0 +1,225 @@
+use remdes::{arq::*, proto::*, relay::*, rendezvous::*, *};
+use std::{
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, mpsc},
+    thread::spawn,
+    time::{Duration, Instant, SystemTime},
+};
+
+const SECRET: &str = "correct horse battery staple";
+
+/// Upper bound for any exchange in these tests.
+const DEADLINE: Duration = Duration::from_secs(5);
+
+fn bind() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").unwrap()
+}
+
+/// Relay on loopback in the background, capped at `max_bitrate`.
+fn relay(max_bitrate: Option<u64>) -> SocketAddr {
+    let relay = Relay::new(bind(), Secret::new(SECRET), max_bitrate);
+    let addr = relay.local_addr().unwrap();
+    spawn(move || relay.run(|_, _, _| {}));
+    addr
+}
+
+/// A server registered with `relay` in the background, along with the
+/// socket it listens on.
+fn serve(relay: SocketAddr) -> (Arc<ArqListener>, UdpSocket) {
+    let udp = bind();
+    let listener = Arc::new(ArqListener::new(&udp).unwrap());
+    let registrars = [Registrar::Relay(relay, Secret::new(SECRET))];
+    let advertised = listener.clone();
+    spawn(move || advertise(&advertised, (&registrars, "game")));
+    (listener, udp)
+}
+
+#[test]
+fn allocations_round_trip() {
+    let secret = Secret::new(SECRET);
+    let now = SystemTime::now();
+    for role in [Role::Server, Role::Client] {
+        let allocation = Allocation::new(role, "game").with_cookie([7; COOKIE_LEN]);
+        let datagram = allocation.encode(&secret, now);
+        assert_eq!(
+            Allocation::decode(&datagram, &secret, now).unwrap(),
+            allocation
+        );
+    }
+}
+
+#[test]
+fn forged_allocations_are_rejected() {
+    let secret = Secret::new(SECRET);
+    let now = SystemTime::now();
+    let datagram = Allocation::new(Role::Client, "game").encode(&secret, now);
+
+    // another secret
+    assert!(Allocation::decode(&datagram, &Secret::new("guess"), now).is_err());
+
+    // another cookie or session under the same tag
+    for at in [10, 10 + COOKIE_LEN] {
+        let mut tampered = datagram.clone();
+        tampered[at] ^= 1;
+        assert!(Allocation::decode(&tampered, &secret, now).is_err());
+    }
+
+    // replayed long after
+    let later = now + ALLOCATION_WINDOW + Duration::from_secs(1);
+    assert!(Allocation::decode(&datagram, &secret, later).is_err());
+
+    assert!(Allocation::decode(&datagram[..20], &secret, now).is_err());
+}
+
+#[test]
+fn allocations_only_count_from_where_they_were_issued() {
+    let (tx, rx) = mpsc::channel();
+    let relay = Relay::new(bind(), Secret::new(SECRET), None);
+    let addr = relay.local_addr().unwrap();
+    spawn(move || relay.run(move |role, _, from| _ = tx.send((role, from))));
+
+    // a server's allocation, captured and replayed from elsewhere
+    let (server, thief) = (bind(), bind());
+    let captured = allocation(&server, addr, Role::Server);
+    thief.send_to(&captured, addr).unwrap();
+    assert!(Challenge::decode(&recv(&thief)).is_some());
+
+    server.send_to(&captured, addr).unwrap();
+    assert!(matches!(
+        Signal::decode(&recv(&server)),
+        std::result::Result::Ok(Signal::Peers(_))
+    ));
+    let registered = rx.recv_timeout(DEADLINE).unwrap();
+    assert_eq!(registered, (Role::Server, server.local_addr().unwrap()));
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn control_and_media_are_relayed() {
+    let relay = relay(None);
+    let (listener, udp) = serve(relay);
+
+    let (control, media) = (bind(), bind());
+    let registrar = Registrar::Relay(relay, Secret::new(SECRET));
+    let server = punch_through(&[&control, &media], (&registrar, "game")).unwrap();
+    assert_eq!(server, relay);
+
+    // control, both ways
+    let client = ControlChannel::new(ArqStream::connect_from(control, server).unwrap());
+    client.send(Message::SetFps(60)).unwrap();
+    let stream = ControlChannel::new(listener.accept().unwrap());
+    stream.set_read_timeout(Some(DEADLINE)).unwrap();
+    assert_eq!(stream.recv().unwrap(), Message::SetFps(60));
+    stream.send(Message::Ack(1)).unwrap();
+    client.set_read_timeout(Some(DEADLINE)).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::Ack(1));
+
+    // media arrives from the relay, on the socket that asked for it
+    let peer = stream.peer_addr().unwrap();
+    media.send_to(&MEDIA_HELLO, server).unwrap();
+    let lane = listener.accept_media(peer.ip(), DEADLINE).unwrap();
+    assert_ne!(lane, peer);
+
+    // only the registered server may use the lane
+    bind().send_to(b"stray", lane).unwrap();
+    udp.send_to(b"frame", lane).unwrap();
+
+    media.set_read_timeout(Some(DEADLINE)).unwrap();
+    let mut buf = [0; 64];
+    loop {
+        let (n, from) = media.recv_from(&mut buf).unwrap();
+        assert_eq!(from, relay);
+        // skip punches still in flight
+        if buf[..n] != PUNCH {
+            assert_eq!(&buf[..n], b"frame");
+            break;
+        }
+    }
+}
+
+#[test]
+fn wrong_secret_is_refused() {
+    let relay = relay(None);
+    let _server = serve(relay);
+
+    let registrar = Registrar::Relay(relay, Secret::new("guess"));
+    let err = punch_through(&[&bind()], (&registrar, "game")).unwrap_err();
+    assert!(err.to_string().contains("No server registered"));
+}
+
+#[test]
+fn sessions_are_capped() {
+    // 800 kbit/s, or 100 KB a second past the burst
+    let relay = relay(Some(800_000));
+    let (server, client) = (bind(), bind());
+
+    // registered by hand, so the server's socket is free to flood the lane
+    allocate(&server, relay, Role::Server);
+    allocate(&client, relay, Role::Client);
+    let lane = loop {
+        if let Some(lane) = allocate(&server, relay, Role::Server).first() {
+            break SocketAddr::new(relay.ip(), lane.port());
+        }
+    };
+
+    let reader = spawn(move || {
+        client
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        let mut buf = [0; 1500];
+        let mut received = 0;
+        while let std::result::Result::Ok((n, _)) = client.recv_from(&mut buf) {
+            received += n;
+        }
+        received
+    });
+
+    // a megabyte a second, for a second
+    let t = Instant::now();
+    for _ in 0..1000 {
+        server.send_to(&[7; 1000], lane).unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    let received = reader.join().unwrap();
+    let elapsed = t.elapsed().as_secs_f64();
+
+    let allowed = 4 * UDP_CHUNK_SIZE + (elapsed * 100_000.0) as usize;
+    assert!(received >= 100_000, "{received} bytes relayed");
+    assert!(
+        received <= allowed,
+        "{received} bytes relayed, {allowed} allowed"
+    );
+}
+
+/// Wait for the next datagram on `udp`.
+fn recv(udp: &UdpSocket) -> Vec<u8> {
+    udp.set_read_timeout(Some(DEADLINE)).unwrap();
+    let mut buf = [0; 256];
+    let (n, _) = udp.recv_from(&mut buf).unwrap();
+    buf[..n].to_vec()
+}
+
+/// An allocation of `udp` in `role`, with the cookie `relay` issues it.
+fn allocation(udp: &UdpSocket, relay: SocketAddr, role: Role) -> Vec<u8> {
+    let (secret, allocation) = (Secret::new(SECRET), Allocation::new(role, "game"));
+    udp.send_to(&allocation.encode(&secret, SystemTime::now()), relay)
+        .unwrap();
+    // skipping peers the relay reported meanwhile
+    let Challenge(cookie) = loop {
+        if let Some(challenge) = Challenge::decode(&recv(udp)) {
+            break challenge;
+        }
+    };
+    allocation
+        .with_cookie(cookie)
+        .encode(&secret, SystemTime::now())
+}
+
+/// Register `udp` with `relay` in `role`, returning the peers it is told of.
+fn allocate(udp: &UdpSocket, relay: SocketAddr, role: Role) -> Vec<SocketAddr> {
+    udp.send_to(&allocation(udp, relay, role), relay).unwrap();
+    match Signal::decode(&recv(udp)).unwrap() {
+        Signal::Peers(peers) => peers,
+        signal => panic!("unexpected {signal:?}"),
+    }
+}
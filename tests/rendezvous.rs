@@ -0,0 +1,83 @@
+use remdes::{arq::*, proto::*, rendezvous::*};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread::spawn,
+    time::Duration,
+};
+
+fn bind() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").unwrap()
+}
+
+/// Serve rendezvous on loopback in the background.
+fn rendezvous() -> Registrar {
+    let mut rendezvous = Rendezvous::new(bind());
+    let addr = rendezvous.local_addr().unwrap();
+    spawn(move || rendezvous.run(|_, _, _| {}));
+    Registrar::Rendezvous(addr)
+}
+
+#[test]
+fn signals_round_trip() {
+    let signals = [
+        Signal::Register(Role::Server, "game".to_owned()),
+        Signal::Register(Role::Client, "game".to_owned()),
+        Signal::Peers(vec![]),
+        Signal::Peers(vec![
+            SocketAddr::from((Ipv4Addr::new(203, 0, 113, 7), 40000)),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, 54287)),
+        ]),
+    ];
+    for signal in signals {
+        assert_eq!(Signal::decode(&signal.encode()).unwrap(), signal);
+    }
+}
+
+#[test]
+fn malformed_signals_are_rejected() {
+    assert!(Signal::decode(&[]).is_err());
+    assert!(Signal::decode(&PUNCH).is_err());
+    assert!(Signal::decode(b"RS").is_err()); // empty session
+    assert!(Signal::decode(b"RXgame").is_err()); // unknown role
+    assert!(Signal::decode(&[b'R', b'S', 0xff]).is_err()); // not UTF-8
+
+    let long = "x".repeat(MAX_SESSION_LEN + 1);
+    assert!(Signal::decode(&Signal::Register(Role::Client, long).encode()).is_err());
+
+    let peers = Signal::Peers(vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 1))]).encode();
+    assert!(Signal::decode(&peers[..peers.len() - 1]).is_err());
+}
+
+#[test]
+fn client_punches_through_to_server() {
+    let rendezvous = rendezvous();
+
+    let udp = bind();
+    let listener = Arc::new(ArqListener::new(&udp).unwrap());
+    {
+        let listener = listener.clone();
+        let registrars = [rendezvous.clone()];
+        spawn(move || advertise(&listener, (&registrars, "game")));
+    }
+
+    let (control, media) = (bind(), bind());
+    let server = punch_through(&[&control, &media], (&rendezvous, "game")).unwrap();
+    assert_eq!(server, udp.local_addr().unwrap());
+
+    // the punched socket then carries the control connection
+    let client = ControlChannel::new(ArqStream::connect_from(control, server).unwrap());
+    client.send(Message::SetFps(60)).unwrap();
+    let stream = ControlChannel::new(listener.accept().unwrap());
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(stream.recv().unwrap(), Message::SetFps(60));
+}
+
+#[test]
+fn unknown_session_times_out() {
+    let rendezvous = rendezvous();
+    let err = punch_through(&[&bind()], (&rendezvous, "nobody")).unwrap_err();
+    assert!(err.to_string().contains("No server registered"));
+}
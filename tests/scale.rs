@@ -0,0 +1,115 @@
+use remdes::{proto::Message, scale::*, *};
+
+/// A BGRA frame whose pixels vary in both directions.
+fn gradient(w: usize, h: usize) -> Region {
+    let mut region = Region::default();
+    region.set_w(w as i32);
+    region.set_h(h as i32);
+    region.set_l(w * h * 4);
+    *region.data_mut() = (0..w * h)
+        .flat_map(|i| {
+            let (x, y) = (i % w, i / w);
+            [(x * 7) as u8, (y * 5) as u8, (x + y) as u8, 255]
+        })
+        .collect();
+    region
+}
+
+#[test]
+fn parses_factors_and_sizes() {
+    assert_eq!("0.5".parse::<Scale>().unwrap(), Scale::Percent(50));
+    assert_eq!("1".parse::<Scale>().unwrap(), Scale::Full);
+    assert_eq!("1280x720".parse::<Scale>().unwrap(), Scale::Fit(1280, 720));
+    assert!("0".parse::<Scale>().is_err());
+    assert!("1.5".parse::<Scale>().is_err());
+    assert!("0x720".parse::<Scale>().is_err());
+}
+
+#[test]
+fn fit_preserves_aspect_and_never_upscales() {
+    let fit = Scale::Fit(1280, 1280);
+    assert_eq!(fit.apply((3840, 2160)), (1280, 720));
+    assert_eq!(fit.apply((1080, 1920)), (720, 1280));
+    assert_eq!(fit.apply((640, 480)), (640, 480));
+    assert_eq!(Scale::Percent(25).apply((3840, 2160)), (960, 540));
+}
+
+#[test]
+fn scale_message_round_trips() {
+    for scale in [Scale::Full, Scale::Percent(33), Scale::Fit(1920, 1080)] {
+        let mut buf = Vec::new();
+        Message::Scale(scale).encode(&mut buf);
+        assert_eq!(Message::decode(&buf[4..]).unwrap(), Message::Scale(scale));
+    }
+}
+
+/// Box average of each 2x2 block, rounded up, computed naively.
+fn box_average(src: &[u8], (w, h): (usize, usize)) -> Vec<u8> {
+    let (dw, dh) = (w / 2, h / 2);
+    (0..dw * dh * 4)
+        .map(|i| {
+            let (x, y, c) = (i / 4 % dw, i / 4 / dw, i % 4);
+            let px = |x: usize, y: usize| src[(y * w + x) * 4 + c] as u16;
+            let sum = px(2 * x, 2 * y)
+                + px(2 * x + 1, 2 * y)
+                + px(2 * x, 2 * y + 1)
+                + px(2 * x + 1, 2 * y + 1);
+            sum.div_ceil(4) as u8
+        })
+        .collect()
+}
+
+#[test]
+fn halving_matches_box_average() {
+    // odd width exercises both the vectorized path and the remainder
+    let (w, h) = (37, 10);
+    let src = gradient(w, h);
+    let mut dst = Vec::new();
+    halve(src.data(), (w, h), &mut dst);
+    assert_eq!(dst.len(), 18 * 5 * 4);
+    assert_eq!(dst, box_average(src.data(), (w, h)));
+}
+
+#[test]
+fn halving_rounds_alike_at_every_width() {
+    // noise hits every rounding case, in and past the vectorized bulk
+    let mut x = 1u64;
+    for w in [3, 9, 17, 31, 33, 63, 65] {
+        let h = 7;
+        let src: Vec<u8> = (0..w * h * 4)
+            .map(|_| {
+                // xorshift64
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        let mut dst = Vec::new();
+        halve(&src, (w, h), &mut dst);
+        assert_eq!(dst, box_average(&src, (w, h)), "width {w}");
+    }
+}
+
+#[test]
+fn bilinear_keeps_flat_colors() {
+    let (w, h) = (30, 20);
+    let src = [10, 20, 30, 255].repeat(w * h);
+    let mut dst = vec![0; 17 * 11 * 4];
+    bilinear(&src, (w, h), &mut dst, (17, 11));
+    assert!(dst.chunks(4).all(|px| px == [10, 20, 30, 255]));
+}
+
+#[test]
+fn scaler_reaches_exact_size() {
+    let src = gradient(400, 300);
+    let mut dst = Region::default();
+    let mut scaler = Scaler::default();
+
+    for size in [(200, 150), (100, 75), (123, 45), (399, 299), (1, 1)] {
+        scaler.scale(&src, &mut dst, size);
+        assert_eq!((dst.w() as usize, dst.h() as usize), size);
+        assert_eq!(dst.data().len(), size.0 * size.1 * 4);
+        assert!(dst.data().chunks(4).all(|px| px[3] == 255));
+    }
+}
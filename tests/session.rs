@@ -0,0 +1,344 @@
+use remdes::{caps::*, proto::GoodbyeReason, session::*, *};
+use std::{
+    net::UdpSocket,
+    sync::Arc,
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+};
+
+/// Upper bound for any teardown in these tests.
+const DEADLINE: Duration = Duration::from_secs(2);
+
+fn params() -> SessionParams {
+    Hello::new().negotiate(&Hello::new()).unwrap()
+}
+
+/// Publish a synthetic BGRA frame whose pixels depend on `n`.
+fn publish_frame(slot: &FrameSlot, n: u8) {
+    let (w, h) = (64, 48);
+    slot.publish(|region| {
+        region.set_w(w);
+        region.set_h(h);
+        region.set_l((w * h * 4) as usize);
+        *region.data_mut() = (0..w * h * 4).map(|i| (i as u8).wrapping_add(n)).collect();
+    });
+}
+
+/// Stand-in for the capture thread: generates frames while the session is
+/// active, then goes quiet like a static window would.
+fn spawn_generator(slot: Arc<FrameSlot>, session: Arc<Session>, frames: u8) {
+    spawn(move || {
+        session.wait_active();
+        for n in 0..frames {
+            publish_frame(&slot, n);
+            sleep(Duration::from_millis(5));
+        }
+    });
+}
+
+fn bind() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").unwrap()
+}
+
+/// Run the server side of one session: accept a peer and stream to it.
+fn serve(
+    udp: Arc<UdpSocket>,
+    slot: Arc<FrameSlot>,
+    session: Arc<Session>,
+) -> std::thread::JoinHandle<Result<bool>> {
+    spawn(move || {
+        let Some(addr) = accept_peer(&udp, DEADLINE)? else {
+            return Ok(false);
+        };
+        let result = stream_frames(
+            &udp,
+            addr,
+            (params(), SendOptions::default()),
+            (&slot, &session),
+            |_, _| (),
+        );
+        session.end();
+        session.release();
+        result.map(|_| true)
+    })
+}
+
+/// Wait for a frame header from the server.
+fn recv_header(client: &UdpSocket) -> RegionHeader {
+    client.set_read_timeout(Some(DEADLINE)).unwrap();
+    let mut buf = vec![0; params().max_datagram()];
+    loop {
+        let n = client.recv(&mut buf).unwrap();
+        if n == size_of::<RegionHeader>() {
+            return bytemuck::pod_read_unaligned(&buf[..n]);
+        }
+    }
+}
+
+#[test]
+fn static_window_does_not_block_teardown() {
+    let (slot, session): (Arc<FrameSlot>, Arc<Session>) = Default::default();
+    let udp = Arc::new(bind());
+
+    session.begin();
+    spawn_generator(slot.clone(), session.clone(), 3);
+    let server = serve(udp.clone(), slot, session.clone());
+
+    let client = bind();
+    client.connect(udp.local_addr().unwrap()).unwrap();
+    client.send(&[0]).unwrap();
+    assert_eq!(recv_header(&client).w(), 64);
+
+    // the generator has gone quiet; disconnect must still end the sender
+    sleep(Duration::from_millis(50));
+    let t = Instant::now();
+    assert!(session.end());
+    assert!(server.join().unwrap().unwrap());
+    assert!(t.elapsed() < FRAME_TIMEOUT * 3);
+    assert_eq!(session.state(), SessionState::Idle);
+}
+
+#[test]
+fn first_end_reason_is_kept_until_next_session() {
+    let session = Session::default();
+    assert_eq!(session.end_reason(), None);
+    assert!(!session.end_with(GoodbyeReason::Error)); // nothing to end
+
+    session.begin();
+    assert!(session.end_with(GoodbyeReason::Timeout));
+    assert!(!session.end());
+    assert_eq!(session.end_reason(), Some(GoodbyeReason::Timeout));
+
+    session.release();
+    session.begin();
+    assert_eq!(session.end_reason(), None);
+    session.end();
+    assert_eq!(session.end_reason(), Some(GoodbyeReason::Quit));
+}
+
+#[test]
+fn new_client_is_accepted_after_disconnect() {
+    let (slot, session): (Arc<FrameSlot>, Arc<Session>) = Default::default();
+    let udp = Arc::new(bind());
+
+    for _ in 0..3 {
+        session.begin();
+        spawn_generator(slot.clone(), session.clone(), 5);
+        let server = serve(udp.clone(), slot.clone(), session.clone());
+
+        let client = bind();
+        client.connect(udp.local_addr().unwrap()).unwrap();
+        client.send(&[0]).unwrap();
+        assert_eq!(recv_header(&client).h(), 48);
+
+        session.end();
+        assert!(server.join().unwrap().unwrap());
+    }
+}
+
+#[test]
+fn missing_peer_times_out() {
+    let udp = bind();
+    let t = Instant::now();
+    assert_eq!(accept_peer(&udp, Duration::from_millis(100)).unwrap(), None);
+    assert!(t.elapsed() < DEADLINE);
+}
+
+#[test]
+fn capture_stops_without_frames() {
+    let session = Arc::new(Session::default());
+    session.begin();
+
+    // mirrors the capture thread: poll until the session is no longer active
+    let capture = {
+        let session = session.clone();
+        spawn(move || while session.wait_ended(Duration::from_millis(20)) {})
+    };
+
+    sleep(Duration::from_millis(50));
+    session.end();
+    capture.join().unwrap();
+
+    // only a released session can begin again
+    assert_eq!(session.state(), SessionState::Ending);
+    session.release();
+    session.begin();
+    assert!(session.is_active());
+}
+
+#[test]
+fn fps_target_paces_frames() {
+    let (slot, session): (Arc<FrameSlot>, Arc<Session>) = Default::default();
+    let udp = Arc::new(bind());
+
+    session.begin();
+    session.set_fps(10);
+    spawn_generator(slot.clone(), session.clone(), 100);
+    let server = serve(udp.clone(), slot, session.clone());
+
+    let client = bind();
+    client.connect(udp.local_addr().unwrap()).unwrap();
+    client.send(&[0]).unwrap();
+    recv_header(&client);
+
+    // ~100 frames are generated over the window, but only every 100ms is sent
+    let t = Instant::now();
+    let mut frames = 0;
+    while t.elapsed() < Duration::from_millis(300) {
+        recv_header(&client);
+        frames += 1;
+    }
+    assert!((2..=4).contains(&frames), "{frames} frames sent");
+
+    session.end();
+    assert!(server.join().unwrap().unwrap());
+}
+
+#[test]
+fn quality_downscales_frames() {
+    let (slot, session): (Arc<FrameSlot>, Arc<Session>) = Default::default();
+    let udp = Arc::new(bind());
+
+    session.begin();
+    session.set_quality(remdes::rate::Quality::new(0, 3, 2));
+    spawn_generator(slot.clone(), session.clone(), 5);
+    let server = serve(udp.clone(), slot, session.clone());
+
+    let client = bind();
+    client.connect(udp.local_addr().unwrap()).unwrap();
+    client.send(&[0]).unwrap();
+    let header = recv_header(&client);
+    assert_eq!((header.w(), header.h(), header.l()), (32, 24, 32 * 24 * 4));
+    assert_eq!((header.src_w(), header.src_h()), (64, 48));
+
+    session.end();
+    assert!(server.join().unwrap().unwrap());
+}
+
+#[test]
+fn client_scale_is_applied() {
+    let (slot, session): (Arc<FrameSlot>, Arc<Session>) = Default::default();
+    let udp = Arc::new(bind());
+
+    session.begin();
+    session.set_scale(remdes::scale::Scale::Fit(40, 40));
+    spawn_generator(slot.clone(), session.clone(), 5);
+    let server = serve(udp.clone(), slot, session.clone());
+
+    let client = bind();
+    client.connect(udp.local_addr().unwrap()).unwrap();
+    client.send(&[0]).unwrap();
+    let header = recv_header(&client);
+    assert_eq!((header.w(), header.h()), (40, 30));
+    assert_eq!((header.src_w(), header.src_h()), (64, 48));
+
+    session.end();
+    assert!(server.join().unwrap().unwrap());
+}
+
+#[test]
+fn acked_frames_become_delta_references() {
+    let (slot, session): (Arc<FrameSlot>, Arc<Session>) = Default::default();
+    let udp = Arc::new(bind());
+
+    session.begin();
+    spawn_generator(slot.clone(), session.clone(), 100);
+    let server = serve(udp.clone(), slot, session.clone());
+
+    let client = bind();
+    client.connect(udp.local_addr().unwrap()).unwrap();
+    client.send(&[0]).unwrap();
+
+    // without acknowledgements every frame stands alone
+    let first = recv_header(&client);
+    assert_eq!(first.base(), 0);
+    session.ack(first.seq());
+
+    let delta = loop {
+        let header = recv_header(&client);
+        if header.base() != 0 {
+            break header;
+        }
+    };
+    assert_eq!(delta.base(), first.seq());
+
+    // a requested keyframe interrupts the deltas
+    session.ack(delta.seq());
+    session.request_keyframe();
+    loop {
+        let header = recv_header(&client);
+        if header.base() == 0 {
+            break;
+        }
+        session.ack(header.seq());
+    }
+
+    session.end();
+    assert!(server.join().unwrap().unwrap());
+}
+
+#[test]
+fn slot_hands_over_latest_frame() {
+    let slot = FrameSlot::default();
+    assert!(slot.take(Duration::from_millis(20)).is_none());
+
+    for n in 0..3 {
+        publish_frame(&slot, n);
+    }
+    let frame = slot.take(DEADLINE).unwrap();
+    assert_eq!(frame.data()[0], 2);
+    drop(frame);
+
+    // an already taken frame is not handed over twice
+    assert!(slot.take(Duration::from_millis(20)).is_none());
+    assert_eq!(
+        slot.stats(),
+        FrameStats {
+            published: 3,
+            dropped: 2
+        }
+    );
+}
+
+#[test]
+fn slot_wakes_waiting_sender() {
+    let slot = Arc::new(FrameSlot::default());
+    let publisher = {
+        let slot = slot.clone();
+        spawn(move || {
+            sleep(Duration::from_millis(50));
+            publish_frame(&slot, 7);
+        })
+    };
+
+    let start = Instant::now();
+    let frame = slot.take(DEADLINE).unwrap();
+    assert!(start.elapsed() < DEADLINE);
+    assert_eq!(frame.data()[0], 7);
+    publisher.join().unwrap();
+}
+
+#[test]
+fn slot_reuses_buffers() {
+    let slot = FrameSlot::default();
+    let mut buffers = Vec::new();
+    for n in 0..20u8 {
+        // fill in place, the way the capture thread does
+        slot.publish(|region| {
+            let data = region.data_mut();
+            data.clear();
+            data.extend((0..4096).map(|i| (i as u8).wrapping_add(n)));
+        });
+        if n % 2 == 1 {
+            let frame = slot.take(DEADLINE).unwrap();
+            assert_eq!(frame.data()[0], n);
+            let ptr = frame.data().as_ptr();
+            if !buffers.contains(&ptr) {
+                buffers.push(ptr);
+            }
+        }
+    }
+    // the three buffers rotate without reallocating
+    assert!(buffers.len() <= 3);
+    assert_eq!(slot.stats().dropped, 10);
+}
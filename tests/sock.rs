@@ -0,0 +1,134 @@
+use remdes::sock::*;
+use std::{net::UdpSocket, time::Duration};
+
+fn pair() -> (UdpSocket, UdpSocket) {
+    let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+    rx.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    (tx, rx)
+}
+
+/// Datagrams of the given sizes, each filled with its own index.
+fn datagrams(sizes: &[usize]) -> Vec<Vec<u8>> {
+    sizes
+        .iter()
+        .enumerate()
+        .map(|(i, &len)| vec![i as u8; len])
+        .collect()
+}
+
+/// Send `sent` through a batch sender and collect what arrives.
+fn round_trip(sent: &[Vec<u8>], (gso, gro): (bool, bool)) -> Vec<Vec<u8>> {
+    let (tx, rx) = pair();
+    let mut sender = BatchSender::new(&tx, rx.local_addr().unwrap(), 1500, gso);
+    let mut receiver = BatchReceiver::new(&rx, 1500, gro).unwrap();
+
+    for datagram in sent {
+        sender.push(datagram).unwrap();
+    }
+    sender.flush().unwrap();
+    assert!(sender.is_empty());
+
+    let mut received = Vec::new();
+    while received.len() < sent.len() {
+        receiver.recv().unwrap();
+        received.extend(receiver.datagrams().map(<[u8]>::to_vec));
+    }
+    received
+}
+
+#[test]
+fn batches_keep_order_and_contents() {
+    // more than one batch, of mixed sizes
+    let sizes: Vec<_> = (0..BATCH * 2 + 5).map(|i| 24 + i * 37 % 1400).collect();
+    let sent = datagrams(&sizes);
+    assert_eq!(round_trip(&sent, (false, false)), sent);
+}
+
+#[test]
+fn coalesced_runs_split_back_apart() {
+    // equal-sized runs, each ended by a shorter datagram, as chunks are
+    let mut sizes = vec![1200; 10];
+    sizes.push(300);
+    sizes.extend([1200; 3]);
+    sizes.extend([24, 800, 1200]);
+    let sent = datagrams(&sizes);
+    assert_eq!(round_trip(&sent, (true, true)), sent);
+    assert_eq!(round_trip(&sent, (true, false)), sent);
+    assert_eq!(round_trip(&sent, (false, true)), sent);
+}
+
+#[test]
+fn push_sends_full_batches() {
+    let (tx, rx) = pair();
+    let mut sender = BatchSender::new(&tx, rx.local_addr().unwrap(), 64, false);
+    for i in 0..BATCH + 1 {
+        sender.push(&[i as u8; 8]).unwrap();
+    }
+    // the first batch left as soon as it filled up
+    assert_eq!(sender.len(), 1);
+
+    let mut receiver = BatchReceiver::new(&rx, 64, false).unwrap();
+    let mut n = 0;
+    while n < BATCH {
+        receiver.recv().unwrap();
+        n += receiver.datagrams().count();
+    }
+    assert_eq!(n, BATCH);
+}
+
+#[test]
+fn receive_times_out() {
+    let (_, rx) = pair();
+    rx.set_read_timeout(Some(Duration::from_millis(20)))
+        .unwrap();
+    let mut receiver = BatchReceiver::new(&rx, 64, false).unwrap();
+    let err = receiver.recv().unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    ));
+    assert_eq!(receiver.datagrams().count(), 0);
+}
+
+#[test]
+fn buffer_sizes_are_applied() {
+    let (tx, rx) = pair();
+    let (send, _) = set_buffer_sizes(&tx, (Some(64 * 1024), None)).unwrap();
+    let (_, recv) = set_buffer_sizes(&rx, (None, Some(64 * 1024))).unwrap();
+    // the OS may round up (Linux doubles the request for bookkeeping)
+    assert!(send >= 64 * 1024);
+    assert!(recv >= 64 * 1024);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn overflows_are_counted() {
+    let (tx, rx) = pair();
+    set_buffer_sizes(&rx, (None, Some(4096))).unwrap();
+    let mut receiver = BatchReceiver::new(&rx, 1500, false).unwrap();
+    let mut sender = BatchSender::new(&tx, rx.local_addr().unwrap(), 1500, false);
+
+    // far more than the receive buffer holds, with nobody reading
+    let sent = 200;
+    for i in 0..sent {
+        sender.push(&[i as u8; 1000]).unwrap();
+    }
+    sender.flush().unwrap();
+
+    rx.set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let mut received = 0;
+    while receiver.recv().is_ok() {
+        received += receiver.datagrams().count() as u32;
+    }
+
+    // the kernel reports drops with the next datagram to arrive
+    sender.push(&[0; 1000]).unwrap();
+    sender.flush().unwrap();
+    receiver.recv().unwrap();
+    received += receiver.datagrams().count() as u32;
+
+    assert!(receiver.drops() > 0);
+    assert_eq!(received + receiver.drops(), sent + 1);
+}
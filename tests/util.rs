@@ -0,0 +1,66 @@
+use remdes::util::*;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+#[test]
+fn resolves_addresses() {
+    let v4 = |ip: Ipv4Addr| SocketAddr::new(IpAddr::V4(ip), 54277);
+    let v6 = |ip: Ipv6Addr| SocketAddr::new(IpAddr::V6(ip), 54277);
+
+    assert_eq!(
+        resolve("127.0.0.1", 54277).unwrap(),
+        v4(Ipv4Addr::LOCALHOST)
+    );
+    assert_eq!(
+        resolve("0.0.0.0", 54277).unwrap(),
+        v4(Ipv4Addr::UNSPECIFIED)
+    );
+    assert_eq!(resolve("::1", 54277).unwrap(), v6(Ipv6Addr::LOCALHOST));
+    assert_eq!(resolve("[::]", 54277).unwrap(), v6(Ipv6Addr::UNSPECIFIED));
+}
+
+#[test]
+fn resolves_hostnames() {
+    let addrs = resolve_all("localhost", 54287).unwrap();
+    assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+    assert!(addrs.iter().all(|addr| addr.port() == 54287));
+}
+
+#[test]
+fn rejects_unresolvable_hosts() {
+    let err = resolve("no such host", 54277).unwrap_err();
+    assert!(err.to_string().contains("no such host"));
+}
+
+#[test]
+fn resolves_endpoints() {
+    let v4 = |port| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+    let v6 = |port| SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port);
+
+    assert_eq!(resolve_endpoint("127.0.0.1", 54297).unwrap(), v4(54297));
+    assert_eq!(resolve_endpoint("127.0.0.1:9", 54297).unwrap(), v4(9));
+    assert_eq!(resolve_endpoint("::1", 54297).unwrap(), v6(54297));
+    assert_eq!(resolve_endpoint("[::1]", 54297).unwrap(), v6(54297));
+    assert_eq!(resolve_endpoint("[::1]:9", 54297).unwrap(), v6(9));
+    assert!(resolve_endpoint("localhost:9", 54297).unwrap().port() == 9);
+    assert!(resolve_endpoint("localhost:port", 54297).is_err());
+}
+
+#[test]
+fn config_dir_is_named_after_remdes() {
+    // unset only in bare environments
+    if let Some(dir) = config_dir() {
+        assert_eq!(dir.file_name().unwrap(), "remdes");
+    }
+}
+
+#[test]
+fn heartbeats_must_be_shorter_than_the_timeout() {
+    let ms = Duration::from_millis;
+    assert!(check_heartbeat((ms(250), ms(2000))).is_ok());
+    assert!(check_heartbeat((ms(2000), ms(2000))).is_err());
+    let e = check_heartbeat((ms(3000), ms(2000))).unwrap_err();
+    assert!(e.to_string().contains("--heartbeat"), "{e}");
+}